@@ -0,0 +1,2 @@
+-- qbittorrent file priority, 0 means the file should not be downloaded
+ALTER TABLE torrent_files ADD COLUMN priority INTEGER NOT NULL DEFAULT 1;
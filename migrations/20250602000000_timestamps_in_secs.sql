@@ -0,0 +1,3 @@
+-- checked_at and finished_at were stored in millis while every other timestamp is in seconds
+UPDATE torrents SET checked_at = checked_at / 1000 WHERE checked_at > 100000000000;
+UPDATE torrents SET finished_at = finished_at / 1000 WHERE finished_at > 100000000000;
@@ -0,0 +1,9 @@
+-- history of what happened to torrents, torrents can be deleted so this only keeps their hash and name
+CREATE TABLE events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind INTEGER NOT NULL,
+    torrent_hash TEXT NOT NULL,
+    torrent_name TEXT NOT NULL,
+    message TEXT,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch())
+) STRICT;
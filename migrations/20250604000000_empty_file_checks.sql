@@ -0,0 +1,3 @@
+-- the number of passes a torrent has had no valid files, torrents are rechecked a few times
+-- because some debrid services populate the file list progressively
+ALTER TABLE torrents ADD COLUMN empty_file_checks INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- the playback duration of a file, used to estimate its bitrate before a reader has been measured
+ALTER TABLE torrent_files ADD COLUMN duration_hint_secs INTEGER;
@@ -0,0 +1,2 @@
+-- how the torrent was added, torrents added before this was tracked were added through the qbittorrent api
+ALTER TABLE torrents ADD COLUMN source INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- real-debrid torrent ids are strings, this gives each one the integer id that's stored as the torrent's debrid id
+CREATE TABLE realdebrid_ids (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    real_id TEXT NOT NULL UNIQUE
+) STRICT;
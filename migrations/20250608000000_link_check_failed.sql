@@ -0,0 +1,2 @@
+-- set when verify_on_ready couldn't create a download link, so the check isn't repeated every pass
+ALTER TABLE torrents ADD COLUMN link_check_failed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,66 @@
+use crate::config::get_config;
+use crate::helpers::now_secs::now_secs;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::Notify;
+
+/// Tracks when lumin was last used by a client, either a read from the mount or an API request.
+///
+/// With `idle_timeout_secs` set (disabled by default), once nothing has been used for that long the
+/// reconciler stops its frequent rechecks and only polls the debrid service at the normal interval.
+/// The next read or request ends the idle period and wakes the reconciler. The mount stays mounted,
+/// read ahead is already tied to active readers and is cancelled once they stop reading.
+pub struct Activity {
+    last_activity: AtomicI64,
+    resumed: Notify,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            last_activity: AtomicI64::new(now_secs()),
+            resumed: Notify::new(),
+        }
+    }
+
+    pub fn record(&self) {
+        let now = now_secs();
+        let previous = self.last_activity.swap(now, Ordering::Relaxed);
+        if is_idle(previous, now, get_config().idle_timeout_secs) {
+            tracing::info!("activity resumed after {} seconds", now - previous);
+            self.resumed.notify_one();
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let last_activity = self.last_activity.load(Ordering::Relaxed);
+        is_idle(last_activity, now_secs(), get_config().idle_timeout_secs)
+    }
+
+    /// Waits for the next activity after being idle.
+    pub async fn resumed(&self) {
+        self.resumed.notified().await
+    }
+}
+
+fn is_idle(last_activity: i64, now: i64, idle_timeout_secs: Option<u64>) -> bool {
+    let Some(idle_timeout_secs) = idle_timeout_secs else {
+        return false;
+    };
+
+    now - last_activity >= idle_timeout_secs as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_idle() {
+        // disabled
+        assert!(!is_idle(0, 10_000, None));
+
+        assert!(!is_idle(1000, 1500, Some(3600)));
+        assert!(is_idle(1000, 4600, Some(3600)));
+        assert!(is_idle(1000, 10_000, Some(3600)));
+    }
+}
@@ -4,20 +4,26 @@ use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use std::{
     ops::RangeInclusive,
-    os::fd::{AsFd, AsRawFd},
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::fs::FileExt,
+    },
+    path::Path,
     sync::{
         Arc,
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
 };
 use tokio::sync::Mutex;
 
-// This is the size of an individual chunk.
+// This is the size of an individual chunk for entries that don't have one in their cachemeta.
 // Generally, chunks will be batched together into a single request.
-// DO NOT CHANGE or it will break existing caches.
-// todo: Ideally this should be serialized in cachemeta.
+// DO NOT CHANGE or it will break existing caches, new entries use the `chunk_size` config.
 pub const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024; // 8MB
 
+// zstd's default level, compressible files are small so there's little to gain from going higher
+const COMPRESSION_LEVEL: i32 = 3;
+
 const PRIORITY_RANGES: [(RangeInclusive<u64>, ChunkPriority); 3] = [
     (0..=20, ChunkPriority::High),
     (21..=95, ChunkPriority::Low),
@@ -26,6 +32,8 @@ const PRIORITY_RANGES: [(RangeInclusive<u64>, ChunkPriority); 3] = [
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 pub enum ChunkPriority {
+    /// Just ahead of a reader, so it's about to be read.
+    AheadOfReader,
     GracePeriod,
     Preloaded,
     FirstChunk,
@@ -42,25 +50,38 @@ pub struct Chunk {
     pub size: u64,
     pub accessed_at_secs: AtomicU64,
     pub cached: AtomicBool,
+    /// The size of the chunk once compressed with zstd, 0 if it's stored uncompressed.
+    pub compressed_size: AtomicU64,
+    /// crc32 of the uncompressed chunk, 0 if it wasn't hashed when it was downloaded.
+    pub checksum: AtomicU32,
+    // whether the checksum has been checked against the data on disk since the chunk was loaded
+    #[serde(skip)]
+    pub verified: AtomicBool,
     #[serde(skip)]
     pub downloading: Arc<Mutex<()>>,
+    // how many bytes of the chunk the current download has written, only meaningful while it's downloading
+    #[serde(skip)]
+    pub written_bytes: AtomicU64,
 }
 
 impl Chunk {
-    pub fn new(index: u64, size: u64) -> Self {
+    pub fn new(index: u64, offset: u64, size: u64) -> Self {
         let now = chrono::Utc::now().timestamp() as u64;
-        let offset = index * DEFAULT_CHUNK_SIZE;
         Self {
             index,
             offset,
             size,
             accessed_at_secs: AtomicU64::new(now),
             cached: AtomicBool::new(false),
+            compressed_size: AtomicU64::new(0),
+            checksum: AtomicU32::new(0),
+            verified: AtomicBool::new(false),
             downloading: Arc::new(Mutex::new(())),
+            written_bytes: AtomicU64::new(0),
         }
     }
 
-    pub fn get_priority(&self, file_size: u64) -> ChunkPriority {
+    pub fn get_priority(&self, file_size: u64, chunk_preload: Option<(u64, u64)>) -> ChunkPriority {
         let now = chrono::Utc::now().timestamp() as u64;
         let accessed_at = self
             .accessed_at_secs
@@ -70,25 +91,29 @@ impl Chunk {
             return ChunkPriority::GracePeriod;
         }
 
+        self.get_position_priority(file_size, chunk_preload)
+    }
+
+    /// The priority of the chunk based only on where it is in the file, ignoring when it was accessed.
+    pub fn get_position_priority(&self, file_size: u64, chunk_preload: Option<(u64, u64)>) -> ChunkPriority {
         // the first and last chunks generally contain metadata that we want to keep
         // so that ffprobe/etc can read the file without requesting new data
         if self.index == 0 {
             return ChunkPriority::FirstChunk;
         }
 
-        let total_chunks = (file_size + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE;
-        if self.index == total_chunks - 1 {
+        if self.offset + self.size >= file_size {
             return ChunkPriority::LastChunk;
         }
 
-        let config = get_config();
-        if let Some((preload_start, preload_end)) = config.chunk_preload {
+        if let Some((preload_start, preload_end)) = chunk_preload {
             if self.index <= preload_start {
                 return ChunkPriority::Preloaded;
             }
 
-            let total_chunks = (file_size + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE;
-            if self.index >= total_chunks - preload_end {
+            // every chunk but the last is the entry's chunk size, so the chunk count can be worked out from this one
+            let total_chunks = file_size.div_ceil(self.size);
+            if self.index >= total_chunks.saturating_sub(preload_end) {
                 return ChunkPriority::Preloaded;
             }
         }
@@ -108,6 +133,11 @@ impl Chunk {
         );
     }
 
+    pub fn touch(&self) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        self.accessed_at_secs.store(now, Ordering::Relaxed);
+    }
+
     pub fn is_cached_or_downloading(&self) -> bool {
         if self.cached.load(std::sync::atomic::Ordering::Relaxed) {
             return true;
@@ -121,6 +151,12 @@ impl Chunk {
         false
     }
 
+    /// Whether the part of `offset..offset + size` inside this chunk has been written by the current download.
+    pub fn is_range_written(&self, offset: u64, size: u64) -> bool {
+        let end_offset = (offset + size).min(self.offset + self.size);
+        end_offset <= self.offset + self.written_bytes.load(Ordering::Acquire)
+    }
+
     pub async fn try_remove(&self, file: Arc<CacheEntry>) -> Result<bool> {
         let Ok(download_lock) = self.downloading.try_lock() else {
             // chunk is already being downloaded by something else, we can skip it
@@ -153,9 +189,13 @@ impl Chunk {
         }
 
         self.cached.store(false, Ordering::SeqCst);
+        self.compressed_size.store(0, Ordering::SeqCst);
+        self.checksum.store(0, Ordering::SeqCst);
+        self.verified.store(false, Ordering::SeqCst);
         drop(download_lock);
         drop(fd);
         file.flush_cache_meta()
+            .await
             .map_err(|e| {
                 tracing::error!("Failed to flush metadata: {}", e);
             })
@@ -184,6 +224,11 @@ impl<'de> serde::Deserialize<'de> for Chunk {
             offset: u64,
             accessed_at_secs: AtomicU64,
             cached: AtomicBool,
+            // metadata from before compression was added
+            #[serde(default)]
+            compressed_size: AtomicU64,
+            #[serde(default)]
+            checksum: AtomicU32,
         }
 
         let data = ChunkData::deserialize(deserializer)?;
@@ -194,26 +239,273 @@ impl<'de> serde::Deserialize<'de> for Chunk {
             offset: data.offset,
             accessed_at_secs: data.accessed_at_secs,
             cached: data.cached,
+            compressed_size: data.compressed_size,
+            checksum: data.checksum,
+            verified: AtomicBool::new(false),
             downloading: Arc::new(Mutex::new(())),
+            written_bytes: AtomicU64::new(0),
         })
     }
 }
 
-pub fn get_chunk_size_from_index(index: u64, file_size: u64) -> u64 {
-    let total_chunks = (file_size + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE;
+pub fn get_chunk_size_from_index(index: u64, file_size: u64, chunk_size: u64) -> u64 {
+    let total_chunks = file_size.div_ceil(chunk_size);
     if index == total_chunks - 1 {
-        return file_size % DEFAULT_CHUNK_SIZE;
+        // not file_size % chunk_size, that's 0 for files that are a multiple of the chunk size
+        return file_size - index * chunk_size;
+    }
+
+    chunk_size
+}
+
+pub fn create_chunks(file_size: u64, chunk_size: u64) -> Vec<Arc<Chunk>> {
+    let total_chunks = file_size.div_ceil(chunk_size);
+    (0..total_chunks)
+        .map(|i| {
+            let size = get_chunk_size_from_index(i, file_size, chunk_size);
+            Arc::new(Chunk::new(i, i * chunk_size, size))
+        })
+        .collect()
+}
+
+/// Compresses a downloaded chunk in place if that makes it smaller, and punches a hole over the rest of
+/// the chunk to free the space. The chunk stays at its usual offset, so reads can still seek straight to it.
+pub fn compress_chunk(path: &Path, chunk: &Chunk) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut data = vec![0; chunk.size as usize];
+    file.read_exact_at(&mut data, chunk.offset)?;
+
+    let compressed = zstd::bulk::compress(&data, COMPRESSION_LEVEL)?;
+    let compressed_size = compressed.len() as u64;
+    if compressed_size >= chunk.size {
+        return Ok(());
+    }
+
+    file.write_all_at(&compressed, chunk.offset)?;
+    let result = unsafe {
+        libc::fallocate64(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            (chunk.offset + compressed_size) as i64,
+            (chunk.size - compressed_size) as i64,
+        )
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    chunk.compressed_size.store(compressed_size, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Reads `offset..offset + size` from the cache file, decompressing the chunks that are compressed.
+pub fn read_chunks(path: &Path, chunks: &[Arc<Chunk>], offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    let end_offset = offset + size;
+    let mut buffer = Vec::with_capacity(size as usize);
+    for chunk in chunks {
+        let start = offset.max(chunk.offset);
+        let end = end_offset.min(chunk.offset + chunk.size);
+        let compressed_size = chunk.compressed_size.load(Ordering::SeqCst);
+        if compressed_size == 0 {
+            let mut data = vec![0; (end - start) as usize];
+            file.read_exact_at(&mut data, start)?;
+            buffer.extend(data);
+            continue;
+        }
+
+        let mut compressed = vec![0; compressed_size as usize];
+        file.read_exact_at(&mut compressed, chunk.offset)?;
+        let data = zstd::bulk::decompress(&compressed, chunk.size as usize)?;
+        buffer.extend(&data[(start - chunk.offset) as usize..(end - chunk.offset) as usize]);
+    }
+
+    Ok(buffer)
+}
+
+/// The crc32 of the chunk's uncompressed data.
+pub fn hash_chunk(path: &Path, chunk: &Arc<Chunk>) -> std::io::Result<u32> {
+    let data = read_chunks(path, std::slice::from_ref(chunk), chunk.offset, chunk.size)?;
+    Ok(crc32fast::hash(&data))
+}
+
+/// Checks the chunk against the checksum from when it was downloaded, chunks without one always pass.
+pub fn verify_chunk(path: &Path, chunk: &Arc<Chunk>) -> std::io::Result<bool> {
+    let checksum = chunk.checksum.load(Ordering::SeqCst);
+    if checksum != 0 && hash_chunk(path, chunk)? != checksum {
+        return Ok(false);
     }
 
-    DEFAULT_CHUNK_SIZE
+    chunk.verified.store(true, Ordering::SeqCst);
+    Ok(true)
+}
+
+#[derive(Serialize)]
+struct CacheMeta<'a> {
+    chunk_size: u64,
+    chunks: &'a [Arc<Chunk>],
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCacheMeta {
+    Current { chunk_size: u64, chunks: Vec<Arc<Chunk>> },
+    // metadata from before the chunk size was configurable is only the chunk list
+    Legacy(Vec<Arc<Chunk>>),
 }
 
-pub fn serialize_chunks(chunks: &[Arc<Chunk>], file: &mut std::fs::File) -> Result<()> {
-    serde_json::to_writer(file, chunks)?;
+pub fn serialize_chunks(chunk_size: u64, chunks: &[Arc<Chunk>], file: &mut std::fs::File) -> Result<()> {
+    serde_json::to_writer(file, &CacheMeta { chunk_size, chunks })?;
     Ok(())
 }
 
-pub fn deserialize_chunks(file: &mut std::fs::File) -> Result<Vec<Arc<Chunk>>> {
-    let chunks: Vec<Arc<Chunk>> = serde_json::from_reader(file)?;
-    Ok(chunks)
+/// Returns the chunk size of the entry and its chunks.
+pub fn deserialize_chunks(file: &mut std::fs::File) -> Result<(u64, Vec<Arc<Chunk>>)> {
+    let meta: StoredCacheMeta = serde_json::from_reader(file)?;
+    match meta {
+        StoredCacheMeta::Current { chunk_size, chunks } => Ok((chunk_size, chunks)),
+        StoredCacheMeta::Legacy(chunks) => Ok((DEFAULT_CHUNK_SIZE, chunks)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_chunks_sub_chunk_file() {
+        // a 40KB subtitle should be backed by a single chunk the size of the file,
+        // not a full 8MB chunk.
+        let file_size = 40 * 1024;
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].index, 0);
+        assert_eq!(chunks[0].offset, 0);
+        assert_eq!(chunks[0].size, file_size);
+    }
+
+    #[test]
+    fn test_create_chunks_exact_multiple() {
+        let file_size = 3 * DEFAULT_CHUNK_SIZE;
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.size == DEFAULT_CHUNK_SIZE));
+        assert_eq!(chunks[2].offset + chunks[2].size, file_size);
+
+        let chunks = create_chunks(file_size + 1, DEFAULT_CHUNK_SIZE);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[3].size, 1);
+    }
+
+    #[test]
+    fn test_is_range_written() {
+        let chunk = Chunk::new(1, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        let offset = DEFAULT_CHUNK_SIZE;
+        assert!(!chunk.is_range_written(offset, 100));
+
+        chunk.written_bytes.store(4096, Ordering::Release);
+        assert!(chunk.is_range_written(offset, 100));
+        assert!(chunk.is_range_written(offset, 4096));
+        assert!(!chunk.is_range_written(offset + 4000, 200));
+        // reads starting in the previous chunk only need the start of this one
+        assert!(chunk.is_range_written(offset - 100, 200));
+
+        // reads past the end of the chunk need the whole chunk
+        assert!(!chunk.is_range_written(offset, DEFAULT_CHUNK_SIZE + 100));
+        chunk.written_bytes.store(DEFAULT_CHUNK_SIZE, Ordering::Release);
+        assert!(chunk.is_range_written(offset, DEFAULT_CHUNK_SIZE + 100));
+    }
+
+    #[test]
+    fn test_touch_updates_accessed_at() {
+        let chunk = Chunk::new(0, 0, DEFAULT_CHUNK_SIZE);
+        chunk.accessed_at_secs.store(0, Ordering::Relaxed);
+        chunk.touch();
+        assert!(chunk.accessed_at_secs.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_compress_chunk() {
+        let dir = std::env::temp_dir().join("lumin-test-compression");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.bin");
+
+        // subtitles compress well, the second chunk is noise that doesn't
+        let file_size = DEFAULT_CHUNK_SIZE + 4096;
+        let mut data = b"1\n00:00:01,000 --> 00:00:02,000\nHello there\n\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(DEFAULT_CHUNK_SIZE as usize)
+            .collect::<Vec<u8>>();
+        let mut state = 0x2545f491u32;
+        data.extend((0..4096).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+        std::fs::write(&path, &data).unwrap();
+
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        for chunk in &chunks {
+            compress_chunk(&path, chunk).unwrap();
+        }
+
+        let compressed_size = chunks[0].compressed_size.load(Ordering::SeqCst);
+        assert!(compressed_size > 0 && compressed_size < DEFAULT_CHUNK_SIZE / 100);
+        assert_eq!(chunks[1].compressed_size.load(Ordering::SeqCst), 0);
+
+        let offset = DEFAULT_CHUNK_SIZE - 100;
+        let read = read_chunks(&path, &chunks, offset, 200).unwrap();
+        assert_eq!(read, &data[offset as usize..offset as usize + 200]);
+        assert_eq!(read_chunks(&path, &chunks[..1], 10, 20).unwrap(), &data[10..30]);
+
+        // the compressed size is kept in the metadata, metadata from before compression has none
+        let meta = serde_json::to_string(&chunks).unwrap();
+        let loaded: Vec<Chunk> = serde_json::from_str(&meta).unwrap();
+        assert_eq!(loaded[0].compressed_size.load(Ordering::SeqCst), compressed_size);
+        let old_meta = r#"[{"index":0,"offset":0,"size":100,"accessed_at_secs":0,"cached":true}]"#;
+        let loaded: Vec<Chunk> = serde_json::from_str(old_meta).unwrap();
+        assert_eq!(loaded[0].compressed_size.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_verify_chunk() {
+        let dir = std::env::temp_dir().join("lumin-test-verify");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.bin");
+
+        let data = (0..4096u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+
+        let chunks = create_chunks(data.len() as u64, DEFAULT_CHUNK_SIZE);
+        let chunk = &chunks[0];
+        // chunks from before verification have no checksum to check against
+        assert!(verify_chunk(&path, chunk).unwrap());
+
+        let checksum = hash_chunk(&path, chunk).unwrap();
+        chunk.checksum.store(checksum, Ordering::SeqCst);
+        chunk.verified.store(false, Ordering::SeqCst);
+        assert!(verify_chunk(&path, chunk).unwrap());
+        assert!(chunk.verified.load(Ordering::SeqCst));
+
+        // the checksum is of the uncompressed data, so it still matches once the chunk is compressed
+        std::fs::write(&path, vec![0; data.len()]).unwrap();
+        let compressible_hash = hash_chunk(&path, chunk).unwrap();
+        compress_chunk(&path, chunk).unwrap();
+        assert!(chunk.compressed_size.load(Ordering::SeqCst) > 0);
+        assert_eq!(hash_chunk(&path, chunk).unwrap(), compressible_hash);
+
+        // a chunk that was partially written but marked cached
+        chunk.compressed_size.store(0, Ordering::SeqCst);
+        let mut truncated = data.clone();
+        truncated[2048..].fill(0);
+        std::fs::write(&path, &truncated).unwrap();
+        chunk.checksum.store(crc32fast::hash(&data), Ordering::SeqCst);
+        chunk.verified.store(false, Ordering::SeqCst);
+        assert!(!verify_chunk(&path, chunk).unwrap());
+        assert!(!chunk.verified.load(Ordering::SeqCst));
+    }
 }
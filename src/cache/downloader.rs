@@ -1,25 +1,48 @@
-use super::{chunk::Chunk, entry::CacheEntry, ratelimiter::Ratelimiter};
+use super::{
+    chunk::{Chunk, compress_chunk, hash_chunk},
+    entry::CacheEntry,
+    ratelimiter::Ratelimiter,
+};
 use crate::{
-    config::get_config,
-    debrid::{Debrid, TorboxError},
-    helpers::get_user_agent::get_user_agent,
+    config::{DownloadMode, HttpVersion, get_config},
+    debrid::{DebridError, DebridProvider},
+    helpers::get_http_client_builder::get_http_client_builder,
 };
 use anyhow::Result;
 use futures_util::StreamExt;
-use reqwest::StatusCode;
+use reqwest::{StatusCode, header::HeaderMap};
 use std::{
-    io::SeekFrom,
+    io::{ErrorKind, SeekFrom},
     sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 use tokio::{
     io::{AsyncSeekExt, AsyncWriteExt},
-    sync::OwnedMutexGuard,
+    sync::{Notify, OwnedMutexGuard},
 };
 
-const RESPONSE_ERROR_RETRIES: [u64; 3] = [1, 5, 30]; // errors that are returned by the server, like 500
+// errors returned by the server use the `download_retry_secs`/`download_server_error_retry_secs` config
 const STREAM_ERROR_RETRIES: [u64; 2] = [5, 30]; // errors that happen while streaming the response chunks
 const FETCH_ERROR_RETRIES: [u64; 1] = [5]; // errors that happen while sending the request
+const STORAGE_FULL_RETRIES: [u64; 1] = [10]; // gives the emergency sweep time to free space
+
+// shared so connections (and HTTP/2 streams) are reused across chunk downloads
+static DOWNLOAD_CLIENT: once_cell::sync::Lazy<reqwest::Client> = once_cell::sync::Lazy::new(|| {
+    let config = get_config();
+    let builder = get_http_client_builder(config)
+        .http2_keep_alive_interval(Duration::from_secs(30))
+        .http2_keep_alive_while_idle(true)
+        .http2_adaptive_window(true);
+
+    let builder = match config.download_http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    builder.build().expect("failed to build download client")
+});
 
 #[derive(Debug, Error)]
 enum DownloadChunkError {
@@ -31,16 +54,21 @@ enum DownloadChunkError {
     ResponseError(StatusCode, bool), // retryable if `bool` is true
     #[error("error while streaming chunks: {0}")]
     StreamError(reqwest::Error), // retryable
+    #[error("server returned {received} bytes when {expected} were requested")]
+    LengthMismatch { expected: u64, received: u64 }, // retryable
+    #[error("server returned range {0} when {1} was requested")]
+    RangeMismatch(String, String), // retryable
     #[error("failed to open file for writing: {0}")]
     IoError(#[from] std::io::Error), // never retried
-    #[error("torbox api error: {0}")]
-    TorboxError(#[from] TorboxError),
+    #[error("debrid api error: {0}")]
+    DebridError(#[from] DebridError),
     #[error("unrecoverable error while downloading chunks: {0}")]
     GenericError(#[from] anyhow::Error), // never retried
 }
 
 impl DownloadChunkError {
     fn get_backoff(&self, attempts: usize, ratelimiter: &Arc<Ratelimiter>) -> Option<u64> {
+        let config = get_config();
         match self {
             DownloadChunkError::Ratelimited(Some(seconds)) => {
                 ratelimiter.set_ratelimited_for(5);
@@ -48,21 +76,32 @@ impl DownloadChunkError {
             }
             DownloadChunkError::Ratelimited(None) => {
                 ratelimiter.set_ratelimited_for(5);
-                RESPONSE_ERROR_RETRIES.get(attempts - 1).copied()
+                config.download_retry_secs.get(attempts - 1).copied()
             }
 
             DownloadChunkError::FetchError(_) => FETCH_ERROR_RETRIES.get(attempts - 1).copied(),
+            // torbox occasionally returns 500s that succeed on retry, but they can also mean the
+            // link is genuinely broken, so they get fewer attempts with longer waits.
+            DownloadChunkError::ResponseError(StatusCode::INTERNAL_SERVER_ERROR, true) => {
+                config.download_server_error_retry_secs.get(attempts - 1).copied()
+            }
             DownloadChunkError::ResponseError(_, retryable) => {
                 if *retryable {
-                    RESPONSE_ERROR_RETRIES.get(attempts - 1).copied()
+                    config.download_retry_secs.get(attempts - 1).copied()
                 } else {
                     None
                 }
             }
-            DownloadChunkError::StreamError(_) => STREAM_ERROR_RETRIES.get(attempts - 1).copied(),
+            DownloadChunkError::StreamError(_) | DownloadChunkError::LengthMismatch { .. } => {
+                STREAM_ERROR_RETRIES.get(attempts - 1).copied()
+            }
+            DownloadChunkError::RangeMismatch(..) => config.download_retry_secs.get(attempts - 1).copied(),
+            DownloadChunkError::IoError(e) if e.kind() == ErrorKind::StorageFull => {
+                STORAGE_FULL_RETRIES.get(attempts - 1).copied()
+            }
             DownloadChunkError::GenericError(_)
             | DownloadChunkError::IoError(_)
-            | DownloadChunkError::TorboxError(_) => None,
+            | DownloadChunkError::DebridError(_) => None,
         }
     }
 }
@@ -71,7 +110,8 @@ pub async fn download_contiguous_chunks(
     chunks: Vec<(OwnedMutexGuard<()>, Arc<Chunk>)>,
     file: Arc<CacheEntry>,
     ratelimiter: Arc<Ratelimiter>,
-    debrid: Arc<Debrid>,
+    debrid: Arc<dyn DebridProvider>,
+    sweep_notify: Arc<Notify>,
 ) -> Result<()> {
     assert!(chunks.len() > 0);
     debug_assert!(
@@ -91,6 +131,10 @@ pub async fn download_contiguous_chunks(
                 return Ok(());
             }
             Err(e) => {
+                if let DownloadChunkError::IoError(io_error) = &e {
+                    handle_cache_io_error(io_error, &sweep_notify);
+                }
+
                 if let Some(backoff) = e.get_backoff(attempts, &ratelimiter) {
                     tracing::warn!(
                         "Error downloading chunks, retrying in {} seconds (attempt {}): {}",
@@ -109,11 +153,29 @@ pub async fn download_contiguous_chunks(
     }
 }
 
+/// Turns a full or read-only cache disk into a clear error instead of a stream of failed downloads.
+fn handle_cache_io_error(error: &std::io::Error, sweep_notify: &Notify) {
+    match error.kind() {
+        ErrorKind::StorageFull => {
+            tracing::error!("the cache disk is full, requesting an emergency cache sweep");
+            sweep_notify.notify_one();
+        }
+        ErrorKind::ReadOnlyFilesystem => {
+            let cache_dir = get_config().cache_dir.as_ref().unwrap();
+            tracing::error!(
+                "the cache directory {} is read-only, uncached files can't be read until it is writable again",
+                cache_dir.display()
+            );
+        }
+        _ => {}
+    }
+}
+
 async fn download_contiguous_chunks_inner(
     chunks: &Vec<(OwnedMutexGuard<()>, Arc<Chunk>)>,
     entry: &Arc<CacheEntry>,
     ratelimiter: &Arc<Ratelimiter>,
-    debrid: &Arc<Debrid>,
+    debrid: &Arc<dyn DebridProvider>,
 ) -> Result<(), DownloadChunkError> {
     // Get the first and last chunk to determine the entire range
     let first_chunk = &chunks.first().unwrap().1;
@@ -123,22 +185,13 @@ async fn download_contiguous_chunks_inner(
 
     let config = get_config();
     let file = entry.get_file();
-    let (url, auth) = match (&config.torbox_username, &config.torbox_password) {
-        (Some(username), Some(password)) => {
+    let (url, auth) = match (config.download_mode, &config.torbox_username, &config.torbox_password) {
+        (DownloadMode::Auto | DownloadMode::Webdav, Some(username), Some(password)) => {
             // with a username nad password, we can use webdav instead which avoids us having to get download links
-            // webdav urls should just be https://webdav.torbox.app/{file_path} with url encoding but `/` not encoded
-            // and the username+pass for basic auth
-            let path = file
-                .path
-                .split('/')
-                .map(|s| urlencoding::encode(s).to_string())
-                .collect::<Vec<_>>()
-                .join("/");
-
-            let url = format!("https://webdav.torbox.app/{}", path);
+            let url = entry.get_webdav_url().to_string();
             (url, Some((username, password)))
         }
-        (None, None) => {
+        (DownloadMode::Auto, None, None) | (DownloadMode::Link, _, _) => {
             // todo: this should really be a FetchError, but the inner type is not compatible with
             // anyhow and making it return a "real" type is borderline impossible. :(
             let url = debrid
@@ -163,11 +216,7 @@ async fn download_contiguous_chunks_inner(
         range
     );
 
-    let client = reqwest::Client::new();
-    let mut builder = client
-        .get(&url)
-        .header("Range", range)
-        .header("User-Agent", get_user_agent());
+    let mut builder = DOWNLOAD_CLIENT.get(&url).header("Range", range);
 
     if let Some(auth) = auth {
         let (username, password) = auth;
@@ -175,7 +224,11 @@ async fn download_contiguous_chunks_inner(
     };
 
     let permit = ratelimiter.wait().await;
+    let started_at = Instant::now();
     let response = builder.send().await.map_err(DownloadChunkError::FetchError)?;
+    if let Some((remaining, reset_secs)) = parse_ratelimit_budget(response.headers()) {
+        ratelimiter.update_budget(remaining, reset_secs);
+    }
 
     match response.status() {
         StatusCode::PARTIAL_CONTENT => {}
@@ -189,9 +242,8 @@ async fn download_contiguous_chunks_inner(
 
             return Err(DownloadChunkError::Ratelimited(retry_after));
         }
-        // todo: INTERNAL_SERVER_ERROR is not included because usually those aren't retryable,
-        // but this needs some more testing before we know for sure.
-        StatusCode::REQUEST_TIMEOUT
+        StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::REQUEST_TIMEOUT
         | StatusCode::BAD_GATEWAY
         | StatusCode::SERVICE_UNAVAILABLE
         | StatusCode::GATEWAY_TIMEOUT => {
@@ -209,16 +261,31 @@ async fn download_contiguous_chunks_inner(
         }
     }
 
-    // assert!(response.status() == StatusCode::PARTIAL_CONTENT);
+    // servers that ignore or clamp the range would otherwise have their response written over the wrong chunks.
+    // a missing Content-Range is left to the length checks below.
+    if let Some(content_range) = response.headers().get("Content-Range") {
+        let content_range = content_range.to_str().unwrap_or_default();
+        if parse_content_range(content_range) != Some((start_offset, end_offset)) {
+            return Err(DownloadChunkError::RangeMismatch(
+                content_range.to_string(),
+                format!("{}-{}", start_offset, end_offset),
+            ));
+        }
+    }
+
+    let expected_length = end_offset - start_offset + 1;
     let content_length = response
         .headers()
         .get("Content-Length")
         .and_then(|h| h.to_str().ok())
-        .and_then(|h| h.parse::<u64>().ok())
-        .unwrap_or(0);
+        .and_then(|h| h.parse::<u64>().ok());
 
-    let expected_length = end_offset - start_offset + 1;
-    assert!(content_length == expected_length);
+    if let Some(content_length) = content_length.filter(|length| *length != expected_length) {
+        return Err(DownloadChunkError::LengthMismatch {
+            expected: expected_length,
+            received: content_length,
+        });
+    }
 
     let mut fd = tokio::fs::OpenOptions::new()
         .read(true)
@@ -229,6 +296,13 @@ async fn download_contiguous_chunks_inner(
 
     fd.seek(SeekFrom::Start(start_offset)).await?;
 
+    let verify = get_config().chunk_verify;
+    for (_, chunk) in chunks {
+        chunk.written_bytes.store(0, Ordering::Release);
+        chunk.checksum.store(0, Ordering::SeqCst);
+        chunk.verified.store(false, Ordering::SeqCst);
+    }
+
     let mut response_stream = response.bytes_stream();
     let mut bytes_written = 0u64;
     let mut current_chunk_index = 0;
@@ -238,20 +312,56 @@ async fn download_contiguous_chunks_inner(
 
     while let Some(block) = response_stream.next().await {
         let block = block.map_err(DownloadChunkError::StreamError)?;
+        if bytes_written + block.len() as u64 > expected_length {
+            return Err(DownloadChunkError::LengthMismatch {
+                expected: expected_length,
+                received: bytes_written + block.len() as u64,
+            });
+        }
 
         fd.write_all(&block).await?;
         bytes_written += block.len() as u64;
 
         // Check if we've completed writing the current chunk
         let current_offset = start_offset + bytes_written;
+        if let Some((_, chunk)) = chunks.get(current_chunk_index).filter(|_| !entry.is_compressed()) {
+            // lets reads of the start of the chunk be served before it's finished.
+            // compressed chunks are rewritten once they're finished, so they can't be read early.
+            let written = (current_offset - chunk.offset).min(chunk.size);
+            chunk.written_bytes.store(written, Ordering::Release);
+        }
 
         // Mark chunks as cached as soon as they're fully downloaded
         while current_offset >= current_chunk_end_offset && current_chunk_index < chunks.len() {
+            if entry.is_compressed() || verify {
+                // the chunk has to be written out before it can be read back to be hashed or compressed
+                fd.flush().await?;
+                let path = entry.get_cache_path();
+                let chunk = chunks[current_chunk_index].1.clone();
+                let compress = entry.is_compressed();
+                tokio::task::spawn_blocking(move || {
+                    if verify {
+                        chunk.checksum.store(hash_chunk(&path, &chunk)?, Ordering::SeqCst);
+                        chunk.verified.store(true, Ordering::SeqCst);
+                    }
+
+                    if compress {
+                        compress_chunk(&path, &chunk)?;
+                    }
+
+                    std::io::Result::Ok(())
+                })
+                .await
+                .map_err(std::io::Error::other)??;
+            }
+
             // Mark this chunk as cached
             chunks[current_chunk_index].1.cached.store(true, Ordering::SeqCst);
+            chunks[current_chunk_index].1.written_bytes.store(0, Ordering::Release);
 
             entry
                 .flush_cache_meta()
+                .await
                 .map_err(|e| {
                     tracing::error!("Failed to flush metadata: {}, this will cause cache issues", e);
                 })
@@ -271,6 +381,15 @@ async fn download_contiguous_chunks_inner(
     }
 
     drop(permit);
+    if bytes_written != expected_length {
+        // the stream ended early, the chunks that weren't finished are left uncached to be downloaded again
+        return Err(DownloadChunkError::LengthMismatch {
+            expected: expected_length,
+            received: bytes_written,
+        });
+    }
+
+    entry.record_download(bytes_written, started_at.elapsed());
     fd.flush().await?;
     fd.sync_all().await?;
     drop(fd);
@@ -283,3 +402,51 @@ async fn download_contiguous_chunks_inner(
 
     Ok(())
 }
+
+/// Parses a `Content-Range` header like `bytes 0-1023/4096` into its inclusive start and end offsets.
+fn parse_content_range(content_range: &str) -> Option<(u64, u64)> {
+    let range = content_range.strip_prefix("bytes ")?;
+    let (range, _total) = range.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+/// Reads the `x-ratelimit-remaining`/`x-ratelimit-reset` headers, if the server sent them.
+/// The reset can either be seconds until the window resets, or a unix timestamp.
+fn parse_ratelimit_budget(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let get_header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+
+    let remaining = get_header("x-ratelimit-remaining")?;
+    let reset_secs = get_header("x-ratelimit-reset").map(|reset| {
+        // anything this large can't be a relative number of seconds
+        if reset > 1_000_000_000 {
+            let now = chrono::Utc::now().timestamp() as u64;
+            reset.saturating_sub(now)
+        } else {
+            reset
+        }
+    });
+
+    Some((remaining, reset_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 0-1023/4096"), Some((0, 1023)));
+        assert_eq!(
+            parse_content_range("bytes 8388608-16777215/*"),
+            Some((8388608, 16777215))
+        );
+        assert_eq!(parse_content_range("bytes */4096"), None);
+        assert_eq!(parse_content_range("0-1023/4096"), None);
+    }
+}
@@ -1,65 +1,125 @@
 use super::{
-    chunk::{Chunk, DEFAULT_CHUNK_SIZE, deserialize_chunks, get_chunk_size_from_index, serialize_chunks},
+    chunk::{Chunk, create_chunks, deserialize_chunks, read_chunks, serialize_chunks, verify_chunk},
     downloader::download_contiguous_chunks,
     ratelimiter::Ratelimiter,
-    reader::Readers,
+    reader::{Reader, Readers},
+};
+use crate::{
+    cache::CacheFile,
+    config::{Config, get_config},
+    debrid::DebridProvider,
 };
-use crate::{cache::CacheFile, config::get_config, debrid::Debrid};
 use anyhow::Result;
 use std::{
     io::SeekFrom,
-    path::PathBuf,
-    sync::{Arc, atomic::Ordering},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
+use thiserror::Error;
 use tokio::{
     io::{AsyncReadExt, AsyncSeekExt},
-    sync::OwnedMutexGuard,
-    time::sleep,
+    sync::{Notify, OwnedMutexGuard, Semaphore},
+    task::{AbortHandle, JoinHandle},
+    time::{Instant, sleep},
 };
 
 // read ahead is essentially when we are within READ_AHEAD_START_BYTES of the start of uncached chunks,
 // we download enough chunks to have a buffer of READ_AHEAD_TARGET_BYTES bytes.
+// once we know how fast we can download vs how fast the reader is consuming the file, the target
+// is scaled between READ_AHEAD_MIN_TARGET_BYTES and READ_AHEAD_MAX_TARGET_BYTES.
 const READ_AHEAD_START_BYTES: u64 = 24 * 1024 * 1024;
 const READ_AHEAD_TARGET_BYTES: u64 = 64 * 1024 * 1024;
+const READ_AHEAD_MIN_TARGET_BYTES: u64 = 32 * 1024 * 1024;
+const READ_AHEAD_MAX_TARGET_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CacheReadError {
+    #[error("timed out waiting for chunk {0} to download")]
+    Timeout(u64),
+    #[error("chunk {0} is not cached and is not downloading, it might have failed to download")]
+    DownloadFailed(u64),
+    #[error("failed to read cached data: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("read of {size} bytes at offset {offset} is outside of the file ({file_size} bytes)")]
+    OutOfRange { offset: u64, size: u64, file_size: u64 },
+    #[error("chunks {start}-{end} are outside of the chunk list ({chunk_count} chunks)")]
+    ChunkOutOfRange { start: u64, end: u64, chunk_count: u64 },
+    #[error("file is not available on the debrid service yet")]
+    NotReady,
+}
+
+/// A read ahead download that was started for a reader, so it can be cancelled if the reader goes away.
+struct ReadAheadDownload {
+    reader_id: u64,
+    chunk_count: u64,
+    start_offset: u64,
+    end_offset: u64,
+    handle: AbortHandle,
+}
 
 pub struct CacheEntry {
     file: CacheFile,
     readers: Readers,
+    read_ahead_downloads: Mutex<Vec<ReadAheadDownload>>,
     chunks: Vec<Arc<Chunk>>,
-    debrid: Arc<Debrid>,
+    // flushes all write to the same temporary file, so they can't run at the same time
+    meta_lock: Mutex<()>,
+    // from the cachemeta, so entries created before chunk_size was changed keep working
+    chunk_size: u64,
+    // the preload range for this file, see get_file_chunk_preload
+    chunk_preload: Option<(u64, u64)>,
+    // whether chunks are compressed once downloaded, see compress_extensions
+    compressed: bool,
+    debrid: Arc<dyn DebridProvider>,
     ratelimiter: Arc<Ratelimiter>,
+    sweep_notify: Arc<Notify>,
+    // bounds the download batches in flight for this file, see spawn_file_download
+    download_batches: Arc<Semaphore>,
+    // moving average of the download throughput for this file, 0 if nothing has been downloaded yet
+    download_bytes_per_sec: AtomicU64,
+    // playback duration of the file, 0 if unknown
+    duration_hint_secs: AtomicU64,
+    webdav_url: OnceLock<String>,
 }
 
 impl CacheEntry {
-    pub fn load(file: CacheFile, debrid: Arc<Debrid>, ratelimiter: Arc<Ratelimiter>) -> Self {
-        let readers = Readers::new();
-        let meta_path = get_config()
-            .cache_dir
-            .as_ref()
-            .unwrap()
-            .join(format!("{}.cachemeta", file.id));
-
-        let chunks = if meta_path.exists() {
-            let mut file = std::fs::File::open(&meta_path).unwrap();
-            deserialize_chunks(&mut file).expect("failed to deserialize chunk metadata")
-        } else {
-            let mut chunks = Vec::new();
-            let total_chunks = (file.size as u64 + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE;
-            for i in 0..total_chunks {
-                let chunk_size = get_chunk_size_from_index(i, file.size as u64);
-                let chunk = Chunk::new(i, chunk_size);
-                chunks.push(Arc::new(chunk));
-            }
+    pub fn load(
+        file: CacheFile,
+        debrid: Arc<dyn DebridProvider>,
+        ratelimiter: Arc<Ratelimiter>,
+        sweep_notify: Arc<Notify>,
+    ) -> Self {
+        let config = get_config();
+        let readers = Readers::new(config.reader_merge_gap_forward, config.reader_merge_gap_backward);
+        let cache_dir = config.cache_dir.as_ref().unwrap();
+        let data_path = cache_dir.join(format!("{}.bin", file.id));
+        let meta_path = cache_dir.join(format!("{}.cachemeta", file.id));
 
-            chunks
-        };
+        let (chunk_size, chunks) = load_chunks(&meta_path, &data_path, file.size as u64, config.chunk_size);
+        let chunk_preload = get_file_chunk_preload(config, &file.path, file.size as u64);
+        let compressed = has_extension(&file.path, &config.compress_extensions);
+        let duration_hint_secs = file.duration_hint_secs.unwrap_or(0).max(0) as u64;
 
         Self {
             file,
             debrid,
             ratelimiter,
+            sweep_notify,
             readers,
+            read_ahead_downloads: Mutex::new(Vec::new()),
+            download_batches: Arc::new(Semaphore::new(config.max_file_download_batches)),
             chunks,
+            meta_lock: Mutex::new(()),
+            chunk_size,
+            chunk_preload,
+            compressed,
+            download_bytes_per_sec: AtomicU64::new(0),
+            duration_hint_secs: AtomicU64::new(duration_hint_secs),
+            webdav_url: OnceLock::new(),
         }
     }
 
@@ -79,15 +139,56 @@ impl CacheEntry {
         &self.chunks
     }
 
+    /// Whether chunks are compressed once they're downloaded, see `compress_extensions`.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn get_cached_bytes(&self) -> u64 {
+        get_cached_bytes(&self.chunks)
+    }
+
+    pub fn get_webdav_url(&self) -> &str {
+        self.webdav_url
+            .get_or_init(|| build_webdav_url(&get_config().torbox_webdav_url, &self.file.path))
+    }
+
+    pub fn record_download(&self, bytes: u64, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        let sample = (bytes as f64 / elapsed) as u64;
+        let previous = self.download_bytes_per_sec.load(Ordering::Relaxed);
+        let average = if previous == 0 {
+            sample
+        } else {
+            (previous * 3 + sample) / 4
+        };
+
+        self.download_bytes_per_sec.store(average, Ordering::Relaxed);
+    }
+
+    /// Sets the playback duration used to estimate the bitrate of the file, None clears it.
+    pub fn set_duration_hint_secs(&self, duration_secs: Option<u64>) {
+        self.duration_hint_secs
+            .store(duration_secs.unwrap_or(0), Ordering::Relaxed);
+    }
+
     fn get_meta_path(&self) -> PathBuf {
         self.get_cache_path().with_extension("cachemeta")
     }
 
-    pub fn flush_cache_meta(&self) -> Result<()> {
-        let meta_path = self.get_meta_path();
-        let mut file = std::fs::File::create(&meta_path).unwrap();
-        serialize_chunks(&self.chunks, &mut file)?;
-        Ok(())
+    /// Writes the chunk metadata on a blocking thread, it's synced to disk so it isn't lost on a crash.
+    pub async fn flush_cache_meta(self: &Arc<Self>) -> Result<()> {
+        let entry = self.clone();
+        tokio::task::spawn_blocking(move || entry.flush_cache_meta_blocking()).await?
+    }
+
+    pub fn flush_cache_meta_blocking(&self) -> Result<()> {
+        let _guard = self.meta_lock.lock().unwrap();
+        write_chunks(&self.get_meta_path(), self.chunk_size, &self.chunks)
     }
 
     pub async fn try_remove(&self) -> Result<bool> {
@@ -106,32 +207,29 @@ impl CacheEntry {
         Ok(true)
     }
 
-    fn get_preload_chunks(&self) -> Option<Vec<Arc<Chunk>>> {
-        let config = get_config();
-        if let Some(preload) = config.chunk_preload {
-            let total_chunks = self.chunks.len() as u64;
-            if total_chunks <= preload.0 + preload.1 + 1 {
-                return Some(self.chunks.clone());
-            }
-
-            let preload_end_index = total_chunks - preload.1;
-            let start_chunks = &self.chunks[0..=preload.0 as usize];
-            let end_chunks = &self.chunks[preload_end_index as usize..];
-            return Some(
-                start_chunks
-                    .iter()
-                    .chain(end_chunks.iter())
-                    .cloned()
-                    .collect::<Vec<_>>(),
-            );
-        }
+    pub fn get_chunk_preload(&self) -> Option<(u64, u64)> {
+        self.chunk_preload
+    }
 
-        None
+    fn get_preload_chunks(&self) -> Option<Vec<Arc<Chunk>>> {
+        self.chunk_preload
+            .map(|preload| select_preload_chunks(&self.chunks, preload))
     }
 
-    fn get_read_ahead_chunks(&self, current_chunk_idx: u64, reader_bytes_read: u64) -> Option<Vec<Arc<Chunk>>> {
-        let read_ahead_trigger_chunks = (READ_AHEAD_START_BYTES / DEFAULT_CHUNK_SIZE).max(1);
-        let read_ahead_target_chunks = (READ_AHEAD_TARGET_BYTES / DEFAULT_CHUNK_SIZE).max(2);
+    fn get_read_ahead_chunks(&self, current_chunk_idx: u64, reader: &Reader) -> Option<Vec<Arc<Chunk>>> {
+        let download_bytes_per_sec = match self.download_bytes_per_sec.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        };
+
+        // until the reader has been measured, the bitrate of the file is the best guess at how fast it's read
+        let duration_hint_secs = self.duration_hint_secs.load(Ordering::Relaxed);
+        let read_bytes_per_sec = reader
+            .bytes_per_sec()
+            .or_else(|| get_bitrate_from_duration(self.file.size as u64, duration_hint_secs));
+        let target_bytes = get_read_ahead_target_bytes(download_bytes_per_sec, read_bytes_per_sec);
+        let read_ahead_trigger_chunks = (READ_AHEAD_START_BYTES / self.chunk_size).max(1);
+        let read_ahead_target_chunks = (target_bytes / self.chunk_size).max(2);
         tracing::trace!("read ahead trigger: {} chunks", read_ahead_trigger_chunks);
         tracing::trace!("read ahead target: {} chunks", read_ahead_target_chunks);
 
@@ -176,54 +274,48 @@ impl CacheEntry {
         }
     }
 
-    pub async fn read_bytes(self: &Arc<Self>, offset: u64, size: u64) -> Result<Vec<u8>> {
+    pub async fn read_bytes(self: &Arc<Self>, offset: u64, size: u64) -> Result<Vec<u8>, CacheReadError> {
+        let file_size = self.file.size as u64;
+        if offset + size > file_size {
+            return Err(CacheReadError::OutOfRange {
+                offset,
+                size,
+                file_size,
+            });
+        }
+
+        self.cancel_idle_read_ahead();
         let reader = self.readers.get_reader(offset, size);
 
-        let start_chunk_index = offset / DEFAULT_CHUNK_SIZE;
-        let end_chunk_index = (offset + size - 1) / DEFAULT_CHUNK_SIZE;
+        let start_chunk_index = offset / self.chunk_size;
+        let end_chunk_index = (offset + size - 1) / self.chunk_size;
 
-        let mut chunks_to_queue = self.chunks[start_chunk_index as usize..=end_chunk_index as usize]
-            .iter()
-            .map(|c| c.clone())
-            .collect::<Vec<_>>();
+        let necessary_chunks = get_chunk_range(&self.chunks, start_chunk_index, end_chunk_index)?;
 
         tracing::trace!("Current chunks: {}-{}", start_chunk_index, end_chunk_index);
-        let config = get_config();
-        let mut is_in_preload = false;
-        if let Some(preload) = config.chunk_preload {
-            // if the requested range is within the preload values,
-            // add the preload chunks to the chunk list.
-            let preload_end_index = self.chunks.len() as u64 - preload.1;
-            if start_chunk_index <= preload.0 || end_chunk_index >= preload_end_index {
-                let mut preload_chunks = Vec::new();
-                for chunk in self.get_preload_chunks().unwrap() {
-                    if chunk.index >= start_chunk_index && chunk.index <= end_chunk_index {
-                        // don't queue the chunk if its already in the list
-                        continue;
-                    }
 
-                    is_in_preload = true;
-                    preload_chunks.push(chunk.index);
-                    chunks_to_queue.push(chunk);
-                }
+        let (preload_chunks, is_in_preload) =
+            get_read_preload_chunks(&self.chunks, self.chunk_preload, start_chunk_index, end_chunk_index);
+        if !preload_chunks.is_empty() {
+            tracing::trace!(
+                "Added preload chunks: {:#?}",
+                preload_chunks.iter().map(|c| c.index).collect::<Vec<_>>()
+            );
+        }
 
-                tracing::trace!("Added preload chunks: {:#?}", preload_chunks);
-            }
+        // the chunks this read is waiting on skip the file's batch limit, so they never wait for
+        // preload and read ahead batches that are already queued for a slot.
+        let (needed_batches, preload_batches) = get_read_batches(necessary_chunks, preload_chunks);
+        for batch in needed_batches {
+            self.pinch_chunk_batch(batch, true);
         }
 
-        // this ensures that when crossing from preload chunks to normal chunks,
-        // we don't freeze the stream because the user passed from preload to uncached normal chunks.
-        let force_read_ahead = self
-            .chunks
-            .get(end_chunk_index as usize + 1)
-            .map(|c| !c.is_cached_or_downloading())
-            .unwrap_or(false);
+        for batch in preload_batches {
+            self.pinch_chunk_batch(batch, false);
+        }
 
-        // we only use read ahead if we aren't in the preload chunks, or else
-        // a tool like ffprobe would trigger read ahead and download 10 chunks instead
-        // of using the preload chunks like it should.
-        if !is_in_preload || force_read_ahead {
-            let read_ahead_chunks = self.get_read_ahead_chunks(end_chunk_index, reader.bytes_read);
+        if should_read_ahead(&self.chunks, end_chunk_index, is_in_preload) {
+            let read_ahead_chunks = self.get_read_ahead_chunks(end_chunk_index, &reader);
             // tracing::trace!("Read ahead chunks: {:#?}", read_ahead_chunks);
             // tracing::trace!("Consecutive reads: {}", consecutive_reads);
             if let Some(read_ahead_chunks) = read_ahead_chunks {
@@ -231,22 +323,36 @@ impl CacheEntry {
                     "Adding read ahead chunks: {:#?}",
                     read_ahead_chunks.iter().map(|c| c.index).collect::<Vec<_>>()
                 );
-                chunks_to_queue.extend(read_ahead_chunks);
-                // chunks_to_queue.sort_by_key(|c| c.index);
+                self.queue_read_ahead_chunks(reader.id, read_ahead_chunks);
             } else {
                 tracing::trace!("No read ahead chunks calculated");
             }
         }
 
-        self.queue_chunks(chunks_to_queue)?;
-
         // we might ensure multiple chunks (for read ahead/preload), but we only need
         // probably 1-2 chunks, so we can skip waiting for the rest.
-        let necessary_chunks = &self.chunks[start_chunk_index as usize..=end_chunk_index as usize];
-        self.wait_for_chunks(necessary_chunks).await?;
+        self.wait_for_chunks(necessary_chunks, offset, size).await?;
+        if get_config().chunk_verify {
+            self.verify_chunks(necessary_chunks, offset, size).await?;
+        }
+
+        for chunk in necessary_chunks {
+            // the sweeper orders by access time, so this has to reflect reads and not just downloads
+            chunk.touch();
+        }
 
         // let mut data = Vec::with_capacity(size as usize);
         let cache_path = self.get_cache_path();
+        let is_compressed = |chunk: &Arc<Chunk>| chunk.compressed_size.load(Ordering::SeqCst) > 0;
+        if necessary_chunks.iter().any(is_compressed) {
+            let chunks = necessary_chunks.to_vec();
+            let buffer = tokio::task::spawn_blocking(move || read_chunks(&cache_path, &chunks, offset, size))
+                .await
+                .map_err(std::io::Error::other)??;
+
+            return Ok(buffer);
+        }
+
         let mut fd = tokio::fs::OpenOptions::new()
             .read(true)
             .write(false)
@@ -261,7 +367,83 @@ impl CacheEntry {
         Ok(buffer)
     }
 
-    async fn wait_for_chunks(self: &Arc<Self>, chunks: &[Arc<Chunk>]) -> Result<()> {
+    /// Checks chunks against their checksum the first time they're read. Chunks that don't match are
+    /// marked as uncached and downloaded again, so corrupted data is never returned.
+    async fn verify_chunks(
+        self: &Arc<Self>,
+        chunks: &[Arc<Chunk>],
+        offset: u64,
+        size: u64,
+    ) -> Result<(), CacheReadError> {
+        let unverified = chunks
+            .iter()
+            .filter(|chunk| chunk.cached.load(Ordering::SeqCst) && !chunk.verified.load(Ordering::SeqCst))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if unverified.is_empty() {
+            return Ok(());
+        }
+
+        let cache_path = self.get_cache_path();
+        let corrupted = tokio::task::spawn_blocking(move || {
+            let mut corrupted = Vec::new();
+            for chunk in unverified {
+                if !verify_chunk(&cache_path, &chunk)? {
+                    corrupted.push(chunk);
+                }
+            }
+
+            std::io::Result::Ok(corrupted)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        if corrupted.is_empty() {
+            return Ok(());
+        }
+
+        for chunk in &corrupted {
+            tracing::warn!(
+                "chunk {} of file {} doesn't match its checksum, downloading it again",
+                chunk.index,
+                self.file.id
+            );
+
+            chunk.cached.store(false, Ordering::SeqCst);
+            chunk.compressed_size.store(0, Ordering::SeqCst);
+            chunk.checksum.store(0, Ordering::SeqCst);
+        }
+
+        self.flush_cache_meta()
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to flush metadata: {}", e);
+            })
+            .ok();
+
+        for batch in get_chunk_batches(corrupted) {
+            self.pinch_chunk_batch(batch, true);
+        }
+
+        self.wait_for_chunks(chunks, offset, size).await
+    }
+
+    /// Failed downloads are re-queued up to `read_retries` times per read, on top of the downloader's
+    /// own retries. The read timeout covers the whole read including retries, so a read never takes longer
+    /// than `read_timeout_secs` no matter how many retries are left.
+    /// With `read_while_downloading`, a chunk that's still downloading doesn't have to be waited for once the
+    /// part of `offset..offset + size` inside it has been written.
+    async fn wait_for_chunks(
+        self: &Arc<Self>,
+        chunks: &[Arc<Chunk>],
+        offset: u64,
+        size: u64,
+    ) -> Result<(), CacheReadError> {
+        let config = get_config();
+        let timeout = Duration::from_secs(config.read_timeout_secs);
+        let mut retries_left = config.read_retries;
+        let started_at = Instant::now();
         for chunk in chunks {
             loop {
                 // the lock wont be released once the chunk is finished downloading because of
@@ -273,12 +455,32 @@ impl CacheEntry {
                     break;
                 }
 
+                // the watermark is only trusted while the lock is held, it's reset when a download starts.
+                if config.read_while_downloading && maybe_lock.is_err() && chunk.is_range_written(offset, size) {
+                    break;
+                }
+
                 if maybe_lock.is_ok() {
                     // if we can acquire the lock we know the download failed.
-                    anyhow::bail!(
-                        "Chunk {} is not cached and is not downloading. It might have failed to download.",
-                        chunk.index
+                    if retries_left == 0 {
+                        return Err(CacheReadError::DownloadFailed(chunk.index));
+                    }
+
+                    tracing::warn!(
+                        "chunk {} for file {} failed to download, retrying ({} retries left)",
+                        chunk.index,
+                        self.file.id,
+                        retries_left
                     );
+
+                    retries_left -= 1;
+                    drop(maybe_lock);
+                    self.queue_chunks(vec![chunk.clone()]);
+                    continue;
+                }
+
+                if started_at.elapsed() > timeout {
+                    return Err(CacheReadError::Timeout(chunk.index));
                 }
 
                 // if the chunk is not cached and is downloading, we wait for it to finish
@@ -289,64 +491,831 @@ impl CacheEntry {
         Ok(())
     }
 
-    fn queue_chunks(self: &Arc<Self>, mut chunks: Vec<Arc<Chunk>>) -> Result<()> {
-        // todo: this is inefficient, we should just assert that these are true and do it as
-        // we build the chunk list, but for now this is fine.
-        // sort the chunks by index
-        chunks.sort_by_key(|c| c.index);
-        // remove duplicates
-        chunks.dedup();
+    /// Starts downloading the preload chunks without waiting for them, chunks that are already
+    /// cached or downloading are skipped.
+    pub fn queue_preload_chunks(self: &Arc<Self>) {
+        if let Some(chunks) = self.get_preload_chunks() {
+            tracing::debug!("queueing preload chunks for file {}", self.file.id);
+            self.queue_chunks(chunks);
+        }
+    }
 
-        let mut current_batch: Vec<(OwnedMutexGuard<()>, Arc<Chunk>)> = Vec::new();
-        for chunk in chunks {
-            let is_cached = chunk.cached.load(Ordering::SeqCst);
-            if is_cached {
-                continue;
-            }
+    fn queue_chunks(self: &Arc<Self>, chunks: Vec<Arc<Chunk>>) {
+        for batch in get_chunk_batches(chunks) {
+            self.pinch_chunk_batch(batch, false);
+        }
+    }
 
-            let download_lock = chunk.downloading.clone().try_lock_owned();
-            let Ok(download_lock) = download_lock else {
-                // chunk is already being downloaded by something else, we can skip it
-                continue;
-            };
+    /// Read ahead is queued separately from the chunks a read is waiting on, so that it can be
+    /// cancelled without affecting the read.
+    ///
+    /// At most `max_read_ahead_chunks` read ahead chunks can be in flight for a file, the chunks closest
+    /// to the reader are kept when over the limit. This only bounds read ahead, the chunks reads are
+    /// waiting on are never limited. Every batch still goes through the global request limit, so this
+    /// stops one chaotic reader from filling the global queue with read ahead for a single file.
+    fn queue_read_ahead_chunks(self: &Arc<Self>, reader_id: u64, mut chunks: Vec<Arc<Chunk>>) {
+        let mut downloads = self.read_ahead_downloads.lock().unwrap();
+        downloads.retain(|download| !download.handle.is_finished());
 
-            debug_assert!(chunk.cached.load(Ordering::SeqCst) == false);
+        let in_flight = downloads.iter().map(|download| download.chunk_count).sum::<u64>();
+        let available = get_config().max_read_ahead_chunks.saturating_sub(in_flight);
+        if (chunks.len() as u64) > available {
+            tracing::debug!(
+                "read ahead for file {} is limited to {} chunks, {} are already in flight",
+                self.file.id,
+                available,
+                in_flight
+            );
 
-            if let Some((_, last)) = current_batch.last() {
-                if last.index != chunk.index - 1 {
-                    // if the previous chunk is not contiguous, we have to spawn the batch
-                    // and start a new one.
-                    tracing::warn!(
-                        "Chunk {} is not contiguous with previous chunk {}",
-                        chunk.index,
-                        last.index
-                    );
-                    self.pinch_chunk_batch(current_batch);
-                    current_batch = Vec::new();
-                }
-            }
+            chunks.truncate(available as usize);
+        }
 
-            current_batch.push((download_lock, chunk.clone()));
+        for batch in get_chunk_batches(chunks) {
+            let start_offset = batch.first().unwrap().1.offset;
+            let last_chunk = &batch.last().unwrap().1;
+            let end_offset = last_chunk.offset + last_chunk.size;
+            downloads.push(ReadAheadDownload {
+                reader_id,
+                chunk_count: batch.len() as u64,
+                start_offset,
+                end_offset,
+                handle: self.pinch_chunk_batch(batch, false),
+            });
         }
+    }
 
-        if !current_batch.is_empty() {
-            self.pinch_chunk_batch(current_batch);
+    pub fn get_reader_positions(&self) -> Vec<u64> {
+        self.readers.get_positions()
+    }
+
+    /// Cancels read ahead for readers that have stopped reading (playback stopped, or the player seeked
+    /// somewhere else). Downloads are kept if another reader is close enough that it would read them.
+    pub fn cancel_idle_read_ahead(&self) {
+        let idle_for = Duration::from_secs(get_config().read_ahead_idle_secs);
+        let idle_readers = self.readers.remove_idle(idle_for);
+        if idle_readers.is_empty() {
+            return;
         }
 
-        Ok(())
+        let positions = self.readers.get_positions();
+        let mut downloads = self.read_ahead_downloads.lock().unwrap();
+        downloads.retain(|download| {
+            if download.handle.is_finished() {
+                return false;
+            }
+
+            if !idle_readers.contains(&download.reader_id)
+                || is_needed_by_readers(&positions, download.start_offset, download.end_offset)
+            {
+                return true;
+            }
+
+            tracing::debug!(
+                "cancelling read ahead of bytes {}-{} for file {}, reader {} is idle",
+                download.start_offset,
+                download.end_offset,
+                self.file.id,
+                download.reader_id
+            );
+
+            // the chunk guards are dropped with the task, so the chunks can be downloaded again later
+            download.handle.abort();
+            false
+        });
     }
 
-    fn pinch_chunk_batch(self: &Arc<Self>, chunks: Vec<(OwnedMutexGuard<()>, Arc<Chunk>)>) {
-        tokio::spawn({
+    /// `needed` is for chunks a read is waiting on, which aren't limited by `max_file_download_batches`.
+    fn pinch_chunk_batch(
+        self: &Arc<Self>,
+        chunks: Vec<(OwnedMutexGuard<()>, Arc<Chunk>)>,
+        needed: bool,
+    ) -> AbortHandle {
+        let download_batches = (!needed).then(|| self.download_batches.clone());
+        let handle = spawn_file_download(download_batches, {
             let file = self.clone();
             let ratelimiter = self.ratelimiter.clone();
             let debrid = self.debrid.clone();
+            let sweep_notify = self.sweep_notify.clone();
             async move {
-                let result = download_contiguous_chunks(chunks, file, ratelimiter, debrid).await;
+                let result = download_contiguous_chunks(chunks, file, ratelimiter, debrid, sweep_notify).await;
                 if let Err(e) = result {
                     tracing::error!("Failed to download chunks: {}", e);
                 }
             }
         });
+
+        handle.abort_handle()
+    }
+}
+
+/// Runs a download once the file has a free batch slot. Scattered reads (several players, or one seeking
+/// around a lot) would otherwise each start their own request and compete for the connection and disk.
+/// The batch keeps its chunks locked while it waits, so other reads wait for it instead of queueing them again.
+/// This is on top of the global request limit in the ratelimiter, which is shared by every file.
+/// Without `download_batches` the download starts straight away, for chunks a read is waiting on.
+fn spawn_file_download<F>(download_batches: Option<Arc<Semaphore>>, download: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let _permit = match download_batches {
+            Some(download_batches) => Some(download_batches.acquire_owned().await.unwrap()),
+            None => None,
+        };
+
+        download.await;
+    })
+}
+
+/// WebDAV urls are {webdav_url}/{file_path}, with each path segment url encoded.
+fn build_webdav_url(webdav_url: &str, path: &str) -> String {
+    let path = path
+        .split('/')
+        .map(|s| urlencoding::encode(s).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("{}/{}", webdav_url, path)
+}
+
+fn get_cached_bytes(chunks: &[Arc<Chunk>]) -> u64 {
+    chunks
+        .iter()
+        .filter(|chunk| chunk.cached.load(Ordering::Relaxed))
+        .map(|chunk| chunk.size)
+        .sum()
+}
+
+/// Whether any reader is positioned so that the bytes would be part of its read ahead.
+fn is_needed_by_readers(positions: &[u64], start_offset: u64, end_offset: u64) -> bool {
+    positions
+        .iter()
+        .any(|&position| position < end_offset && position + READ_AHEAD_MAX_TARGET_BYTES >= start_offset)
+}
+
+type ChunkBatch = Vec<(OwnedMutexGuard<()>, Arc<Chunk>)>;
+
+/// Splits chunks into contiguous batches that can each be fetched with a single request.
+/// Chunks can be passed in any order and with duplicates (preload and read ahead ranges overlap),
+/// chunks that are cached or already being downloaded are never part of a batch.
+fn get_chunk_batches(mut chunks: Vec<Arc<Chunk>>) -> Vec<ChunkBatch> {
+    chunks.sort_by_key(|c| c.index);
+    chunks.dedup();
+
+    let mut batches = Vec::new();
+    let mut current_batch: ChunkBatch = Vec::new();
+    for chunk in chunks {
+        if chunk.cached.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        let download_lock = chunk.downloading.clone().try_lock_owned();
+        let Ok(download_lock) = download_lock else {
+            // chunk is already being downloaded by something else, we can skip it
+            continue;
+        };
+
+        // the chunk could have finished downloading between the check and taking the lock
+        if chunk.cached.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        if let Some((_, last)) = current_batch.last() {
+            if last.index != chunk.index - 1 {
+                // if the previous chunk is not contiguous, we have to start a new batch
+                batches.push(current_batch);
+                current_batch = Vec::new();
+            }
+        }
+
+        current_batch.push((download_lock, chunk));
+    }
+
+    if !current_batch.is_empty() {
+        batches.push(current_batch);
+    }
+
+    batches
+}
+
+/// The preload chunks to queue alongside a read of chunks `start_chunk_index..=end_chunk_index`, and whether
+/// the read is within the preload range. Files that fit in a single chunk (subtitles, etc) skip preload entirely,
+/// the chunk being read is already the entire file.
+fn get_read_preload_chunks(
+    chunks: &[Arc<Chunk>],
+    chunk_preload: Option<(u64, u64)>,
+    start_chunk_index: u64,
+    end_chunk_index: u64,
+) -> (Vec<Arc<Chunk>>, bool) {
+    let mut preload_chunks = Vec::new();
+    let mut is_in_preload = false;
+    let Some(preload) = chunk_preload.filter(|_| chunks.len() > 1) else {
+        return (preload_chunks, is_in_preload);
+    };
+
+    // if the requested range is within the preload values,
+    // add the preload chunks to the chunk list.
+    let preload_end_index = (chunks.len() as u64).saturating_sub(preload.1);
+    if start_chunk_index <= preload.0 || end_chunk_index >= preload_end_index {
+        for chunk in select_preload_chunks(chunks, preload) {
+            if chunk.index >= start_chunk_index && chunk.index <= end_chunk_index {
+                // don't queue the chunk if its already in the list
+                continue;
+            }
+
+            is_in_preload = true;
+            preload_chunks.push(chunk);
+        }
+    }
+
+    (preload_chunks, is_in_preload)
+}
+
+/// Whether a read ending at `end_chunk_index` should queue read ahead. Like preload, files that fit
+/// in a single chunk never read ahead.
+fn should_read_ahead(chunks: &[Arc<Chunk>], end_chunk_index: u64, is_in_preload: bool) -> bool {
+    if chunks.len() == 1 {
+        return false;
+    }
+
+    // this ensures that when crossing from preload chunks to normal chunks,
+    // we don't freeze the stream because the user passed from preload to uncached normal chunks.
+    let force_read_ahead = chunks
+        .get(end_chunk_index as usize + 1)
+        .map(|c| !c.is_cached_or_downloading())
+        .unwrap_or(false);
+
+    // we only use read ahead if we aren't in the preload chunks, or else
+    // a tool like ffprobe would trigger read ahead and download 10 chunks instead
+    // of using the preload chunks like it should.
+    !is_in_preload || force_read_ahead
+}
+
+/// Batches for a read, the chunks the read needs come first and are never batched together with
+/// preload chunks, otherwise a needed chunk could wait for the preload chunks before it in the same request.
+/// Chunks that are cached or already downloading are skipped, including needed chunks that are also preloaded.
+/// Returns the batches of needed chunks and the batches of preload chunks separately.
+fn get_read_batches(
+    necessary_chunks: &[Arc<Chunk>],
+    preload_chunks: Vec<Arc<Chunk>>,
+) -> (Vec<ChunkBatch>, Vec<ChunkBatch>) {
+    // needed chunks are locked first, so preload skips them
+    let needed_batches = get_chunk_batches(necessary_chunks.to_vec());
+    (needed_batches, get_chunk_batches(preload_chunks))
+}
+
+/// Bounds checked slice of the chunks a read touches, so a chunk count that doesn't match
+/// the file size results in an error instead of a panic.
+fn get_chunk_range(chunks: &[Arc<Chunk>], start: u64, end: u64) -> Result<&[Arc<Chunk>], CacheReadError> {
+    chunks
+        .get(start as usize..=end as usize)
+        .ok_or(CacheReadError::ChunkOutOfRange {
+            start,
+            end,
+            chunk_count: chunks.len() as u64,
+        })
+}
+
+/// Scales the read ahead buffer by how much headroom downloads have over playback. With 2x headroom
+/// we use READ_AHEAD_TARGET_BYTES, downloads keeping well ahead shrink it and downloads falling
+/// behind grow it so we have more buffer to absorb slow requests.
+fn get_read_ahead_target_bytes(download_bytes_per_sec: Option<u64>, read_bytes_per_sec: Option<u64>) -> u64 {
+    let (Some(download_rate), Some(read_rate)) = (download_bytes_per_sec, read_bytes_per_sec) else {
+        return READ_AHEAD_TARGET_BYTES;
+    };
+
+    if read_rate == 0 {
+        return READ_AHEAD_TARGET_BYTES;
+    }
+
+    let headroom = download_rate as f64 / read_rate as f64;
+    let target = (READ_AHEAD_TARGET_BYTES as f64 * 2.0 / headroom) as u64;
+    target.clamp(READ_AHEAD_MIN_TARGET_BYTES, READ_AHEAD_MAX_TARGET_BYTES)
+}
+
+/// The chunks up to and including `preload.0` and the last `preload.1` chunks, or every chunk if they overlap.
+fn select_preload_chunks(chunks: &[Arc<Chunk>], preload: (u64, u64)) -> Vec<Arc<Chunk>> {
+    let total_chunks = chunks.len() as u64;
+    if total_chunks <= preload.0 + preload.1 + 1 {
+        return chunks.to_vec();
+    }
+
+    let preload_end_index = total_chunks - preload.1;
+    let start_chunks = &chunks[0..=preload.0 as usize];
+    let end_chunks = &chunks[preload_end_index as usize..];
+    start_chunks.iter().chain(end_chunks.iter()).cloned().collect()
+}
+
+/// Returns the preload range for a file, None if it shouldn't be preloaded. Files above
+/// `preload_end_max_size` only preload the start, the end is rarely needed and expensive for huge files.
+fn get_file_chunk_preload(config: &Config, path: &str, file_size: u64) -> Option<(u64, u64)> {
+    let (preload_start, preload_end) = config.chunk_preload?;
+    let preload_end = config.preload_end_chunks.unwrap_or(preload_end);
+    if !config.preload_extensions.is_empty() && !has_extension(path, &config.preload_extensions) {
+        return None;
+    }
+
+    if config.preload_end_max_size.is_some_and(|max_size| file_size > max_size) {
+        return Some((preload_start, 0));
+    }
+
+    Some((preload_start, preload_end))
+}
+
+/// Whether the path has one of the extensions, which are lowercase and without the leading dot.
+fn has_extension(path: &str, extensions: &[String]) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    extension.is_some_and(|extension| extensions.contains(&extension))
+}
+
+/// Returns the average bytes per second needed to play a file, None if the duration is unknown.
+fn get_bitrate_from_duration(file_size: u64, duration_secs: u64) -> Option<u64> {
+    if duration_secs == 0 {
+        return None;
+    }
+
+    Some(file_size / duration_secs)
+}
+
+/// Writes the chunk metadata to a temp file and renames it over the real one, so an unclean
+/// shutdown mid-write leaves the previous metadata intact instead of a truncated file.
+fn write_chunks(meta_path: &Path, chunk_size: u64, chunks: &[Arc<Chunk>]) -> Result<()> {
+    let tmp_path = meta_path.with_extension("cachemeta.tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    serialize_chunks(chunk_size, chunks, &mut file)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, meta_path)?;
+    Ok(())
+}
+
+/// Loads the chunk size and chunk metadata for a file, starting from scratch with `new_chunk_size` if it's
+/// missing or corrupt. Fresh chunks are all uncached, so anything already in the .bin file is downloaded again.
+fn load_chunks(meta_path: &Path, data_path: &Path, file_size: u64, new_chunk_size: u64) -> (u64, Vec<Arc<Chunk>>) {
+    let fresh = || (new_chunk_size, create_chunks(file_size, new_chunk_size));
+    if !meta_path.exists() {
+        return fresh();
+    }
+
+    if !data_path.exists() {
+        // the .bin was deleted from under us, the chunks marked as cached would read as zeros or fail.
+        tracing::warn!("Cache meta {} has no data file, discarding it", meta_path.display());
+        if let Err(e) = std::fs::remove_file(meta_path) {
+            tracing::warn!("Failed to remove cache meta {}: {}", meta_path.display(), e);
+        }
+
+        return fresh();
+    }
+
+    let chunks = std::fs::File::open(meta_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| deserialize_chunks(&mut file));
+
+    match chunks {
+        Ok((chunk_size, chunks)) => {
+            // metadata written before the last chunk size was fixed can have a 0 byte last chunk,
+            // so the sizes are compared and not just the chunk count.
+            let expected = create_chunks(file_size, chunk_size);
+            if chunks.len() == expected.len() && chunks.iter().zip(&expected).all(|(a, b)| a.size == b.size) {
+                return (chunk_size, chunks);
+            }
+
+            tracing::warn!(
+                "Cache meta {} has {} chunks, which doesn't match the file size, discarding it",
+                meta_path.display(),
+                chunks.len()
+            );
+            fresh()
+        }
+        Err(e) => {
+            tracing::warn!("Cache meta {} is corrupt, discarding it: {}", meta_path.display(), e);
+            fresh()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::chunk::{ChunkPriority, DEFAULT_CHUNK_SIZE};
+    use crate::config::get_test_config;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_read_ahead_target_unknown_rates() {
+        assert_eq!(get_read_ahead_target_bytes(None, None), READ_AHEAD_TARGET_BYTES);
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(10 * MB), None),
+            READ_AHEAD_TARGET_BYTES
+        );
+        assert_eq!(get_read_ahead_target_bytes(None, Some(MB)), READ_AHEAD_TARGET_BYTES);
+    }
+
+    #[test]
+    fn test_read_ahead_target_scales_with_headroom() {
+        // 2x headroom keeps the default target
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(2 * MB), Some(MB)),
+            READ_AHEAD_TARGET_BYTES
+        );
+        // downloads keeping well ahead shrink the buffer
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(100 * MB), Some(MB)),
+            READ_AHEAD_MIN_TARGET_BYTES
+        );
+        // downloads barely keeping up grow it
+        assert_eq!(get_read_ahead_target_bytes(Some(MB), Some(MB)), 128 * MB);
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(MB), Some(10 * MB)),
+            READ_AHEAD_MAX_TARGET_BYTES
+        );
+    }
+
+    #[test]
+    fn test_read_ahead_target_with_duration_hint() {
+        // a 4GB, 2 hour movie is read at ~0.55MB/s, so a 10MB/s download keeps well ahead
+        let bitrate = get_bitrate_from_duration(4096 * MB, 2 * 60 * 60);
+        assert_eq!(bitrate, Some(4096 * MB / 7200));
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(10 * MB), bitrate),
+            READ_AHEAD_MIN_TARGET_BYTES
+        );
+
+        // without a hint the default target is used
+        let bitrate = get_bitrate_from_duration(4096 * MB, 0);
+        assert_eq!(bitrate, None);
+        assert_eq!(
+            get_read_ahead_target_bytes(Some(10 * MB), bitrate),
+            READ_AHEAD_TARGET_BYTES
+        );
+    }
+
+    #[test]
+    fn test_file_chunk_preload() {
+        let gb = 1024 * MB;
+        let mut config = get_test_config(&[]);
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.mkv", gb), Some((4, 1)));
+
+        config.preload_end_max_size = Some(10 * gb);
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.mkv", gb), Some((4, 1)));
+        assert_eq!(
+            get_file_chunk_preload(&config, "Movie/remux.mkv", 60 * gb),
+            Some((4, 0))
+        );
+
+        config.preload_extensions = vec!["mkv".to_string(), "mp4".to_string()];
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.MKV", gb), Some((4, 1)));
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.avi", gb), None);
+        assert_eq!(get_file_chunk_preload(&config, "Show/README", gb), None);
+
+        config.preload_end_chunks = Some(3);
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.mkv", gb), Some((4, 3)));
+
+        config.chunk_preload = None;
+        assert_eq!(get_file_chunk_preload(&config, "Show/E01.mkv", gb), None);
+    }
+
+    #[test]
+    fn test_single_chunk_file_read() {
+        // a 40KB subtitle only queues its own chunk, with no preload or read ahead
+        let chunks = create_chunks(40 * 1024, DEFAULT_CHUNK_SIZE);
+        let (preload_chunks, is_in_preload) = get_read_preload_chunks(&chunks, Some((4, 1)), 0, 0);
+        assert!(preload_chunks.is_empty());
+        assert!(!is_in_preload);
+        assert!(!should_read_ahead(&chunks, 0, is_in_preload));
+
+        let (needed_batches, preload_batches) = get_read_batches(&chunks, preload_chunks);
+        assert!(preload_batches.is_empty());
+        assert_eq!(needed_batches.len(), 1);
+        assert_eq!(
+            needed_batches[0].iter().map(|(_, c)| c.index).collect::<Vec<_>>(),
+            vec![0]
+        );
+
+        // larger files still preload from the first read and read ahead past the preload range
+        let chunks = create_chunks(20 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        let (preload_chunks, is_in_preload) = get_read_preload_chunks(&chunks, Some((4, 1)), 0, 0);
+        assert_eq!(
+            preload_chunks.iter().map(|c| c.index).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 19]
+        );
+        assert!(is_in_preload);
+        assert!(should_read_ahead(&chunks, 10, false));
+    }
+
+    #[test]
+    fn test_select_preload_chunks() {
+        let chunks = create_chunks(20 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        let select = |preload| {
+            select_preload_chunks(&chunks, preload)
+                .iter()
+                .map(|chunk| chunk.index)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(select((4, 1)), vec![0, 1, 2, 3, 4, 19]);
+        assert_eq!(select((4, 0)), vec![0, 1, 2, 3, 4]);
+        assert_eq!(select((0, 3)), vec![0, 17, 18, 19]);
+        // overlapping start and end preloads the whole file
+        assert_eq!(select((10, 9)), (0..20).collect::<Vec<_>>());
+        assert_eq!(select((15, 10)), (0..20).collect::<Vec<_>>());
+
+        let chunks = create_chunks(DEFAULT_CHUNK_SIZE / 2, DEFAULT_CHUNK_SIZE);
+        assert_eq!(select_preload_chunks(&chunks, (4, 1)).len(), 1);
+
+        // preload_end_chunks can be larger than a small file's chunk count
+        let file_size = 3 * DEFAULT_CHUNK_SIZE;
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(select_preload_chunks(&chunks, (0, 5)).len(), 3);
+        let (preload_chunks, is_in_preload) = get_read_preload_chunks(&chunks, Some((0, 5)), 1, 1);
+        assert_eq!(preload_chunks.iter().map(|c| c.index).collect::<Vec<_>>(), vec![0, 2]);
+        assert!(is_in_preload);
+        assert_eq!(
+            chunks[1].get_position_priority(file_size, Some((0, 5))),
+            ChunkPriority::Preloaded
+        );
+    }
+
+    #[test]
+    fn test_read_batches_straddling_chunks() {
+        let chunks = create_chunks(10 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+
+        // a read across the boundary of chunks 3 and 4, with chunk 3 already cached
+        chunks[3].cached.store(true, Ordering::SeqCst);
+        let preload = [0, 1, 2, 9].iter().map(|&i| chunks[i].clone()).collect();
+        let (needed_batches, preload_batches) = get_read_batches(&chunks[3..=4], preload);
+        let get_indexes = |batches: &[ChunkBatch]| {
+            batches
+                .iter()
+                .map(|batch| batch.iter().map(|(_, c)| c.index).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(get_indexes(&needed_batches), vec![vec![4]]);
+        assert_eq!(get_indexes(&preload_batches), vec![vec![0, 1, 2], vec![9]]);
+
+        // while those are downloading, the same read doesn't queue anything again
+        let (needed_batches, preload_batches) = get_read_batches(&chunks[3..=4], vec![chunks[0].clone()]);
+        assert!(needed_batches.is_empty() && preload_batches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_download_limit_with_scattered_reads() {
+        let chunks = create_chunks(100 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        let download_batches = Arc::new(Semaphore::new(2));
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let max_in_flight = Arc::new(AtomicU64::new(0));
+
+        // reads all over the file, each one starting its own batch
+        let mut handles = Vec::new();
+        for index in [3, 90, 41, 7, 66, 25] {
+            let batches = get_chunk_batches(vec![chunks[index].clone(), chunks[index + 1].clone()]);
+            assert_eq!(batches.len(), 1);
+
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            let batch = batches.into_iter().next().unwrap();
+            handles.push(spawn_file_download(Some(download_batches.clone()), async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                sleep(Duration::from_millis(10)).await;
+                for (_, chunk) in &batch {
+                    chunk.cached.store(true, Ordering::SeqCst);
+                }
+
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        assert_eq!(get_cached_bytes(&chunks), 12 * DEFAULT_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_needed_chunks_skip_file_download_limit() {
+        // every batch slot is taken by read ahead that's still downloading
+        let download_batches = Arc::new(Semaphore::new(1));
+        let _read_ahead = download_batches.clone().acquire_owned().await.unwrap();
+
+        let queued = spawn_file_download(Some(download_batches.clone()), async {});
+        let needed = spawn_file_download(None, async {});
+        tokio::time::timeout(Duration::from_secs(1), needed)
+            .await
+            .expect("needed chunks waited for a batch slot")
+            .unwrap();
+        assert!(!queued.is_finished());
+    }
+
+    #[test]
+    fn test_chunk_batches_with_overlapping_ranges() {
+        let chunks = create_chunks(10 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        chunks[3].cached.store(true, Ordering::SeqCst);
+        let _downloading = chunks[6].downloading.clone().try_lock_owned().unwrap();
+
+        // preload 0-3, read ahead 2-7 and 9, out of order
+        let preload = [0, 1, 2, 3];
+        let read_ahead = [7, 6, 5, 4, 3, 2, 9];
+        let queued = preload
+            .iter()
+            .chain(read_ahead.iter())
+            .map(|&i| chunks[i].clone())
+            .collect::<Vec<_>>();
+
+        let batches = get_chunk_batches(queued);
+        let indexes = batches
+            .iter()
+            .map(|batch| batch.iter().map(|(_, c)| c.index).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(indexes, vec![vec![0, 1, 2], vec![4, 5], vec![7], vec![9]]);
+        for (_, chunk) in batches.iter().flatten() {
+            assert!(!chunk.cached.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn test_chunk_range_exact_multiple() {
+        let file_size = 2 * DEFAULT_CHUNK_SIZE;
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+
+        // the last bytes of the file are in the last chunk
+        let offset = file_size - 4096;
+        let end = (offset + 4096 - 1) / DEFAULT_CHUNK_SIZE;
+        let range = get_chunk_range(&chunks, offset / DEFAULT_CHUNK_SIZE, end).unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(range[0].index, 1);
+        assert_eq!(range[0].size, DEFAULT_CHUNK_SIZE);
+
+        // a chunk list that is too short errors instead of panicking
+        assert!(matches!(
+            get_chunk_range(&chunks[..1], 1, end),
+            Err(CacheReadError::ChunkOutOfRange { chunk_count: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_cached_bytes() {
+        let chunks = create_chunks(2 * DEFAULT_CHUNK_SIZE + 1000, DEFAULT_CHUNK_SIZE);
+        assert_eq!(get_cached_bytes(&chunks), 0);
+
+        chunks[0].cached.store(true, Ordering::SeqCst);
+        chunks[2].cached.store(true, Ordering::SeqCst);
+        assert_eq!(get_cached_bytes(&chunks), DEFAULT_CHUNK_SIZE + 1000);
+    }
+
+    #[test]
+    fn test_load_chunks_corrupt_meta() {
+        let dir = std::env::temp_dir().join("lumin-test-cachemeta");
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta_path = dir.join("1.cachemeta");
+        let data_path = dir.join("1.bin");
+        std::fs::write(&data_path, "").unwrap();
+        let file_size = 3 * DEFAULT_CHUNK_SIZE;
+
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        chunks[1].cached.store(true, Ordering::SeqCst);
+        serialize_chunks(
+            DEFAULT_CHUNK_SIZE,
+            &chunks,
+            &mut std::fs::File::create(&meta_path).unwrap(),
+        )
+        .unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert!(loaded[1].cached.load(Ordering::SeqCst));
+
+        // truncated mid-write
+        let data = std::fs::read(&meta_path).unwrap();
+        std::fs::write(&meta_path, &data[..data.len() / 2]).unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.iter().all(|c| !c.cached.load(Ordering::SeqCst)));
+
+        // older metadata with a 0 byte last chunk
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        let mut chunks = chunks[..2].to_vec();
+        chunks.push(Arc::new(Chunk::new(2, 2 * DEFAULT_CHUNK_SIZE, 0)));
+        serialize_chunks(
+            DEFAULT_CHUNK_SIZE,
+            &chunks,
+            &mut std::fs::File::create(&meta_path).unwrap(),
+        )
+        .unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded[2].size, DEFAULT_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_load_chunks_missing_data() {
+        let dir = std::env::temp_dir().join("lumin-test-cachemeta");
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta_path = dir.join("3.cachemeta");
+        let data_path = dir.join("3.bin");
+        let file_size = 3 * DEFAULT_CHUNK_SIZE;
+        let _ = std::fs::remove_file(&data_path);
+
+        // meta without a data file
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        chunks[1].cached.store(true, Ordering::SeqCst);
+        write_chunks(&meta_path, DEFAULT_CHUNK_SIZE, &chunks).unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert!(loaded.iter().all(|c| !c.cached.load(Ordering::SeqCst)));
+        assert!(!meta_path.exists());
+
+        // data file without a meta
+        std::fs::write(&data_path, vec![1u8; 1024]).unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.iter().all(|c| !c.cached.load(Ordering::SeqCst)));
+    }
+
+    #[test]
+    fn test_load_chunks_chunk_size() {
+        let dir = std::env::temp_dir().join("lumin-test-cachemeta");
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta_path = dir.join("4.cachemeta");
+        let data_path = dir.join("4.bin");
+        std::fs::write(&data_path, "").unwrap();
+        let _ = std::fs::remove_file(&meta_path);
+        let chunk_size = 4 * DEFAULT_CHUNK_SIZE;
+        let file_size = 10 * DEFAULT_CHUNK_SIZE;
+
+        // new entries use the configured chunk size
+        let (loaded_size, chunks) = load_chunks(&meta_path, &data_path, file_size, chunk_size);
+        assert_eq!(loaded_size, chunk_size);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].offset, chunk_size);
+        assert_eq!(chunks[2].size, 2 * DEFAULT_CHUNK_SIZE);
+
+        // and keep it once the configured size changes
+        chunks[1].cached.store(true, Ordering::SeqCst);
+        write_chunks(&meta_path, chunk_size, &chunks).unwrap();
+        let (loaded_size, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded_size, chunk_size);
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded[1].cached.load(Ordering::SeqCst));
+
+        // metadata from before the chunk size was stored is always 8MB chunks
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        chunks[5].cached.store(true, Ordering::SeqCst);
+        serde_json::to_writer(std::fs::File::create(&meta_path).unwrap(), &chunks).unwrap();
+        let (loaded_size, loaded) = load_chunks(&meta_path, &data_path, file_size, chunk_size);
+        assert_eq!(loaded_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded.len(), 10);
+        assert!(loaded[5].cached.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_write_chunks_interrupted() {
+        let dir = std::env::temp_dir().join("lumin-test-cachemeta");
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta_path = dir.join("2.cachemeta");
+        let tmp_path = dir.join("2.cachemeta.tmp");
+        let data_path = dir.join("2.bin");
+        std::fs::write(&data_path, "").unwrap();
+        let file_size = 3 * DEFAULT_CHUNK_SIZE;
+
+        let chunks = create_chunks(file_size, DEFAULT_CHUNK_SIZE);
+        chunks[0].cached.store(true, Ordering::SeqCst);
+        write_chunks(&meta_path, DEFAULT_CHUNK_SIZE, &chunks).unwrap();
+        assert!(!tmp_path.exists());
+
+        // a crash mid-write only leaves a partial temp file behind
+        std::fs::write(&tmp_path, "[{\"index\":0,").unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert!(loaded[0].cached.load(Ordering::SeqCst));
+
+        chunks[2].cached.store(true, Ordering::SeqCst);
+        write_chunks(&meta_path, DEFAULT_CHUNK_SIZE, &chunks).unwrap();
+        let (_, loaded) = load_chunks(&meta_path, &data_path, file_size, DEFAULT_CHUNK_SIZE);
+        assert!(loaded[0].cached.load(Ordering::SeqCst));
+        assert!(loaded[2].cached.load(Ordering::SeqCst));
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_build_webdav_url() {
+        assert_eq!(
+            build_webdav_url("https://webdav.torbox.app", "Some Show/Season 1/E01 [1080p].mkv"),
+            "https://webdav.torbox.app/Some%20Show/Season%201/E01%20%5B1080p%5D.mkv"
+        );
+    }
+
+    #[test]
+    fn test_is_needed_by_readers() {
+        let mb = 1024 * 1024;
+        // a reader just behind the download would read it next
+        assert!(is_needed_by_readers(&[90 * mb], 100 * mb, 132 * mb));
+        // a reader inside the download range still needs the rest of it
+        assert!(is_needed_by_readers(&[110 * mb], 100 * mb, 132 * mb));
+        // readers past the download, or too far behind it, don't
+        assert!(!is_needed_by_readers(&[132 * mb], 100 * mb, 132 * mb));
+        assert!(!is_needed_by_readers(&[0], 1000 * mb, 1032 * mb));
+        assert!(!is_needed_by_readers(&[], 100 * mb, 132 * mb));
     }
 }
@@ -1,14 +1,18 @@
-use crate::{config::get_config, debrid::Debrid};
+use crate::{config::get_config, debrid::DebridProvider};
 use anyhow::Result;
 use chunk::{Chunk, ChunkPriority};
 use entry::CacheEntry;
+pub use entry::CacheReadError;
 use ratelimiter::Ratelimiter;
 use sqlx::SqlitePool;
 use std::{
+    cmp,
     collections::HashMap,
     sync::{Arc, RwLock, atomic::Ordering},
     time::Duration,
 };
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
 
 mod chunk;
 mod downloader;
@@ -16,24 +20,36 @@ mod entry;
 mod ratelimiter;
 mod reader;
 
+// when the disk fills up before the cache reaches its max size, something else is using the space,
+// so an emergency sweep frees at least this much even if the cache is below its target size.
+const EMERGENCY_SWEEP_BYTES: u64 = 1024 * 1024 * 1024; // 1GB
+// with playback_aware_eviction, chunks this far ahead of a reader are kept over everything else,
+// and chunks further than PLAYBACK_BEHIND_BYTES behind every reader lose their grace period.
+const PLAYBACK_AHEAD_BYTES: u64 = 256 * 1024 * 1024; // 256MB
+const PLAYBACK_BEHIND_BYTES: u64 = 64 * 1024 * 1024; // 64MB
+
 pub struct CacheFile {
     pub id: i64,
     pub size: i64,
     pub path: String,
     pub file_debrid_id: i64,
     pub torrent_debrid_id: i64,
+    pub duration_hint_secs: Option<i64>,
 }
 
 pub struct Cache {
     pool: SqlitePool,
     ratelimiter: Arc<Ratelimiter>,
-    debrid: Arc<Debrid>,
+    debrid: Arc<dyn DebridProvider>,
     entries: RwLock<HashMap<i64, Arc<CacheEntry>>>,
+    // notified by downloads when the disk is full, to sweep without waiting for the next interval
+    sweep_notify: Arc<Notify>,
 }
 
 impl Cache {
-    pub async fn load(pool: &SqlitePool, debrid: Arc<Debrid>) -> Result<Arc<Self>> {
+    pub async fn load(pool: &SqlitePool, debrid: Arc<dyn DebridProvider>) -> Result<Arc<Self>> {
         let ratelimiter = Arc::new(Ratelimiter::new());
+        let sweep_notify = Arc::new(Notify::new());
 
         let cache_dir = get_config().cache_dir.as_ref().unwrap();
         let mut files = tokio::fs::read_dir(&cache_dir).await?;
@@ -53,7 +69,8 @@ impl Cache {
 
             let result = sqlx::query_as!(
                 CacheFile,
-                r#"SELECT tf.id, tf.size, tf.path, tf.debrid_id as file_debrid_id, t.debrid_id as "torrent_debrid_id!"
+                r#"SELECT tf.id, tf.size, tf.path, tf.debrid_id as file_debrid_id, t.debrid_id as "torrent_debrid_id!",
+                    tf.duration_hint_secs
                 FROM torrent_files tf
                 JOIN torrents t ON t.id = tf.torrent_id
                 WHERE tf.id = ? AND t.debrid_id IS NOT NULL"#,
@@ -76,7 +93,7 @@ impl Cache {
             };
 
             let file_id = file.id;
-            let entry = CacheEntry::load(file, debrid.clone(), ratelimiter.clone());
+            let entry = CacheEntry::load(file, debrid.clone(), ratelimiter.clone(), sweep_notify.clone());
             entries.insert(file_id, Arc::new(entry));
         }
 
@@ -86,6 +103,7 @@ impl Cache {
             ratelimiter,
             entries,
             debrid,
+            sweep_notify,
         }))
     }
 
@@ -103,13 +121,69 @@ impl Cache {
         }
 
         let file_id = file.id;
-        let entry = CacheEntry::load(file, self.debrid.clone(), self.ratelimiter.clone());
+        let entry = CacheEntry::load(
+            file,
+            self.debrid.clone(),
+            self.ratelimiter.clone(),
+            self.sweep_notify.clone(),
+        );
         let entry = Arc::new(entry);
         entries.insert(file_id, entry.clone());
         entry
     }
 
-    pub async fn start_sweeper(&self) -> Result<()> {
+    pub fn get_entry(&self, file_id: i64) -> Option<Arc<CacheEntry>> {
+        let entries = self.entries.read().unwrap();
+        entries.get(&file_id).cloned()
+    }
+
+    /// Snapshot of every loaded entry, the lock is only held long enough to clone the Arcs.
+    pub fn get_all_entries(&self) -> Vec<Arc<CacheEntry>> {
+        let entries = self.entries.read().unwrap();
+        entries.values().cloned().collect()
+    }
+
+    pub fn get_ratelimiter(&self) -> &Ratelimiter {
+        &self.ratelimiter
+    }
+
+    pub fn get_cached_bytes(&self) -> u64 {
+        self.get_all_entries()
+            .iter()
+            .map(|entry| entry.get_cached_bytes())
+            .sum()
+    }
+
+    /// Removes every cached chunk of the given files, returning the number of bytes freed. The files
+    /// are downloaded again on their next read. Chunks that are being downloaded are left alone.
+    pub async fn evict_files(&self, file_ids: &[i64]) -> Result<u64> {
+        let mut removed_bytes = 0;
+        for entry in self.get_all_entries() {
+            if !file_ids.contains(&entry.get_file().id) {
+                continue;
+            }
+
+            for chunk in entry.get_chunks() {
+                if !chunk.cached.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                match chunk.try_remove(entry.clone()).await {
+                    Ok(true) => removed_bytes += chunk.size,
+                    Ok(false) => {}
+                    // the sweeper removed the chunk between the check and taking its lock
+                    Err(_) if !chunk.cached.load(Ordering::SeqCst) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(removed_bytes)
+    }
+
+    /// Runs until `shutdown` is cancelled. Cancellation is only checked between chunk removals,
+    /// so a removal that has started always finishes before the cache metadata is flushed.
+    pub async fn start_sweeper(&self, shutdown: CancellationToken) -> Result<()> {
         let config = get_config();
         let sweep_duration = Duration::from_secs(config.cache_sweep_interval_secs);
 
@@ -120,18 +194,28 @@ impl Cache {
         // files on disk and use that as a base, or maybe have some kind of "repair" that punches
         // holes in uncached sections to ensure they're gone.
         loop {
-            tokio::time::sleep(sweep_duration).await;
+            let mut emergency = false;
+            tokio::select! {
+                _ = tokio::time::sleep(sweep_duration) => {}
+                _ = self.sweep_notify.notified() => {
+                    tracing::warn!("cache disk is full, starting an emergency sweep");
+                    emergency = true;
+                }
+                _ = shutdown.cancelled() => {
+                    self.flush_all_cache_meta();
+                    return Ok(());
+                }
+            }
+
             tracing::info!("starting cache sweep");
 
             let mut all_chunks: Vec<(Arc<CacheEntry>, Arc<Chunk>, ChunkPriority)> = Vec::new();
             let mut total_size_bytes = 0;
 
-            let entries = {
-                let entries = self.entries.read().unwrap();
-                entries.values().cloned().collect::<Vec<_>>()
-            };
+            for entry in self.get_all_entries().into_iter() {
+                // reads also do this, but a reader that stopped reading won't trigger it
+                entry.cancel_idle_read_ahead();
 
-            for entry in entries.into_iter() {
                 let file_id = entry.get_file().id;
                 let file = sqlx::query!(r#"SELECT id FROM torrent_files WHERE id = ?"#, file_id)
                     .fetch_optional(&self.pool)
@@ -153,44 +237,59 @@ impl Cache {
                     }
                 }
 
+                let positions = if config.playback_aware_eviction {
+                    entry.get_reader_positions()
+                } else {
+                    Vec::new()
+                };
+
+                let chunk_preload = entry.get_chunk_preload();
                 for chunk in entry.get_chunks() {
                     let is_cached = chunk.cached.load(Ordering::Relaxed);
                     if !is_cached {
                         continue;
                     }
 
-                    let file = entry.get_file();
-                    let priority = chunk.get_priority(file.size as u64);
+                    let file_size = entry.get_file().size as u64;
+                    if config.always_keep_preload && is_always_kept(chunk, file_size, chunk_preload) {
+                        // still counts towards the cache size, it's just never a candidate for eviction
+                        total_size_bytes += chunk.size;
+                        continue;
+                    }
+
+                    let priority = match get_playback_position(chunk, &positions) {
+                        Some(PlaybackPosition::Ahead) => ChunkPriority::AheadOfReader,
+                        Some(PlaybackPosition::Behind) => chunk.get_position_priority(file_size, chunk_preload),
+                        None => chunk.get_priority(file_size, chunk_preload),
+                    };
                     all_chunks.push((entry.clone(), chunk.clone(), priority));
                     total_size_bytes += chunk.size;
                 }
             }
 
-            if total_size_bytes < config.cache_max_size {
+            let Some(target_size_bytes) = get_sweep_target_bytes(
+                total_size_bytes,
+                config.cache_max_size,
+                config.cache_target_size,
+                emergency,
+            ) else {
                 let total_size_mb = total_size_bytes / (1024 * 1024);
                 tracing::info!(
                     "cache sweep finished, size is below threshold, total size is {} MB",
                     total_size_mb
                 );
                 continue;
-            }
-
-            all_chunks.sort_by(|a, b| {
-                // sort by priority first (lower is higher priority)
-                // then sort by last accessed time (higher is higher priority)
-                let a_priority = a.2 as u64;
-                let b_priority = b.2 as u64;
-                if a_priority != b_priority {
-                    return a_priority.cmp(&b_priority);
-                }
+            };
 
-                let a_accessed = a.1.accessed_at_secs.load(Ordering::Relaxed);
-                let b_accessed = b.1.accessed_at_secs.load(Ordering::Relaxed);
-                a_accessed.cmp(&b_accessed)
-            });
+            all_chunks.sort_by(|a, b| cmp_eviction_order((&a.1, a.2), (&b.1, b.2)));
 
             let mut total_removed_bytes = 0;
             for (entry, chunk, priority) in all_chunks.into_iter() {
+                if shutdown.is_cancelled() {
+                    tracing::info!("stopping cache sweep early for shutdown");
+                    break;
+                }
+
                 let file_id = entry.get_file().id;
                 tracing::info!(
                     "removing chunk {} for file {}, priority {:?}",
@@ -203,7 +302,7 @@ impl Cache {
                 if removed {
                     total_removed_bytes += chunk.size;
                     total_size_bytes -= chunk.size;
-                    if total_size_bytes < config.cache_target_size {
+                    if total_size_bytes < target_size_bytes {
                         break;
                     }
                 }
@@ -218,4 +317,160 @@ impl Cache {
             );
         }
     }
+
+    fn flush_all_cache_meta(&self) {
+        for entry in self.get_all_entries() {
+            if let Err(e) = entry.flush_cache_meta_blocking() {
+                tracing::error!("Failed to flush metadata for file {}: {}", entry.get_file().id, e);
+            }
+        }
+    }
+}
+
+/// Whether a chunk is never evicted with always_keep_preload. These are the chunks players and
+/// ffprobe read when a file is opened, keeping them makes opening any file fast.
+fn is_always_kept(chunk: &Chunk, file_size: u64, chunk_preload: Option<(u64, u64)>) -> bool {
+    matches!(
+        chunk.get_position_priority(file_size, chunk_preload),
+        ChunkPriority::Preloaded | ChunkPriority::FirstChunk | ChunkPriority::LastChunk
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum PlaybackPosition {
+    /// Within PLAYBACK_AHEAD_BYTES ahead of a reader.
+    Ahead,
+    /// More than PLAYBACK_BEHIND_BYTES behind every reader, so it has already been watched.
+    Behind,
+}
+
+/// Where a chunk is relative to the readers of its file, None if there are no readers or it's
+/// close behind one (a player seeking back a little would read it again).
+fn get_playback_position(chunk: &Chunk, positions: &[u64]) -> Option<PlaybackPosition> {
+    if positions.is_empty() {
+        return None;
+    }
+
+    let chunk_end = chunk.offset + chunk.size;
+    let is_ahead = positions
+        .iter()
+        .any(|&position| chunk_end > position && chunk.offset < position + PLAYBACK_AHEAD_BYTES);
+    if is_ahead {
+        return Some(PlaybackPosition::Ahead);
+    }
+
+    let is_behind = positions
+        .iter()
+        .all(|&position| chunk_end + PLAYBACK_BEHIND_BYTES <= position);
+    if is_behind {
+        return Some(PlaybackPosition::Behind);
+    }
+
+    None
+}
+
+/// Returns the size the sweeper should shrink the cache to, or None if nothing needs to be removed.
+fn get_sweep_target_bytes(total_bytes: u64, max_bytes: u64, target_bytes: u64, emergency: bool) -> Option<u64> {
+    if emergency {
+        return Some(target_bytes.min(total_bytes.saturating_sub(EMERGENCY_SWEEP_BYTES)));
+    }
+
+    if total_bytes < max_bytes {
+        return None;
+    }
+
+    Some(target_bytes)
+}
+
+/// Ordering used by the sweeper, chunks that sort first are evicted first.
+/// Sorts by priority first (lower is higher priority, so it's evicted last),
+/// then by last accessed time (least recently accessed is evicted first).
+fn cmp_eviction_order(a: (&Chunk, ChunkPriority), b: (&Chunk, ChunkPriority)) -> cmp::Ordering {
+    let (a_chunk, a_priority) = a;
+    let (b_chunk, b_priority) = b;
+    if a_priority != b_priority {
+        return b_priority.cmp(&a_priority);
+    }
+
+    let a_accessed = a_chunk.accessed_at_secs.load(Ordering::Relaxed);
+    let b_accessed = b_chunk.accessed_at_secs.load(Ordering::Relaxed);
+    a_accessed.cmp(&b_accessed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::DEFAULT_CHUNK_SIZE;
+
+    #[test]
+    fn test_eviction_order_prefers_cold_chunks() {
+        let watched = Chunk::new(0, 0, DEFAULT_CHUNK_SIZE);
+        let cold = Chunk::new(1, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        watched.accessed_at_secs.store(0, Ordering::Relaxed);
+        cold.accessed_at_secs.store(0, Ordering::Relaxed);
+
+        // reading the chunk bumps it behind the cold one
+        watched.touch();
+        let mut chunks = [(&watched, ChunkPriority::Low), (&cold, ChunkPriority::Low)];
+        chunks.sort_by(|a, b| cmp_eviction_order(*a, *b));
+        assert_eq!(chunks[0].0.index, cold.index);
+    }
+
+    #[test]
+    fn test_eviction_order_respects_priority() {
+        let grace = Chunk::new(0, 0, DEFAULT_CHUNK_SIZE);
+        let low = Chunk::new(1, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+        let mut chunks = [(&grace, ChunkPriority::GracePeriod), (&low, ChunkPriority::Low)];
+        chunks.sort_by(|a, b| cmp_eviction_order(*a, *b));
+        assert_eq!(chunks[0].1, ChunkPriority::Low);
+        assert_eq!(chunks[1].1, ChunkPriority::GracePeriod);
+    }
+
+    #[test]
+    fn test_get_playback_position() {
+        let mb = 1024 * 1024;
+        let chunk = Chunk::new(10, 10 * DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE); // 80MB-88MB
+        assert_eq!(get_playback_position(&chunk, &[]), None);
+        assert_eq!(get_playback_position(&chunk, &[84 * mb]), Some(PlaybackPosition::Ahead));
+        assert_eq!(get_playback_position(&chunk, &[0]), Some(PlaybackPosition::Ahead));
+        // just behind the reader, it could still be seeked back to
+        assert_eq!(get_playback_position(&chunk, &[100 * mb]), None);
+        assert_eq!(
+            get_playback_position(&chunk, &[500 * mb]),
+            Some(PlaybackPosition::Behind)
+        );
+        // another reader still needs it
+        assert_eq!(
+            get_playback_position(&chunk, &[500 * mb, 40 * mb]),
+            Some(PlaybackPosition::Ahead)
+        );
+        assert_eq!(get_playback_position(&chunk, &[500 * mb, 100 * mb]), None);
+    }
+
+    #[test]
+    fn test_always_kept_chunks() {
+        let file_size = 20 * DEFAULT_CHUNK_SIZE;
+        let kept = |chunk_preload| {
+            chunk::create_chunks(file_size, DEFAULT_CHUNK_SIZE)
+                .iter()
+                .filter(|chunk| is_always_kept(chunk, file_size, chunk_preload))
+                .map(|chunk| chunk.index)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(kept(Some((4, 1))), vec![0, 1, 2, 3, 4, 19]);
+        assert_eq!(kept(None), vec![0, 19]);
+    }
+
+    #[test]
+    fn test_get_sweep_target_bytes() {
+        let gb = 1024 * 1024 * 1024;
+        let (max, target) = (125 * gb, 100 * gb);
+        assert_eq!(get_sweep_target_bytes(90 * gb, max, target, false), None);
+        assert_eq!(get_sweep_target_bytes(130 * gb, max, target, false), Some(target));
+        // emergency sweeps always free space, even below the target size
+        assert_eq!(get_sweep_target_bytes(130 * gb, max, target, true), Some(target));
+        assert_eq!(get_sweep_target_bytes(90 * gb, max, target, true), Some(89 * gb));
+        assert_eq!(get_sweep_target_bytes(gb / 2, max, target, true), Some(0));
+    }
 }
@@ -8,10 +8,17 @@ use std::{
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 const MAX_CONCURRENT_REQUESTS: usize = 6;
+// when the server reports this many or fewer requests left in the window, we start spacing out requests
+// so we don't hit a hard 429 mid-stream.
+const LOW_BUDGET_THRESHOLD: u64 = 2;
+// used when the server reports a low budget but not when the window resets
+const DEFAULT_BUDGET_RESET_SECS: u64 = 5;
 
 pub struct Ratelimiter {
     // Store timestamp as u64 (milliseconds since epoch)
     ratelimited_until: Arc<AtomicU64>,
+    // last x-ratelimit-remaining value the server sent, u64::MAX if we haven't seen one
+    remaining_budget: Arc<AtomicU64>,
     semaphore: Arc<Semaphore>,
 }
 
@@ -19,6 +26,7 @@ impl Ratelimiter {
     pub fn new() -> Self {
         Self {
             ratelimited_until: Arc::new(AtomicU64::new(0)), // 0 means no rate limiting
+            remaining_budget: Arc::new(AtomicU64::new(u64::MAX)),
             semaphore: Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS)),
         }
     }
@@ -40,11 +48,55 @@ impl Ratelimiter {
         permit
     }
 
+    /// Request slots that are free right now, 0 means downloads are waiting for one.
+    pub fn available_permits(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// When the current ratelimit ends in milliseconds since the unix epoch, None if requests aren't held back.
+    pub fn ratelimited_until(&self) -> Option<u64> {
+        let until_timestamp = self.ratelimited_until.load(Ordering::Acquire);
+        if until_timestamp > instant_to_u64(Instant::now()) {
+            Some(until_timestamp)
+        } else {
+            None
+        }
+    }
+
+    /// The last request budget the server reported, None if it hasn't sent one.
+    pub fn remaining_budget(&self) -> Option<u64> {
+        match self.remaining_budget.load(Ordering::Acquire) {
+            u64::MAX => None,
+            remaining => Some(remaining),
+        }
+    }
+
     pub fn set_ratelimited_for(&self, for_seconds: u64) {
         let until = Instant::now() + Duration::from_secs(for_seconds);
         self.ratelimited_until
             .store(instant_to_u64(until), Ordering::Release);
     }
+
+    /// Records the budget reported by the server. When it's running low, the remaining requests are
+    /// spread across the rest of the window, and when it's exhausted we wait for the window to reset.
+    pub fn update_budget(&self, remaining: u64, reset_secs: Option<u64>) {
+        self.remaining_budget.store(remaining, Ordering::Release);
+        if remaining > LOW_BUDGET_THRESHOLD {
+            return;
+        }
+
+        let reset_secs = reset_secs.unwrap_or(DEFAULT_BUDGET_RESET_SECS);
+        let backoff_secs = reset_secs / (remaining + 1);
+        if backoff_secs > 0 {
+            tracing::debug!(
+                "ratelimit budget is low ({} remaining, resets in {}s), waiting {}s between requests",
+                remaining,
+                reset_secs,
+                backoff_secs
+            );
+            self.set_ratelimited_for(backoff_secs);
+        }
+    }
 }
 
 // Convert Instant to milliseconds since some base point
@@ -135,6 +187,41 @@ mod tests {
         assert!(elapsed.as_secs() >= 1);
     }
 
+    #[tokio::test]
+    async fn test_update_budget_exhausted() {
+        let ratelimiter = Ratelimiter::new();
+        ratelimiter.update_budget(0, Some(1));
+        assert_eq!(ratelimiter.remaining_budget.load(Ordering::Acquire), 0);
+
+        let start = Instant::now();
+        let _permit = ratelimiter.wait().await;
+        assert!(start.elapsed().as_secs() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_status_accessors() {
+        let ratelimiter = Ratelimiter::new();
+        assert_eq!(ratelimiter.available_permits(), MAX_CONCURRENT_REQUESTS);
+        assert_eq!(ratelimiter.ratelimited_until(), None);
+        assert_eq!(ratelimiter.remaining_budget(), None);
+
+        let _permit = ratelimiter.wait().await;
+        assert_eq!(ratelimiter.available_permits(), MAX_CONCURRENT_REQUESTS - 1);
+
+        let before = instant_to_u64(Instant::now());
+        ratelimiter.update_budget(0, Some(30));
+        assert_eq!(ratelimiter.remaining_budget(), Some(0));
+        let until = ratelimiter.ratelimited_until().unwrap();
+        assert!(until >= before + 29_000);
+    }
+
+    #[tokio::test]
+    async fn test_update_budget_plenty_remaining() {
+        let ratelimiter = Ratelimiter::new();
+        ratelimiter.update_budget(100, Some(60));
+        assert_eq!(ratelimiter.ratelimited_until.load(Ordering::Acquire), 0);
+    }
+
     #[tokio::test]
     async fn test_expired_rate_limit() {
         let ratelimiter = Ratelimiter::new();
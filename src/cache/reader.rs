@@ -1,35 +1,65 @@
-use std::{sync::Mutex, time::Instant};
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-const MAX_READER_MERGE_GAP: u64 = 16 * 1024 * 1024; // 16MB
+// below this the consumption rate is mostly noise from the player filling its own buffer
+const MIN_RATE_SAMPLE_BYTES: u64 = 16 * 1024 * 1024; // 16MB
 
 #[derive(Debug, Clone)]
 pub struct Reader {
+    pub id: u64,
     pub position: u64,
+    pub started_at: Instant,
     pub last_read: Instant,
     pub bytes_read: u64,
 }
 
 impl Reader {
     pub fn new(offset: u64, size: u64) -> Self {
+        let now = Instant::now();
         Self {
+            id: 0,
             position: offset + size,
-            last_read: Instant::now(),
+            started_at: now,
+            last_read: now,
             bytes_read: size,
         }
     }
 
-    pub fn matches(&self, offset: u64) -> bool {
-        let gap = self.position.abs_diff(offset);
-        if gap > MAX_READER_MERGE_GAP {
-            false
+    /// How fast the reader is consuming the file, or None if it hasn't read enough to tell.
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        let elapsed = self.last_read.duration_since(self.started_at).as_secs_f64();
+        if self.bytes_read < MIN_RATE_SAMPLE_BYTES || elapsed < 1.0 {
+            return None;
+        }
+
+        Some((self.bytes_read as f64 / elapsed) as u64)
+    }
+
+    /// A small forward gap is a continuation of the stream (the kernel skipping ahead or reads
+    /// arriving out of order), a backward jump is much more likely to be a seek, so they use
+    /// separate thresholds.
+    pub fn matches(&self, offset: u64, forward_gap: u64, backward_gap: u64) -> bool {
+        if offset >= self.position {
+            offset - self.position <= forward_gap
         } else {
-            true
+            self.position - offset <= backward_gap
         }
     }
 
     pub fn update(&mut self, offset: u64, size: u64) {
-        self.position = offset + size;
-        self.bytes_read += size;
+        // overlapping or out of order reads only count the bytes past the current position,
+        // otherwise concurrent reads at nearby offsets would inflate bytes_read.
+        let end = offset + size;
+        if end > self.position {
+            self.bytes_read += end - self.position.max(offset);
+            self.position = end;
+        }
+
         self.last_read = Instant::now();
     }
 }
@@ -37,27 +67,142 @@ impl Reader {
 #[derive(Debug)]
 pub struct Readers {
     readers: Mutex<Vec<Reader>>,
+    next_id: AtomicU64,
+    forward_gap: u64,
+    backward_gap: u64,
 }
 
 impl Readers {
-    pub fn new() -> Self {
+    pub fn new(forward_gap: u64, backward_gap: u64) -> Self {
         Self {
             readers: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            forward_gap,
+            backward_gap,
         }
     }
 
     pub fn get_reader(&self, offset: u64, size: u64) -> Reader {
         let mut readers = self.readers.lock().unwrap();
 
-        for reader in readers.iter_mut() {
-            if reader.matches(offset) {
-                reader.update(offset, size);
-                return reader.clone();
-            }
+        // with multiple streams reading the same file (two players, or ffmpeg and a player),
+        // a read can match more than one reader. the closest one is the stream it belongs to.
+        let closest = readers
+            .iter_mut()
+            .filter(|reader| reader.matches(offset, self.forward_gap, self.backward_gap))
+            .min_by_key(|reader| reader.position.abs_diff(offset));
+
+        if let Some(reader) = closest {
+            reader.update(offset, size);
+            return reader.clone();
         }
 
-        let new_reader = Reader::new(offset, size);
+        let mut new_reader = Reader::new(offset, size);
+        new_reader.id = self.next_id.fetch_add(1, Ordering::Relaxed);
         readers.push(new_reader.clone());
         new_reader
     }
+
+    /// Forgets readers that haven't read anything for `idle_for`, returning their ids.
+    pub fn remove_idle(&self, idle_for: Duration) -> Vec<u64> {
+        let mut readers = self.readers.lock().unwrap();
+        let mut removed = Vec::new();
+        readers.retain(|reader| {
+            let is_idle = reader.last_read.elapsed() >= idle_for;
+            if is_idle {
+                removed.push(reader.id);
+            }
+
+            !is_idle
+        });
+
+        removed
+    }
+
+    pub fn get_positions(&self) -> Vec<u64> {
+        let readers = self.readers.lock().unwrap();
+        readers.iter().map(|reader| reader.position).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_reader_matches_forward() {
+        let reader = Reader::new(0, MB);
+        assert!(reader.matches(MB, 16 * MB, 2 * MB));
+        assert!(reader.matches(17 * MB, 16 * MB, 2 * MB));
+        assert!(!reader.matches(17 * MB + 1, 16 * MB, 2 * MB));
+    }
+
+    #[test]
+    fn test_reader_matches_backward() {
+        let reader = Reader::new(10 * MB, MB);
+        assert!(reader.matches(9 * MB, 16 * MB, 2 * MB));
+        assert!(!reader.matches(8 * MB, 16 * MB, 2 * MB));
+    }
+
+    #[test]
+    fn test_readers_split_on_seek() {
+        let readers = Readers::new(16 * MB, 2 * MB);
+        readers.get_reader(100 * MB, MB);
+        let continued = readers.get_reader(101 * MB, MB);
+        assert_eq!(continued.bytes_read, 2 * MB);
+
+        // seeking backwards past the backward gap starts a fresh reader
+        let seeked = readers.get_reader(50 * MB, MB);
+        assert_eq!(seeked.bytes_read, MB);
+    }
+
+    #[test]
+    fn test_readers_interleaved_streams() {
+        let readers = Readers::new(16 * MB, 2 * MB);
+        let mut stream_a = None;
+        let mut stream_b = None;
+        for i in 0..10 {
+            stream_a = Some(readers.get_reader(i * MB, MB));
+            stream_b = Some(readers.get_reader(64 * MB + i * MB, MB));
+        }
+
+        assert_eq!(stream_a.unwrap().bytes_read, 10 * MB);
+        assert_eq!(stream_b.unwrap().bytes_read, 10 * MB);
+    }
+
+    #[test]
+    fn test_readers_prefer_closest() {
+        let readers = Readers::new(16 * MB, 2 * MB);
+        readers.get_reader(0, MB);
+        readers.get_reader(19 * MB, MB);
+        readers.get_reader(MB, 9 * MB);
+
+        // both readers are within the forward gap, but the read continues the second one
+        let reader = readers.get_reader(20 * MB, MB);
+        assert_eq!(reader.bytes_read, 2 * MB);
+        assert_eq!(reader.position, 21 * MB);
+    }
+
+    #[test]
+    fn test_reader_overlapping_reads_not_double_counted() {
+        let mut reader = Reader::new(0, MB);
+        reader.update(0, MB);
+        reader.update(MB / 2, MB);
+        assert_eq!(reader.bytes_read, MB + MB / 2);
+        assert_eq!(reader.position, MB + MB / 2);
+    }
+
+    #[test]
+    fn test_readers_remove_idle() {
+        let readers = Readers::new(16 * MB, 2 * MB);
+        let idle = readers.get_reader(0, MB);
+        let active = readers.get_reader(500 * MB, MB);
+        assert_ne!(idle.id, active.id);
+
+        readers.readers.lock().unwrap()[0].last_read = Instant::now() - Duration::from_secs(60);
+        assert_eq!(readers.remove_idle(Duration::from_secs(10)), vec![idle.id]);
+        assert_eq!(readers.get_positions(), vec![501 * MB]);
+    }
 }
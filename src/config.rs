@@ -1,25 +1,120 @@
-use serde::{Deserialize, Serialize};
+use crate::helpers::get_http_client_builder::load_ca_certs;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub cache_dir: Option<PathBuf>,
+    pub chunk_size: u64,
     pub chunk_preload: Option<(u64, u64)>,
+    pub preload_on_open: bool,
+    pub always_keep_preload: bool,
+    pub preload_extensions: Vec<String>,
+    /// Files with these extensions have their cached chunks compressed, nothing is compressed by default.
+    /// Only meant for text like subtitles, video is already compressed and would only be slower to read.
+    pub compress_extensions: Vec<String>,
+    pub preload_end_max_size: Option<u64>,
+    /// How many chunks from the end of a file are preloaded, overriding the end of `chunk_preload`.
+    /// MP4s with their index (moov) at the end and MKV cues only need the last chunk, which is the default.
+    pub preload_end_chunks: Option<u64>,
     pub mount_path: PathBuf,
     pub allow_other: bool,
     pub mount_unprivileged: bool,
     pub ensure_unmounted: bool,
+    pub provider: Provider,
     pub torbox_key: String,
+    pub torbox_base_url: String,
+    pub torbox_webdav_url: String,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub extra_ca_cert: Option<PathBuf>,
+    pub danger_accept_invalid_certs: bool,
     pub torbox_username: Option<String>,
     pub torbox_password: Option<String>,
+    pub realdebrid_key: String,
+    pub realdebrid_base_url: String,
     pub delete_unmapped: bool,
+    pub honor_remote_deletion: bool,
+    pub import_unmapped: bool,
     pub categories: Vec<String>,
+    pub default_category: Option<String>,
+    #[serde(deserialize_with = "deserialize_category_extensions")]
+    pub category_extensions: HashMap<String, Vec<String>>,
+    pub keep_extras_categories: Vec<String>,
+    pub audio_categories: Vec<String>,
+    pub root_dirs: Vec<String>,
+    pub cleanup_empty_dirs: bool,
+    pub append_trackers: bool,
+    pub strip_trackers: bool,
     pub max_torrent_size: Option<u64>,
     pub cache_target_size: u64,
     pub cache_max_size: u64,
+    pub cache_size_gap: u64,
     pub cache_grace_period_secs: u64,
     pub cache_sweep_interval_secs: u64,
+    pub playback_aware_eviction: bool,
+    pub reader_merge_gap_forward: u64,
+    pub reader_merge_gap_backward: u64,
+    pub read_ahead_idle_secs: u64,
+    pub max_read_ahead_chunks: u64,
+    pub max_file_download_batches: usize,
+    pub report_unready_as_empty: bool,
+    pub report_cached_blocks: bool,
+    #[serde(deserialize_with = "deserialize_u64_list")]
+    pub download_retry_secs: Vec<u64>,
+    #[serde(deserialize_with = "deserialize_u64_list")]
+    pub download_server_error_retry_secs: Vec<u64>,
+    pub read_timeout_secs: u64,
+    pub read_retries: u32,
+    pub read_while_downloading: bool,
+    /// Hashes chunks when they're downloaded and checks the hash the first time they're read,
+    /// so chunks that were corrupted on disk are downloaded again instead of being served.
+    pub chunk_verify: bool,
+    pub remove_orphaned_nodes: bool,
+    pub download_slots: usize,
+    pub max_creates_per_pass: usize,
+    pub reconcile_on_add: bool,
+    pub debug_rpc: bool,
+    pub sanitize_node_names: bool,
+    pub delete_immediately: bool,
+    pub min_torrent_age_secs: u64,
+    pub stuck_torrent_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub trust_download_present: bool,
+    pub verify_on_ready: bool,
+    pub reported_ratio: Option<f64>,
+    pub qbittorrent_version: String,
+    pub qbittorrent_webapi_version: String,
+    pub download_mode: DownloadMode,
+    pub download_http_version: HttpVersion,
+}
+
+/// The debrid service torrents are added to, see `debrid::get_debrid_provider`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Torbox,
+    Realdebrid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadMode {
+    /// Uses WebDAV if the username and password are set, otherwise download links.
+    Auto,
+    Webdav,
+    Link,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// Negotiated with the server, HTTP/2 is used if the server supports it.
+    Auto,
+    Http1,
+    Http2,
 }
 
 static CONFIG: once_cell::sync::Lazy<Config> =
@@ -30,21 +125,7 @@ pub fn get_config() -> &'static Config {
 }
 
 fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let cache_target_size: u64 = 100 * 1024 * 1024 * 1024; // 100GB
-    let cache_max_size: u64 = 125 * 1024 * 1024 * 1024; // 125GB
-    let chunk_preload: (u32, u32) = (4, 1); // preload the first/last N chunks
-    let config = config::Config::builder()
-        .add_source(config::Environment::with_prefix("lumin"))
-        .set_default("allow_other", false)?
-        .set_default("mount_unprivileged", true)?
-        .set_default("ensure_unmounted", true)?
-        .set_default("cache_target_size", cache_target_size)?
-        .set_default("cache_max_size", cache_max_size)?
-        .set_default("delete_unmapped", false)?
-        .set_default("categories", vec!["sonarr", "radarr"])?
-        .set_default("chunk_preload", vec![chunk_preload.0, chunk_preload.1])?
-        .set_default("cache_grace_period_secs", 300)? // 5 minutes
-        .set_default("cache_sweep_interval_secs", 60)? // 1 minute
+    let config = get_config_builder(config::Environment::with_prefix("lumin"))?
         .build()
         .unwrap();
 
@@ -66,8 +147,74 @@ fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
         std::fs::create_dir_all(&img_dir)?;
     }
 
-    if config.cache_target_size + 5000000000 > config.cache_max_size {
-        return Err("Cache target size must be less than 5GB less than cache max size".into());
+    for url in [
+        &mut config.torbox_base_url,
+        &mut config.torbox_webdav_url,
+        &mut config.realdebrid_base_url,
+    ] {
+        if !url::Url::parse(url).is_ok_and(|parsed| matches!(parsed.scheme(), "http" | "https")) {
+            return Err(format!("{} is not a valid http(s) url", url).into());
+        }
+
+        // endpoints and paths are appended with a leading slash
+        *url = url.trim_end_matches('/').to_string();
+    }
+
+    for proxy in [&config.http_proxy, &config.https_proxy].into_iter().flatten() {
+        if !url::Url::parse(proxy).is_ok_and(|parsed| matches!(parsed.scheme(), "http" | "https")) {
+            return Err(format!("proxy {} is not a valid http(s) url", proxy).into());
+        }
+    }
+
+    if let Some(path) = &config.extra_ca_cert {
+        load_ca_certs(path).map_err(|e| format!("invalid extra_ca_cert: {}", e))?;
+    }
+
+    if config.danger_accept_invalid_certs {
+        tracing::warn!("danger_accept_invalid_certs is enabled, TLS certificates will not be verified");
+    }
+
+    validate_cache_sizes(config.cache_target_size, config.cache_max_size, config.cache_size_gap)?;
+    if config.chunk_size == 0 || !config.chunk_size.is_multiple_of(1024 * 1024) {
+        return Err("chunk_size must be a multiple of 1MB".into());
+    }
+
+    if config.max_file_download_batches == 0 {
+        return Err("max_file_download_batches must be greater than 0".into());
+    }
+
+    validate_version("qbittorrent_version", &config.qbittorrent_version, true)?;
+    validate_version("qbittorrent_webapi_version", &config.qbittorrent_webapi_version, false)?;
+
+    if config.delete_unmapped && config.import_unmapped {
+        return Err("delete_unmapped and import_unmapped cannot both be enabled".into());
+    }
+
+    match config.provider {
+        Provider::Torbox => {
+            // the api is used to manage torrents in every download mode, not just for links
+            if config.torbox_key.is_empty() {
+                return Err("provider torbox requires torbox_key to be set".into());
+            }
+
+            if config.download_mode == DownloadMode::Webdav
+                && (config.torbox_username.is_none() || config.torbox_password.is_none())
+            {
+                return Err("download_mode webdav requires torbox_username and torbox_password to be set".into());
+            }
+        }
+        Provider::Realdebrid => {
+            if config.realdebrid_key.is_empty() {
+                return Err("provider realdebrid requires realdebrid_key to be set".into());
+            }
+
+            if config.download_mode == DownloadMode::Webdav {
+                return Err("download_mode webdav is only supported by the torbox provider".into());
+            }
+
+            // webdav is torbox only, files are always downloaded through unrestricted links
+            config.download_mode = DownloadMode::Link;
+        }
     }
 
     if config.categories.len() == 1 {
@@ -75,5 +222,355 @@ fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
         config.categories = first.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
     }
 
+    for categories in [&mut config.keep_extras_categories, &mut config.audio_categories] {
+        if categories.len() == 1 {
+            let first = categories.remove(0);
+            *categories = first
+                .split(",")
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<String>>();
+        }
+    }
+
+    if config.root_dirs.len() == 1 {
+        let first = config.root_dirs.remove(0);
+        config.root_dirs = first
+            .split(",")
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<String>>();
+    }
+
+    if config.preload_extensions.len() == 1 {
+        let first = config.preload_extensions.remove(0);
+        config.preload_extensions = first.split(",").map(|s| s.trim().to_string()).collect::<Vec<String>>();
+    }
+
+    config.preload_extensions = config
+        .preload_extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+
+    if config.compress_extensions.len() == 1 {
+        let first = config.compress_extensions.remove(0);
+        config.compress_extensions = first.split(",").map(|s| s.trim().to_string()).collect::<Vec<String>>();
+    }
+
+    config.compress_extensions = config
+        .compress_extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+
+    for root_dir in &config.root_dirs {
+        // downloads is managed by the reconciler, everything else under the root is left alone by it
+        if root_dir == "downloads" || root_dir == "." || root_dir == ".." || root_dir.contains('/') {
+            return Err(format!("root_dirs entry {} is not a valid directory name", root_dir).into());
+        }
+    }
+
+    if let Some(default_category) = &config.default_category
+        && !config.categories.contains(default_category)
+    {
+        return Err(format!("default_category {} is not in categories", default_category).into());
+    }
+
+    let filtered_categories = config
+        .category_extensions
+        .keys()
+        .chain(&config.keep_extras_categories)
+        .chain(&config.audio_categories);
+    for category in filtered_categories {
+        if !config.categories.contains(category) {
+            return Err(format!("category filter for {} is not in categories", category).into());
+        }
+    }
+
     Ok(config)
 }
+
+/// The sweeper shrinks the cache to the target size once it reaches the max size, the gap between them
+/// is the headroom for downloads between sweeps.
+fn validate_cache_sizes(target_size: u64, max_size: u64, gap: u64) -> Result<(), String> {
+    if target_size == 0 || max_size == 0 {
+        return Err("cache_target_size and cache_max_size must be greater than 0".to_string());
+    }
+
+    if target_size >= max_size {
+        return Err(format!(
+            "cache_target_size ({} bytes) must be less than cache_max_size ({} bytes)",
+            target_size, max_size
+        ));
+    }
+
+    if max_size - target_size < gap {
+        return Err(format!(
+            "cache_max_size must be at least cache_size_gap ({} bytes) larger than cache_target_size, \
+            lower cache_target_size or cache_size_gap",
+            gap
+        ));
+    }
+
+    Ok(())
+}
+
+/// Clients parse the reported versions to decide which endpoints and options to use, so they have to be
+/// dotted numbers like qbittorrent reports them. The app version has a leading "v" (`v4.3.2`), the web api
+/// version doesn't (`2.7`). Sonarr and Radarr need a web api version of at least 2.0 to use the v2 api,
+/// and only send some options when adding torrents (like the content layout) to newer versions.
+fn validate_version(key: &str, version: &str, with_prefix: bool) -> Result<(), String> {
+    let numbers = if with_prefix {
+        version.strip_prefix('v')
+    } else {
+        Some(version)
+    };
+
+    let is_valid = numbers.is_some_and(|numbers| {
+        let parts = numbers.split('.').collect::<Vec<_>>();
+        (2..=4).contains(&parts.len())
+            && parts
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+    });
+
+    if !is_valid {
+        let example = if with_prefix { "v4.3.2" } else { "2.7" };
+        return Err(format!("{} {} is not a valid version, eg {}", key, version, example));
+    }
+
+    Ok(())
+}
+
+fn get_config_builder(
+    source: config::Environment,
+) -> Result<config::ConfigBuilder<config::builder::DefaultState>, config::ConfigError> {
+    let cache_target_size: u64 = 100 * 1024 * 1024 * 1024; // 100GB
+    let cache_max_size: u64 = 125 * 1024 * 1024 * 1024; // 125GB
+    let chunk_size: u64 = 8 * 1024 * 1024; // 8MB
+    let chunk_preload: (u32, u32) = (4, 1); // preload the first/last N chunks
+    let reader_merge_gap_forward: u64 = 16 * 1024 * 1024; // 16MB
+    let reader_merge_gap_backward: u64 = 2 * 1024 * 1024; // 2MB
+    let builder = config::Config::builder()
+        .add_source(source)
+        .set_default("provider", "torbox")?
+        .set_default("torbox_key", "")?
+        .set_default("torbox_base_url", "https://api.torbox.app/v1/api")?
+        .set_default("torbox_webdav_url", "https://webdav.torbox.app")?
+        .set_default("realdebrid_key", "")?
+        .set_default("realdebrid_base_url", "https://api.real-debrid.com/rest/1.0")?
+        .set_default("allow_other", false)?
+        .set_default("mount_unprivileged", true)?
+        .set_default("ensure_unmounted", true)?
+        .set_default("cache_target_size", cache_target_size)?
+        .set_default("cache_max_size", cache_max_size)?
+        .set_default("cache_size_gap", 5_000_000_000u64)? // 5GB
+        .set_default("danger_accept_invalid_certs", false)?
+        .set_default("delete_unmapped", false)?
+        .set_default("honor_remote_deletion", false)?
+        .set_default("import_unmapped", false)?
+        .set_default("categories", vec!["sonarr", "radarr"])?
+        .set_default("category_extensions", "")? // eg "lidarr=mp3|flac|m4a"
+        .set_default("keep_extras_categories", Vec::<String>::new())?
+        .set_default("audio_categories", Vec::<String>::new())?
+        .set_default("root_dirs", Vec::<String>::new())?
+        .set_default("cleanup_empty_dirs", false)?
+        .set_default("append_trackers", true)?
+        .set_default("strip_trackers", false)?
+        .set_default("chunk_size", chunk_size)? // only used for new cache entries
+        .set_default("chunk_preload", vec![chunk_preload.0, chunk_preload.1])?
+        .set_default("preload_on_open", false)?
+        // preloaded chunks are never evicted, with the default preload that can be 48MB for every file that has been read
+        .set_default("always_keep_preload", false)?
+        .set_default("preload_extensions", Vec::<String>::new())? // empty preloads every file
+        .set_default("compress_extensions", Vec::<String>::new())? // eg "srt,ass,nfo"
+        .set_default("cache_grace_period_secs", 300)? // 5 minutes
+        .set_default("cache_sweep_interval_secs", 60)? // 1 minute
+        .set_default("playback_aware_eviction", false)?
+        .set_default("reader_merge_gap_forward", reader_merge_gap_forward)?
+        .set_default("reader_merge_gap_backward", reader_merge_gap_backward)?
+        .set_default("read_ahead_idle_secs", 10)?
+        .set_default("max_read_ahead_chunks", 32)? // 256MB, the largest read ahead target
+        .set_default("max_file_download_batches", 8)?
+        .set_default("report_unready_as_empty", false)?
+        .set_default("report_cached_blocks", false)?
+        .set_default("download_retry_secs", vec![1, 5, 30])? // 408/429/502/503/504
+        .set_default("download_server_error_retry_secs", vec![10, 60])? // 500
+        .set_default("read_timeout_secs", 120)? // 2 minutes
+        .set_default("read_retries", 2)?
+        .set_default("read_while_downloading", false)?
+        .set_default("chunk_verify", false)?
+        .set_default("remove_orphaned_nodes", true)?
+        .set_default("download_slots", 20)?
+        .set_default("max_creates_per_pass", 10)?
+        .set_default("reconcile_on_add", false)?
+        .set_default("debug_rpc", false)?
+        .set_default("sanitize_node_names", false)?
+        .set_default("delete_immediately", false)?
+        .set_default("min_torrent_age_secs", 300)? // 5 minutes
+        .set_default("trust_download_present", true)?
+        .set_default("verify_on_ready", false)?
+        .set_default("qbittorrent_version", "v4.3.2")?
+        .set_default("qbittorrent_webapi_version", "2.7")? // the api version of qbittorrent 4.3.2
+        .set_default("download_mode", "auto")?
+        .set_default("download_http_version", "auto")?;
+
+    Ok(builder)
+}
+
+/// A config with the defaults applied that doesn't touch the filesystem or the environment.
+#[cfg(test)]
+pub fn get_test_config(overrides: &[(&str, &str)]) -> Config {
+    let mut source = std::collections::HashMap::from([
+        ("data_dir".to_string(), "/tmp/lumin-test".to_string()),
+        ("mount_path".to_string(), "/tmp/lumin-test/mount".to_string()),
+        ("torbox_key".to_string(), "test".to_string()),
+    ]);
+
+    for (key, value) in overrides {
+        source.insert(key.to_string(), value.to_string());
+    }
+
+    get_config_builder(config::Environment::default().source(Some(source)))
+        .unwrap()
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+/// Accepts either a list or a comma separated string, so lists can be set with env vars (eg `5,30`).
+fn deserialize_u64_list<'de, D>(deserializer: D) -> Result<Vec<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ListOrString {
+        List(Vec<u64>),
+        String(String),
+    }
+
+    match ListOrString::deserialize(deserializer)? {
+        ListOrString::List(list) => Ok(list),
+        ListOrString::String(s) => s
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<u64>().map_err(serde::de::Error::custom))
+            .collect(),
+    }
+}
+
+/// Per category allowed extensions, as a map or a string so they can be set with env vars
+/// (eg `lidarr=mp3|flac,readarr=epub`). Extensions are lowercased and stored without the leading dot.
+fn deserialize_category_extensions<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MapOrString {
+        Map(HashMap<String, Vec<String>>),
+        String(String),
+    }
+
+    let map = match MapOrString::deserialize(deserializer)? {
+        MapOrString::Map(map) => map,
+        MapOrString::String(s) => s
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                let (category, extensions) = entry
+                    .split_once('=')
+                    .ok_or_else(|| serde::de::Error::custom(format!("{} is not category=extensions", entry)))?;
+                let extensions = extensions.split('|').map(|s| s.to_string()).collect();
+                Ok((category.trim().to_string(), extensions))
+            })
+            .collect::<Result<_, D::Error>>()?,
+    };
+
+    Ok(map
+        .into_iter()
+        .map(|(category, extensions)| {
+            let extensions = extensions
+                .iter()
+                .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                .filter(|extension| !extension.is_empty())
+                .collect();
+            (category, extensions)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct RetryConfig {
+        #[serde(deserialize_with = "deserialize_u64_list")]
+        retry_secs: Vec<u64>,
+    }
+
+    #[test]
+    fn test_validate_cache_sizes() {
+        let gb = 1024 * 1024 * 1024;
+        assert!(validate_cache_sizes(100 * gb, 125 * gb, 5 * gb).is_ok());
+        // small caches can use a smaller gap
+        assert!(validate_cache_sizes(18 * gb, 20 * gb, 5 * gb).is_err());
+        assert!(validate_cache_sizes(18 * gb, 20 * gb, gb).is_ok());
+
+        assert!(validate_cache_sizes(0, 20 * gb, 0).is_err());
+        assert!(validate_cache_sizes(20 * gb, 20 * gb, 0).is_err());
+        assert!(validate_cache_sizes(30 * gb, 20 * gb, 0).is_err());
+    }
+
+    #[test]
+    fn test_validate_version() {
+        assert!(validate_version("qbittorrent_version", "v4.3.2", true).is_ok());
+        assert!(validate_version("qbittorrent_version", "v5.0.0.1", true).is_ok());
+        assert!(validate_version("qbittorrent_version", "4.3.2", true).is_err());
+        assert!(validate_version("qbittorrent_version", "v4", true).is_err());
+        assert!(validate_version("qbittorrent_version", "v4.3.x", true).is_err());
+
+        assert!(validate_version("qbittorrent_webapi_version", "2.7", false).is_ok());
+        assert!(validate_version("qbittorrent_webapi_version", "2.11.4", false).is_ok());
+        assert!(validate_version("qbittorrent_webapi_version", "v2.7", false).is_err());
+        assert!(validate_version("qbittorrent_webapi_version", "2..7", false).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_u64_list() {
+        let config = config::Config::builder()
+            .set_default("retry_secs", vec![1, 5, 30])
+            .unwrap()
+            .build()
+            .unwrap();
+        let parsed: RetryConfig = config.try_deserialize().unwrap();
+        assert_eq!(parsed.retry_secs, vec![1, 5, 30]);
+
+        // env vars come through as a single string
+        let config = config::Config::builder()
+            .set_override("retry_secs", "10, 60")
+            .unwrap()
+            .build()
+            .unwrap();
+        let parsed: RetryConfig = config.try_deserialize().unwrap();
+        assert_eq!(parsed.retry_secs, vec![10, 60]);
+    }
+
+    #[test]
+    fn test_deserialize_category_extensions() {
+        let config = get_test_config(&[("category_extensions", "lidarr=mp3|.FLAC, readarr=epub")]);
+        assert_eq!(config.category_extensions.len(), 2);
+        assert_eq!(config.category_extensions["lidarr"], vec!["mp3", "flac"]);
+        assert_eq!(config.category_extensions["readarr"], vec!["epub"]);
+
+        assert!(get_test_config(&[]).category_extensions.is_empty());
+    }
+}
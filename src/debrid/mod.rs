@@ -1,14 +1,21 @@
-use crate::{config::get_config, helpers::get_user_agent::get_user_agent};
+use crate::{
+    config::{Config, Provider, get_config},
+    helpers::get_http_client_builder::get_http_client_builder,
+    state::TorrentState,
+};
 use anyhow::Result;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use ratelimit::Ratelimiter;
-use serde::{Deserialize, de::DeserializeOwned};
+use realdebrid::RealDebridProvider;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
 use serde_json::json;
+use sqlx::SqlitePool;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use tracing::info;
 
-const BASE_URL: &str = "https://api.torbox.app/v1/api";
+mod realdebrid;
 
 #[derive(Debug, Deserialize)]
 pub struct TorboxApiError {
@@ -28,7 +35,7 @@ impl std::fmt::Display for TorboxApiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "TorboxError({}): {}",
+            "DebridError({}): {}",
             self.error.as_deref().unwrap_or("UNKNOWN"),
             self.detail
         )
@@ -38,8 +45,8 @@ impl std::fmt::Display for TorboxApiError {
 impl std::error::Error for TorboxApiError {}
 
 #[derive(Debug, thiserror::Error)]
-pub enum TorboxError {
-    #[error("Torbox API error: {0}")]
+pub enum DebridError {
+    #[error("Debrid API error: {0}")]
     ApiError(#[from] TorboxApiError),
 
     #[error("Request error: {0}")]
@@ -58,12 +65,12 @@ enum TorboxResponse<T> {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct TorboxCreateTorrentData {
+pub struct DebridCreatedTorrent {
     pub torrent_id: u32,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct TorboxListTorrent {
+#[derive(Debug, Clone, Deserialize)]
+pub struct DebridTorrent {
     pub id: u64,
     pub hash: String,
     pub seeds: u32,
@@ -76,23 +83,30 @@ pub struct TorboxListTorrent {
     pub eta: u32,
     pub size: u64,
     pub upload_speed: u32,
-    pub download_state: String,
+    #[serde(rename = "download_state", deserialize_with = "deserialize_torbox_state")]
+    pub state: TorrentState,
     pub download_present: bool,
-    pub files: Option<Vec<TorboxTorrentFile>>,
+    pub files: Option<Vec<DebridTorrentFile>>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct TorboxTorrentFile {
+/// Torbox reports qbittorrent-like download states, other providers map their statuses to a state directly.
+fn deserialize_torbox_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TorrentState, D::Error> {
+    let download_state = String::deserialize(deserializer)?;
+    Ok(TorrentState::from_str(&download_state))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DebridTorrentFile {
     pub id: u64,
     pub name: String,
     pub size: u64,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct TorboxInstantAvailability(pub HashMap<String, TorboxInstantAvailabilityData>);
+pub struct DebridInstantAvailability(pub HashMap<String, DebridInstantAvailabilityData>);
 
 #[derive(Debug, Deserialize)]
-pub struct TorboxInstantAvailabilityData {
+pub struct DebridInstantAvailabilityData {
     pub name: String,
     pub size: u64,
     pub hash: String,
@@ -124,15 +138,36 @@ impl<T> From<T> for ExpiringItem<T> {
     }
 }
 
-pub struct Debrid {
+/// A debrid service lumin can add torrents to and stream files from, selected with the `provider` config key.
+/// Everything outside this module holds an `Arc<dyn DebridProvider>`, so it can be run against a fake service in tests.
+#[async_trait]
+pub trait DebridProvider: Send + Sync {
+    async fn get_torrent_list(&self, use_cache: bool) -> Result<Vec<DebridTorrent>, DebridError>;
+    async fn get_torrent_info(&self, torrent_id: &u32) -> Result<DebridTorrent, DebridError>;
+    async fn create_from_magnet(&self, magnet_uri: &str) -> Result<DebridCreatedTorrent, DebridError>;
+    async fn delete_torrent(&self, torrent_id: &u64) -> Result<(), DebridError>;
+    async fn check_cached(&self, hashes: &[String]) -> Result<DebridInstantAvailability, DebridError>;
+    async fn get_download_link(&self, torrent_id: i64, file_id: i64) -> Result<String, DebridError>;
+}
+
+pub fn get_debrid_provider(config: &Config, db: &SqlitePool) -> Arc<dyn DebridProvider> {
+    match config.provider {
+        Provider::Torbox => Arc::new(TorboxProvider::new()),
+        Provider::Realdebrid => Arc::new(RealDebridProvider::new(db.clone())),
+    }
+}
+
+pub struct TorboxProvider {
     client: reqwest::Client,
+    base_url: String,
     token: String,
     limiter: Ratelimiter,
+    limiter_queue: Mutex<()>,
     url_cache: Mutex<HashMap<String, ExpiringItem<String>>>,
     url_mutex: Mutex<HashMap<String, Arc<Mutex<()>>>>,
 }
 
-impl Debrid {
+impl TorboxProvider {
     pub fn new() -> Self {
         let config = get_config();
 
@@ -145,18 +180,97 @@ impl Debrid {
             .build()
             .expect("Failed to create rate limiter");
 
-        Debrid {
-            client: reqwest::Client::new(),
+        // api requests are small, anything taking this long is not going to finish
+        let client = get_http_client_builder(config)
+            .timeout(Duration::from_secs(60))
+            .build()
+            .expect("Failed to create http client");
+
+        TorboxProvider {
+            client,
+            base_url: config.torbox_base_url.clone(),
             token: config.torbox_key.clone(),
             url_cache: Mutex::new(HashMap::new()),
             url_mutex: Mutex::new(HashMap::new()),
             limiter,
+            limiter_queue: Mutex::new(()),
+        }
+    }
+
+    async fn set_cached_url(&self, file_hash: &str, url: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        let cached_url = ExpiringItem::new(url.to_string(), expires_at);
+        self.url_cache.lock().await.insert(file_hash.to_string(), cached_url);
+
+        Ok(())
+    }
+
+    async fn get_cached_url(&self, file_id: &str) -> Result<Option<String>> {
+        let mut url_cache = self.url_cache.lock().await;
+        match url_cache.get(file_id) {
+            Some(cached_url) => {
+                if cached_url.is_expired() {
+                    url_cache.remove(file_id);
+                    return Ok(None);
+                }
+
+                return Ok(Some(cached_url.item.to_string()));
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn add_headers(&self, builder: reqwest::RequestBuilder, auth: bool) -> reqwest::RequestBuilder {
+        let builder = if auth {
+            builder.header("Authorization", format!("Bearer {}", self.token))
+        } else {
+            builder
+        };
+
+        builder.header("Accept", "application/json")
+    }
+
+    fn parse_response<T: DeserializeOwned>(&self, mut response: serde_json::Value) -> Result<T, TorboxApiError> {
+        // doing it like this avoids issues with serdes untagged enums,
+        // mostly that if the response is a success but deserialization fails,
+        // it skips the error and tries to deserialize as an error which then
+        // either succeeds or causes an obscure error about no variant matching.
+        let is_success = response["success"].as_bool().unwrap_or(false);
+        if is_success {
+            let data = response["data"].take();
+            let data_clone = data.clone();
+            let result: T = serde_json::from_value(data).map_err(|e| TorboxApiError {
+                error: Some("Deserialization error".to_string()),
+                detail: e.to_string(),
+                data: TorboxApiErrorType::Generic(data_clone),
+            })?;
+
+            Ok(result)
+        } else {
+            let error = serde_json::from_value::<TorboxApiError>(response.clone()).unwrap();
+            Err(error)
         }
     }
 
-    pub async fn create_from_magnet(&self, magnet_uri: &str) -> Result<TorboxCreateTorrentData, TorboxError> {
+    async fn wait(&self) {
+        wait_for_token(&self.limiter, &self.limiter_queue).await;
+    }
+}
+
+/// Waits until the limiter has a token available. Waiters are queued so each one sleeps once for the
+/// duration the limiter returns, instead of every waiter racing for the same token.
+async fn wait_for_token(limiter: &Ratelimiter, queue: &Mutex<()>) {
+    let _guard = queue.lock().await;
+    while let Err(wait_for) = limiter.try_wait() {
+        // the token may not be available at exactly the returned time, so this can still loop
+        tokio::time::sleep(wait_for).await;
+    }
+}
+
+#[async_trait]
+impl DebridProvider for TorboxProvider {
+    async fn create_from_magnet(&self, magnet_uri: &str) -> Result<DebridCreatedTorrent, DebridError> {
         info!("Creating torrent from magnet: {}", magnet_uri);
-        let url = format!("{}/torrents/createtorrent", BASE_URL);
+        let url = format!("{}/torrents/createtorrent", self.base_url);
         let body = json!({ "magnet": magnet_uri, "allow_zip": false });
         self.wait().await;
         let response = self
@@ -167,12 +281,12 @@ impl Debrid {
             .json()
             .await?;
 
-        Ok(self.parse_response::<TorboxCreateTorrentData>(response)?)
+        Ok(self.parse_response::<DebridCreatedTorrent>(response)?)
     }
 
-    pub async fn delete_torrent(&self, torrent_id: &u64) -> Result<(), TorboxError> {
+    async fn delete_torrent(&self, torrent_id: &u64) -> Result<(), DebridError> {
         info!("Deleting torrent: {}", torrent_id);
-        let url = format!("{}/torrents/controltorrent", BASE_URL);
+        let url = format!("{}/torrents/controltorrent", self.base_url);
         self.wait().await;
         let response = self
             .add_headers(self.client.post(url), true)
@@ -189,8 +303,8 @@ impl Debrid {
         Ok(())
     }
 
-    pub async fn get_torrent_info(&self, torrent_id: &u32) -> Result<TorboxListTorrent, TorboxError> {
-        let url = format!("{}/torrents/mylist?bypass_cache=true&id={}", BASE_URL, torrent_id);
+    async fn get_torrent_info(&self, torrent_id: &u32) -> Result<DebridTorrent, DebridError> {
+        let url = format!("{}/torrents/mylist?bypass_cache=true&id={}", self.base_url, torrent_id);
 
         self.wait().await;
         let response = self
@@ -200,11 +314,11 @@ impl Debrid {
             .json()
             .await?;
 
-        Ok(self.parse_response::<TorboxListTorrent>(response)?)
+        Ok(self.parse_response::<DebridTorrent>(response)?)
     }
 
-    pub async fn get_torrent_list(&self, use_cache: bool) -> Result<Vec<TorboxListTorrent>, TorboxError> {
-        let url = format!("{}/torrents/mylist?bypass_cache={}", BASE_URL, !use_cache);
+    async fn get_torrent_list(&self, use_cache: bool) -> Result<Vec<DebridTorrent>, DebridError> {
+        let url = format!("{}/torrents/mylist?bypass_cache={}", self.base_url, !use_cache);
         self.wait().await;
         let response = self
             .add_headers(self.client.get(&url), true)
@@ -213,17 +327,20 @@ impl Debrid {
             .json()
             .await?;
 
-        Ok(self.parse_response::<Vec<TorboxListTorrent>>(response)?)
+        Ok(self.parse_response::<Vec<DebridTorrent>>(response)?)
     }
 
-    pub async fn check_cached(&self, hashes: &[String]) -> Result<TorboxInstantAvailability, TorboxError> {
+    async fn check_cached(&self, hashes: &[String]) -> Result<DebridInstantAvailability, DebridError> {
         let hash_batches = hashes
             .chunks(100)
             .map(|chunk| chunk.join(","))
             .collect::<Vec<_>>()
             .join("&hash=");
 
-        let url = format!("{}/torrents/checkcached?format=object&hash={}", BASE_URL, hash_batches);
+        let url = format!(
+            "{}/torrents/checkcached?format=object&hash={}",
+            self.base_url, hash_batches
+        );
 
         self.wait().await;
         let response = self
@@ -233,10 +350,10 @@ impl Debrid {
             .json()
             .await?;
 
-        Ok(self.parse_response::<TorboxInstantAvailability>(response)?)
+        Ok(self.parse_response::<DebridInstantAvailability>(response)?)
     }
 
-    pub async fn get_download_link(&self, torrent_id: i64, file_id: i64) -> Result<String, TorboxError> {
+    async fn get_download_link(&self, torrent_id: i64, file_id: i64) -> Result<String, DebridError> {
         let file_key = format!("{}:{}", torrent_id, file_id);
         let cached_url = self.get_cached_url(&file_key).await?;
         if let Some(cached_url) = cached_url {
@@ -263,7 +380,7 @@ impl Debrid {
 
         let url = format!(
             "{}/torrents/requestdl?torrent_id={}&file_id={}&token={}",
-            BASE_URL, torrent_id, file_id, self.token
+            self.base_url, torrent_id, file_id, self.token
         );
 
         info!("Requesting download link for file: {} via {}", file_key, url);
@@ -282,72 +399,38 @@ impl Debrid {
 
         Ok(data)
     }
+}
 
-    async fn set_cached_url(&self, file_hash: &str, url: &str, expires_at: DateTime<Utc>) -> Result<()> {
-        let cached_url = ExpiringItem::new(url.to_string(), expires_at);
-        self.url_cache.lock().await.insert(file_hash.to_string(), cached_url);
-
-        Ok(())
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    async fn get_cached_url(&self, file_id: &str) -> Result<Option<String>> {
-        let mut url_cache = self.url_cache.lock().await;
-        match url_cache.get(file_id) {
-            Some(cached_url) => {
-                if cached_url.is_expired() {
-                    url_cache.remove(file_id);
-                    return Ok(None);
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_wait_for_token_does_not_block_runtime() {
+        let limiter = Ratelimiter::builder(1, Duration::from_millis(200))
+            .max_tokens(1)
+            .initial_available(0)
+            .build()
+            .unwrap();
+        let queue = Mutex::new(());
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        // with a single thread runtime, this task can only run if the waiter yields
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
                 }
-
-                return Ok(Some(cached_url.item.to_string()));
-            }
-            None => Ok(None),
-        }
-    }
-
-    fn add_headers(&self, builder: reqwest::RequestBuilder, auth: bool) -> reqwest::RequestBuilder {
-        let builder = if auth {
-            builder.header("Authorization", format!("Bearer {}", self.token))
-        } else {
-            builder
+            })
         };
 
-        builder
-            .header("Accept", "application/json")
-            .header("User-Agent", get_user_agent())
-    }
+        wait_for_token(&limiter, &queue).await;
+        wait_for_token(&limiter, &queue).await;
+        ticker.abort();
 
-    fn parse_response<T: DeserializeOwned>(&self, mut response: serde_json::Value) -> Result<T, TorboxApiError> {
-        // doing it like this avoids issues with serdes untagged enums,
-        // mostly that if the response is a success but deserialization fails,
-        // it skips the error and tries to deserialize as an error which then
-        // either succeeds or causes an obscure error about no variant matching.
-        let is_success = response["success"].as_bool().unwrap_or(false);
-        if is_success {
-            let data = response["data"].take();
-            let data_clone = data.clone();
-            let result: T = serde_json::from_value(data).map_err(|e| TorboxApiError {
-                error: Some("Deserialization error".to_string()),
-                detail: e.to_string(),
-                data: TorboxApiErrorType::Generic(data_clone),
-            })?;
-
-            Ok(result)
-        } else {
-            let error = serde_json::from_value::<TorboxApiError>(response.clone()).unwrap();
-            Err(error)
-        }
-    }
-
-    // todo: this sucks ass
-    async fn wait(&self) {
-        loop {
-            if let Err(sleep) = self.limiter.try_wait() {
-                std::thread::sleep(sleep);
-                continue;
-            }
-
-            break;
-        }
+        assert!(ticks.load(Ordering::SeqCst) > 5);
     }
 }
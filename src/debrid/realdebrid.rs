@@ -0,0 +1,527 @@
+use super::{
+    DebridCreatedTorrent, DebridError, DebridInstantAvailability, DebridInstantAvailabilityData, DebridProvider,
+    DebridTorrent, DebridTorrentFile, ExpiringItem, wait_for_token,
+};
+use crate::{config::get_config, helpers::get_http_client_builder::get_http_client_builder, state::TorrentState};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::Utc;
+use ratelimit::Ratelimiter;
+use reqwest::StatusCode;
+use serde::{Deserialize, de::DeserializeOwned};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::Mutex;
+use tracing::info;
+
+// the api returns at most 5000 torrents per page
+const PAGE_SIZE: usize = 5000;
+
+// how many times to check a new torrent for its file list before leaving file selection to the next list
+const MAGNET_CONVERSION_CHECKS: usize = 5;
+
+#[derive(Debug, Deserialize)]
+struct RealDebridApiError {
+    error: String,
+    error_code: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealDebridTorrent {
+    id: String,
+    filename: String,
+    hash: String,
+    bytes: u64,
+    /// 0 to 100
+    progress: f32,
+    status: String,
+    #[serde(default)]
+    links: Vec<String>,
+    speed: Option<u32>,
+    seeders: Option<u32>,
+    /// Only included when requesting a single torrent.
+    files: Option<Vec<RealDebridFile>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealDebridFile {
+    id: u64,
+    /// Starts with a slash and doesn't include the torrent name.
+    path: String,
+    bytes: u64,
+    selected: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealDebridAddMagnet {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealDebridUnrestrictedLink {
+    download: String,
+}
+
+/// Real-Debrid torrent ids are strings, but torrents and files store the debrid id as an integer.
+/// Each real id is given a numeric id in the `realdebrid_ids` table the first time it's seen,
+/// so the real id can be looked up without listing torrents, even after a restart.
+pub struct RealDebridProvider {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+    limiter: Ratelimiter,
+    limiter_queue: Mutex<()>,
+    db: SqlitePool,
+    /// Files of downloaded torrents, they don't change once downloaded so they're only requested once.
+    files: Mutex<HashMap<String, Vec<DebridTorrentFile>>>,
+    url_cache: Mutex<HashMap<String, ExpiringItem<String>>>,
+}
+
+impl RealDebridProvider {
+    pub fn new(db: SqlitePool) -> Self {
+        let config = get_config();
+
+        // real-debrid allows 250 requests per minute
+        let limiter = Ratelimiter::builder(1, Duration::from_millis(250))
+            .max_tokens(10)
+            .initial_available(5)
+            .build()
+            .expect("Failed to create rate limiter");
+
+        let client = get_http_client_builder(config)
+            .timeout(Duration::from_secs(60))
+            .build()
+            .expect("Failed to create http client");
+
+        RealDebridProvider {
+            client,
+            base_url: config.realdebrid_base_url.clone(),
+            token: config.realdebrid_key.clone(),
+            limiter,
+            limiter_queue: Mutex::new(()),
+            db,
+            files: Mutex::new(HashMap::new()),
+            url_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn get_torrent(&self, id: &str) -> Result<RealDebridTorrent, DebridError> {
+        let url = format!("{}/torrents/info/{}", self.base_url, id);
+        let torrent = self.send(self.client.get(url)).await?;
+        torrent.ok_or_else(|| anyhow!("Real-Debrid returned no torrent for {}", id).into())
+    }
+
+    async fn select_files(&self, id: &str) -> Result<(), DebridError> {
+        info!("Selecting all files for torrent: {}", id);
+        let url = format!("{}/torrents/selectFiles/{}", self.base_url, id);
+        let request = self.client.post(url).form(&[("files", "all")]);
+        self.send::<serde_json::Value>(request).await?;
+        Ok(())
+    }
+
+    async fn get_real_id(&self, torrent_id: u64) -> Result<String, DebridError> {
+        let id = find_real_id(&self.db, torrent_id).await?;
+        id.ok_or_else(|| anyhow!("Unknown Real-Debrid torrent id {}", torrent_id).into())
+    }
+
+    async fn to_debrid_torrent(&self, torrent: RealDebridTorrent) -> Result<DebridTorrent, DebridError> {
+        let numeric_id = get_numeric_id(&self.db, &torrent.id).await?;
+
+        // the list doesn't include files, they're only requested once the torrent is downloaded
+        let files = if let Some(files) = &torrent.files {
+            Some(get_files(&torrent.filename, files))
+        } else if torrent.status == "downloaded" {
+            let cached = self.files.lock().await.get(&torrent.id).cloned();
+            match cached {
+                Some(files) => Some(files),
+                None => {
+                    let info = self.get_torrent(&torrent.id).await?;
+                    let files = get_files(&info.filename, info.files.as_deref().unwrap_or_default());
+                    self.files.lock().await.insert(torrent.id.clone(), files.clone());
+                    Some(files)
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(get_debrid_torrent(numeric_id, torrent, files))
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<Option<T>, DebridError> {
+        wait_for_token(&self.limiter, &self.limiter_queue).await;
+        let response = request
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("Accept", "application/json")
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+
+        if !status.is_success() {
+            let error = match response.json::<RealDebridApiError>().await {
+                Ok(error) => anyhow!(
+                    "Real-Debrid API error ({}): {}",
+                    error.error_code.unwrap_or_default(),
+                    error.error
+                ),
+                Err(_) => anyhow!("Real-Debrid API error: HTTP {}", status),
+            };
+
+            return Err(error.into());
+        }
+
+        Ok(Some(response.json().await?))
+    }
+}
+
+#[async_trait]
+impl DebridProvider for RealDebridProvider {
+    async fn get_torrent_list(&self, _use_cache: bool) -> Result<Vec<DebridTorrent>, DebridError> {
+        let mut torrents = Vec::new();
+        for page in 1.. {
+            let url = format!("{}/torrents?page={}&limit={}", self.base_url, page, PAGE_SIZE);
+            let batch: Vec<RealDebridTorrent> = self.send(self.client.get(url)).await?.unwrap_or_default();
+            let done = batch.len() < PAGE_SIZE;
+            torrents.extend(batch);
+            if done {
+                break;
+            }
+        }
+
+        let mut list = Vec::with_capacity(torrents.len());
+        for torrent in torrents {
+            if torrent.status == "waiting_files_selection" {
+                // the magnet was still being converted when it was added
+                self.select_files(&torrent.id).await?;
+            }
+
+            list.push(self.to_debrid_torrent(torrent).await?);
+        }
+
+        Ok(list)
+    }
+
+    async fn get_torrent_info(&self, torrent_id: &u32) -> Result<DebridTorrent, DebridError> {
+        let id = self.get_real_id(*torrent_id as u64).await?;
+        let torrent = self.get_torrent(&id).await?;
+        self.to_debrid_torrent(torrent).await
+    }
+
+    async fn create_from_magnet(&self, magnet_uri: &str) -> Result<DebridCreatedTorrent, DebridError> {
+        info!("Creating torrent from magnet: {}", magnet_uri);
+        let url = format!("{}/torrents/addMagnet", self.base_url);
+        let created: Option<RealDebridAddMagnet> =
+            self.send(self.client.post(url).form(&[("magnet", magnet_uri)])).await?;
+        let created = created.ok_or_else(|| anyhow!("Real-Debrid did not return the added torrent"))?;
+
+        let torrent_id = get_numeric_id(&self.db, &created.id).await?;
+
+        // nothing is downloaded until files are selected, which can only happen once the magnet is converted
+        for _ in 0..MAGNET_CONVERSION_CHECKS {
+            let torrent = self.get_torrent(&created.id).await?;
+            if torrent.status == "waiting_files_selection" {
+                self.select_files(&created.id).await?;
+                break;
+            }
+
+            if torrent.status != "magnet_conversion" {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+
+        Ok(DebridCreatedTorrent {
+            torrent_id: torrent_id as u32,
+        })
+    }
+
+    async fn delete_torrent(&self, torrent_id: &u64) -> Result<(), DebridError> {
+        info!("Deleting torrent: {}", torrent_id);
+        let id = self.get_real_id(*torrent_id).await?;
+        let url = format!("{}/torrents/delete/{}", self.base_url, id);
+        self.send::<serde_json::Value>(self.client.delete(url)).await?;
+        let numeric_id = *torrent_id as i64;
+        sqlx::query!("DELETE FROM realdebrid_ids WHERE id = ?", numeric_id)
+            .execute(&self.db)
+            .await
+            .map_err(anyhow::Error::from)?;
+        self.files.lock().await.remove(&id);
+        Ok(())
+    }
+
+    async fn check_cached(&self, hashes: &[String]) -> Result<DebridInstantAvailability, DebridError> {
+        let mut availability = HashMap::new();
+        for batch in hashes.chunks(100) {
+            let url = format!("{}/torrents/instantAvailability/{}", self.base_url, batch.join("/"));
+            let response: Option<serde_json::Value> = self.send(self.client.get(url)).await?;
+            if let Some(response) = response {
+                availability.extend(parse_instant_availability(&response));
+            }
+        }
+
+        Ok(DebridInstantAvailability(availability))
+    }
+
+    async fn get_download_link(&self, torrent_id: i64, file_id: i64) -> Result<String, DebridError> {
+        let file_key = format!("{}:{}", torrent_id, file_id);
+        {
+            let mut url_cache = self.url_cache.lock().await;
+            match url_cache.get(&file_key) {
+                Some(cached_url) if !cached_url.is_expired() => return Ok(cached_url.item.clone()),
+                Some(_) => {
+                    url_cache.remove(&file_key);
+                }
+                None => {}
+            }
+        }
+
+        // links are hoster links to the selected files in order, which have to be unrestricted to download them
+        let id = self.get_real_id(torrent_id as u64).await?;
+        let torrent = self.get_torrent(&id).await?;
+        let link = get_file_link(&torrent, file_id as u64)
+            .ok_or_else(|| anyhow!("Torrent {} has no link for file {}", id, file_id))?;
+
+        info!("Requesting download link for file: {}", file_key);
+        let start = Utc::now();
+        let url = format!("{}/unrestrict/link", self.base_url);
+        let unrestricted: Option<RealDebridUnrestrictedLink> =
+            self.send(self.client.post(url).form(&[("link", link)])).await?;
+        let unrestricted = unrestricted.ok_or_else(|| anyhow!("Real-Debrid did not return a download link"))?;
+
+        let cached_url = ExpiringItem::new(unrestricted.download.clone(), start + chrono::Duration::minutes(175));
+        self.url_cache.lock().await.insert(file_key, cached_url);
+        Ok(unrestricted.download)
+    }
+}
+
+fn get_torrent_state(status: &str) -> TorrentState {
+    match status {
+        "magnet_conversion" | "waiting_files_selection" | "queued" => TorrentState::Pending,
+        "downloading" => TorrentState::Downloading,
+        // uploading is real-debrid moving the finished download to its hosters, not seeding
+        "compressing" | "uploading" => TorrentState::Stalled,
+        "downloaded" => TorrentState::Ready,
+        _ => TorrentState::Error,
+    }
+}
+
+/// Returns the numeric id for a real id, giving it the next free id if it doesn't have one yet.
+async fn get_numeric_id(db: &SqlitePool, real_id: &str) -> anyhow::Result<u64> {
+    let id = sqlx::query_scalar!(
+        r#"INSERT INTO realdebrid_ids (real_id) VALUES (?)
+        ON CONFLICT (real_id) DO UPDATE SET real_id = excluded.real_id
+        RETURNING id as "id!: i64""#,
+        real_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(id as u64)
+}
+
+async fn find_real_id(db: &SqlitePool, id: u64) -> anyhow::Result<Option<String>> {
+    let id = id as i64;
+    let real_id = sqlx::query_scalar!("SELECT real_id FROM realdebrid_ids WHERE id = ?", id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(real_id)
+}
+
+/// Builds file names the way Torbox does, prefixed with the torrent name for multi file torrents.
+fn get_files(torrent_name: &str, files: &[RealDebridFile]) -> Vec<DebridTorrentFile> {
+    let selected = files.iter().filter(|file| file.selected == 1).collect::<Vec<_>>();
+    let is_single_file = selected.len() == 1;
+    selected
+        .into_iter()
+        .map(|file| {
+            let path = file.path.trim_start_matches('/');
+            let name = if is_single_file {
+                path.to_string()
+            } else {
+                format!("{}/{}", torrent_name, path)
+            };
+
+            DebridTorrentFile {
+                id: file.id,
+                name,
+                size: file.bytes,
+            }
+        })
+        .collect()
+}
+
+fn get_file_link(torrent: &RealDebridTorrent, file_id: u64) -> Option<&str> {
+    let files = torrent.files.as_ref()?;
+    let index = files
+        .iter()
+        .filter(|file| file.selected == 1)
+        .position(|file| file.id == file_id)?;
+
+    torrent.links.get(index).map(|link| link.as_str())
+}
+
+fn get_debrid_torrent(id: u64, torrent: RealDebridTorrent, files: Option<Vec<DebridTorrentFile>>) -> DebridTorrent {
+    let state = get_torrent_state(&torrent.status);
+    DebridTorrent {
+        id,
+        hash: torrent.hash.to_lowercase(),
+        seeds: torrent.seeders.unwrap_or(0),
+        peers: 0,
+        name: torrent.filename,
+        ratio: 0.0,
+        progress: torrent.progress / 100.0,
+        download_speed: torrent.speed.unwrap_or(0),
+        active: matches!(
+            state,
+            TorrentState::Pending | TorrentState::Downloading | TorrentState::Stalled
+        ),
+        eta: 0,
+        size: torrent.bytes,
+        upload_speed: 0,
+        state,
+        download_present: torrent.status == "downloaded",
+        files,
+    }
+}
+
+/// Unavailable hashes are returned as an empty array or object, available ones have a list of file
+/// variants per hoster. The first variant is used for the name and size.
+fn parse_instant_availability(response: &serde_json::Value) -> HashMap<String, DebridInstantAvailabilityData> {
+    let mut availability = HashMap::new();
+    let Some(hashes) = response.as_object() else {
+        return availability;
+    };
+
+    for (hash, hosters) in hashes {
+        let variant = hosters
+            .get("rd")
+            .and_then(|variants| variants.as_array())
+            .and_then(|variants| variants.first())
+            .and_then(|variant| variant.as_object());
+
+        let Some(variant) = variant else {
+            continue;
+        };
+
+        let name = variant
+            .values()
+            .find_map(|file| file["filename"].as_str())
+            .unwrap_or_default()
+            .to_string();
+        let size = variant.values().filter_map(|file| file["filesize"].as_u64()).sum();
+
+        let hash = hash.to_lowercase();
+        let data = DebridInstantAvailabilityData {
+            name,
+            size,
+            hash: hash.clone(),
+        };
+
+        availability.insert(hash, data);
+    }
+
+    availability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::get_test_pool;
+
+    #[test]
+    fn test_torrent_state() {
+        let states = [
+            ("magnet_conversion", TorrentState::Pending),
+            ("waiting_files_selection", TorrentState::Pending),
+            ("queued", TorrentState::Pending),
+            ("downloading", TorrentState::Downloading),
+            ("uploading", TorrentState::Stalled),
+            ("downloaded", TorrentState::Ready),
+            ("magnet_error", TorrentState::Error),
+            ("virus", TorrentState::Error),
+            ("dead", TorrentState::Error),
+        ];
+
+        for (status, state) in states {
+            assert_eq!(get_torrent_state(status), state, "{}", status);
+        }
+    }
+
+    #[test]
+    fn test_debrid_torrent() {
+        let torrent: RealDebridTorrent = serde_json::from_value(serde_json::json!({
+            "id": "NZ2FCCPWLAH2Q",
+            "filename": "Show S01",
+            "hash": "1234567890ABCDEF1234567890ABCDEF12345678",
+            "bytes": 300,
+            "progress": 100,
+            "status": "downloaded",
+            "links": ["https://real-debrid.com/d/E01", "https://real-debrid.com/d/E02"],
+            "files": [
+                { "id": 1, "path": "/E01.mkv", "bytes": 100, "selected": 1 },
+                { "id": 2, "path": "/E01.nfo", "bytes": 100, "selected": 0 },
+                { "id": 3, "path": "/E02.mkv", "bytes": 100, "selected": 1 },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(get_file_link(&torrent, 3), Some("https://real-debrid.com/d/E02"));
+        assert_eq!(get_file_link(&torrent, 2), None);
+
+        let files = get_files(&torrent.filename, torrent.files.as_deref().unwrap());
+        let debrid_torrent = get_debrid_torrent(1, torrent, Some(files));
+        assert_eq!(debrid_torrent.hash, "1234567890abcdef1234567890abcdef12345678");
+        assert_eq!(debrid_torrent.progress, 1.0);
+        assert!(debrid_torrent.download_present);
+        assert!(!debrid_torrent.active);
+
+        let names = debrid_torrent
+            .files
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Show S01/E01.mkv", "Show S01/E02.mkv"]);
+    }
+
+    #[tokio::test]
+    async fn test_numeric_ids() {
+        let pool = get_test_pool(false).await;
+        let first = get_numeric_id(&pool, "NZ2FCCPWLAH2Q").await.unwrap();
+        let second = get_numeric_id(&pool, "XQ7P2YB4WMJRA").await.unwrap();
+        assert_ne!(first, second);
+        // the same real id always gets the same numeric id
+        assert_eq!(get_numeric_id(&pool, "NZ2FCCPWLAH2Q").await.unwrap(), first);
+
+        assert_eq!(
+            find_real_id(&pool, second).await.unwrap().as_deref(),
+            Some("XQ7P2YB4WMJRA")
+        );
+        assert_eq!(find_real_id(&pool, second + 1).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_instant_availability() {
+        let response = serde_json::json!({
+            "1234567890ABCDEF1234567890ABCDEF12345678": {
+                "rd": [{
+                    "1": { "filename": "E01.mkv", "filesize": 100 },
+                    "2": { "filename": "E02.mkv", "filesize": 200 },
+                }],
+            },
+            "abcdef1234567890abcdef1234567890abcdef12": [],
+        });
+
+        let availability = parse_instant_availability(&response);
+        assert_eq!(availability.len(), 1);
+        let data = &availability["1234567890abcdef1234567890abcdef12345678"];
+        assert_eq!(data.size, 300);
+        assert_eq!(data.name, "E01.mkv");
+    }
+}
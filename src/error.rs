@@ -3,6 +3,7 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use serde::Serialize;
 use serde_json::json;
 
 // Make our own error that wraps `anyhow::Error`.
@@ -31,3 +32,66 @@ where
         Self(err.into())
     }
 }
+
+/// Errors returned by the RPC api, the code lets clients tell failures apart without parsing the message.
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: RpcErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcErrorCode {
+    NotFound,
+    BadRequest,
+    InternalServerError,
+}
+
+impl RpcError {
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            code: RpcErrorCode::NotFound,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            code: RpcErrorCode::BadRequest,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for RpcError {
+    fn into_response(self) -> Response {
+        let status = match self.code {
+            RpcErrorCode::NotFound => StatusCode::NOT_FOUND,
+            RpcErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            RpcErrorCode::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (
+            status,
+            Json(json!({
+                "error_code": self.code,
+                "error_message": self.message,
+            })),
+        )
+            .into_response()
+    }
+}
+
+// Anything that isn't explicitly mapped to a code is an internal error, same as `AppError`.
+impl<E> From<E> for RpcError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self {
+            code: RpcErrorCode::InternalServerError,
+            message: err.into().to_string(),
+        }
+    }
+}
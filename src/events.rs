@@ -0,0 +1,131 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+// older events are pruned whenever a new one is recorded
+const MAX_EVENTS: i64 = 1000;
+
+#[derive(Debug, PartialEq, Clone, Eq, Copy, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum EventKind {
+    Added = 0,
+    /// The client asked for the torrent to be deleted, it's removed once nothing references it.
+    Deleted = 1,
+    Removed = 2,
+    Ready = 3,
+    Failed = 4,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Event {
+    pub id: i64,
+    pub kind: EventKind,
+    pub torrent_hash: String,
+    pub torrent_name: String,
+    pub message: Option<String>,
+    pub created_at: i64,
+}
+
+/// The event log is best effort, failing to write to it is logged and never fails the action being recorded.
+pub async fn record_event(
+    db: &SqlitePool,
+    kind: EventKind,
+    torrent_hash: &str,
+    torrent_name: &str,
+    message: Option<&str>,
+) {
+    if let Err(e) = insert_event(db, kind, torrent_hash, torrent_name, message).await {
+        tracing::warn!("failed to record {:?} event for torrent {}: {}", kind, torrent_hash, e);
+    }
+}
+
+async fn insert_event(
+    db: &SqlitePool,
+    kind: EventKind,
+    torrent_hash: &str,
+    torrent_name: &str,
+    message: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO events (kind, torrent_hash, torrent_name, message) VALUES (?, ?, ?, ?)",
+        kind,
+        torrent_hash,
+        torrent_name,
+        message
+    )
+    .execute(db)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM events WHERE id <= (SELECT MAX(id) FROM events) - ?",
+        MAX_EVENTS
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the most recent events, newest first.
+pub async fn find_recent_events(db: &SqlitePool, limit: i64) -> Result<Vec<Event>, sqlx::Error> {
+    let limit = limit.clamp(0, MAX_EVENTS);
+    sqlx::query_as!(
+        Event,
+        r#"SELECT id, kind as "kind: EventKind", torrent_hash, torrent_name, message, created_at
+        FROM events ORDER BY id DESC LIMIT ?"#,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::get_test_pool;
+
+    #[tokio::test]
+    async fn test_recent_events() {
+        let pool = get_test_pool(false).await;
+        insert_event(&pool, EventKind::Added, "abc", "Show", None)
+            .await
+            .unwrap();
+        insert_event(
+            &pool,
+            EventKind::Failed,
+            "abc",
+            "Show",
+            Some("Torrent has no valid files"),
+        )
+        .await
+        .unwrap();
+
+        let events = find_recent_events(&pool, 10).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, EventKind::Failed);
+        assert_eq!(events[0].message.as_deref(), Some("Torrent has no valid files"));
+        assert_eq!(events[1].kind, EventKind::Added);
+        assert_eq!(find_recent_events(&pool, 1).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_pruned() {
+        let pool = get_test_pool(false).await;
+        for i in 0..MAX_EVENTS + 5 {
+            insert_event(&pool, EventKind::Added, &i.to_string(), "Show", None)
+                .await
+                .unwrap();
+        }
+
+        let events = find_recent_events(&pool, MAX_EVENTS * 2).await.unwrap();
+        assert_eq!(events.len(), MAX_EVENTS as usize);
+        assert_eq!(events[0].torrent_hash, (MAX_EVENTS + 4).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_record_event_failure_is_not_fatal() {
+        let pool = get_test_pool(false).await;
+        pool.close().await;
+        record_event(&pool, EventKind::Added, "abc", "Show", None).await;
+    }
+}
@@ -19,3 +19,31 @@ pub fn add_trackers_to_magnet_uri(magnet_uri: &str) -> String {
 
     parsed_magnet.to_string()
 }
+
+/// Removes every tracker from the magnet, leaving the debrid service to find peers through DHT.
+pub fn strip_trackers_from_magnet_uri(magnet_uri: &str) -> String {
+    let mut parsed_magnet = url::Url::parse(magnet_uri).expect("Invalid magnet URI");
+    let pairs = parsed_magnet
+        .query_pairs()
+        .filter(|(key, _)| key != "tr")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    parsed_magnet.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed_magnet.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_trackers_from_magnet_uri() {
+        let magnet = "magnet:?xt=urn:btih:1234567890abcdef1234567890abcdef12345678&dn=example\
+            &tr=udp%3A%2F%2Ftracker.example.com%3A1337%2Fannounce";
+        assert_eq!(
+            strip_trackers_from_magnet_uri(magnet),
+            "magnet:?xt=urn%3Abtih%3A1234567890abcdef1234567890abcdef12345678&dn=example"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::helpers::get_user_agent::get_user_agent;
+use std::path::Path;
+use std::time::Duration;
+
+/// Returns a client builder with the settings shared by all outbound requests, so timeouts,
+/// connection pooling and proxies are configured in one place.
+pub fn get_http_client_builder(config: &Config) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(get_user_agent())
+        .connect_timeout(Duration::from_secs(30))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60));
+
+    // the urls are validated when the config is loaded. like the standard env vars, each proxy
+    // is used for requests to urls with that scheme. setting either disables the env vars.
+    if let Some(proxy) = &config.http_proxy {
+        builder = builder.proxy(reqwest::Proxy::http(proxy).expect("invalid http_proxy"));
+    }
+
+    if let Some(proxy) = &config.https_proxy {
+        builder = builder.proxy(reqwest::Proxy::https(proxy).expect("invalid https_proxy"));
+    }
+
+    if let Some(path) = &config.extra_ca_cert {
+        for cert in load_ca_certs(path).expect("invalid extra_ca_cert") {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if config.danger_accept_invalid_certs {
+        // only meant for testing against self-hosted endpoints, this disables all certificate checks
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder
+}
+
+/// Loads every certificate in a PEM file, erroring if there are none.
+pub fn load_ca_certs(path: &Path) -> Result<Vec<reqwest::Certificate>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem)
+        .map_err(|e| format!("{} is not a valid PEM certificate: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("{} does not contain any certificates", path.display()));
+    }
+
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_test_config;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_load_ca_certs() {
+        let dir = std::env::temp_dir().join("lumin-test-certs");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("empty.pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+
+        assert!(
+            load_ca_certs(&path)
+                .unwrap_err()
+                .contains("does not contain any certificates")
+        );
+        assert!(
+            load_ca_certs(&dir.join("missing.pem"))
+                .unwrap_err()
+                .starts_with("failed to read")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_http_client_builder_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+
+            String::from_utf8_lossy(&buf[..read]).into_owned()
+        });
+
+        let config = get_test_config(&[("http_proxy", &proxy_url)]);
+        let client = get_http_client_builder(&config).build().unwrap();
+        let response = client.get("http://lumin.invalid/test").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // requests through a proxy use the absolute url as the request target
+        let request = proxy.await.unwrap();
+        assert!(request.starts_with("GET http://lumin.invalid/test HTTP/1.1"));
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashSet;
+
+/// Returns `name`, or `name (N).ext` if `name` is already taken.
+pub fn get_unique_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+
+    let mut i = 1;
+    loop {
+        let candidate = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, i, extension),
+            None => format!("{} ({})", stem, i),
+        };
+
+        if !taken.contains(&candidate) {
+            return candidate;
+        }
+
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_unique_name() {
+        let taken = HashSet::from([
+            "movie.mkv".to_string(),
+            "movie (1).mkv".to_string(),
+            "extras".to_string(),
+        ]);
+        assert_eq!(get_unique_name("other.mkv", &taken), "other.mkv");
+        assert_eq!(get_unique_name("movie.mkv", &taken), "movie (2).mkv");
+        assert_eq!(get_unique_name("extras", &taken), "extras (1)");
+    }
+}
@@ -1,4 +1,8 @@
 pub mod add_trackers_to_magnet_uri;
+pub mod get_http_client_builder;
+pub mod get_unique_name;
 pub mod get_user_agent;
+pub mod now_secs;
 pub mod parse_magnet_uri;
+pub mod sanitize_node_name;
 pub mod should_ignore_path;
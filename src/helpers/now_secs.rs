@@ -0,0 +1,4 @@
+/// All timestamps in the database are stored as seconds since the unix epoch, the same as `unixepoch()`.
+pub fn now_secs() -> i64 {
+    chrono::Utc::now().timestamp()
+}
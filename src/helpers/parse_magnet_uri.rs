@@ -1,16 +1,25 @@
+/// Hybrid magnets carry both a v1 (`urn:btih:`) and a v2 (`urn:btmh:`) hash for the same content.
+/// The v1 hash is always used as the canonical hash because torbox keys torrents by it, so a hybrid
+/// magnet and a v1 magnet for the same content resolve to the same torrent. Magnets with only a v2
+/// hash are not supported.
 pub struct ParsedMagnet {
     pub hash: String,
+    pub v2_hash: Option<String>,
     pub name: Option<String>,
 }
 
 pub fn parse_magnet_uri(magnet_uri: &str) -> Option<ParsedMagnet> {
     let parts = url::Url::parse(magnet_uri).ok()?;
     let mut hash = None;
+    let mut v2_hash = None;
     let mut name = None;
     for (key, value) in parts.query_pairs() {
         match key.as_ref() {
             "xt" if value.starts_with("urn:btih:") => {
-                hash = Some(value[9..].to_lowercase().to_string());
+                hash.get_or_insert_with(|| value[9..].to_lowercase());
+            }
+            "xt" if value.starts_with("urn:btmh:") => {
+                v2_hash.get_or_insert_with(|| value[9..].to_lowercase());
             }
             "dn" => {
                 name = Some(value.to_string());
@@ -20,7 +29,7 @@ pub fn parse_magnet_uri(magnet_uri: &str) -> Option<ParsedMagnet> {
     }
 
     if let Some(hash) = hash {
-        Some(ParsedMagnet { hash, name })
+        Some(ParsedMagnet { hash, v2_hash, name })
     } else {
         None
     }
@@ -51,4 +60,29 @@ mod tests {
         let magnet = "invalid_magnet_uri";
         assert!(parse_magnet_uri(magnet).is_none());
     }
+
+    #[test]
+    fn test_parse_hybrid_magnet_uri() {
+        let magnet = "magnet:?xt=urn:btih:1234567890ABCDEF1234567890ABCDEF12345678\
+            &xt=urn:btmh:1220aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa&dn=example_file";
+        let parsed = parse_magnet_uri(magnet).unwrap();
+        assert_eq!(parsed.hash, "1234567890abcdef1234567890abcdef12345678");
+        assert_eq!(
+            parsed.v2_hash,
+            Some("1220aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_string())
+        );
+
+        // the order of the xt params must not change the canonical hash
+        let magnet = "magnet:?xt=urn:btmh:1220aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\
+            &xt=urn:btih:1234567890abcdef1234567890abcdef12345678";
+        let parsed = parse_magnet_uri(magnet).unwrap();
+        assert_eq!(parsed.hash, "1234567890abcdef1234567890abcdef12345678");
+        assert!(parsed.v2_hash.is_some());
+    }
+
+    #[test]
+    fn test_parse_v2_only_magnet_uri() {
+        let magnet = "magnet:?xt=urn:btmh:1220aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert!(parse_magnet_uri(magnet).is_none());
+    }
 }
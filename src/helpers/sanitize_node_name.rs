@@ -0,0 +1,57 @@
+/// Normalizes a single path component so it is friendlier to media scanners: control characters are
+/// removed, runs of whitespace are collapsed and leading/trailing whitespace and trailing dots are trimmed.
+pub fn sanitize_node_name(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    let mut last_was_space = false;
+    for c in name.chars() {
+        if c.is_control() {
+            continue;
+        }
+
+        if c.is_whitespace() {
+            if !last_was_space {
+                sanitized.push(' ');
+            }
+
+            last_was_space = true;
+            continue;
+        }
+
+        sanitized.push(c);
+        last_was_space = false;
+    }
+
+    let sanitized = sanitized.trim().trim_end_matches(['.', ' ']);
+    if sanitized.is_empty() {
+        return "_".to_string();
+    }
+
+    sanitized.to_string()
+}
+
+/// Sanitizes each component of a `/` separated path.
+pub fn sanitize_node_path(path: &str) -> String {
+    path.split('/').map(sanitize_node_name).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_node_name() {
+        assert_eq!(sanitize_node_name("Normal Name.mkv"), "Normal Name.mkv");
+        assert_eq!(sanitize_node_name("Bad\u{0007}Name\r\n.mkv"), "BadName.mkv");
+        assert_eq!(sanitize_node_name("Too   Many \t Spaces.mkv"), "Too Many Spaces.mkv");
+        assert_eq!(sanitize_node_name("  Season 1. . "), "Season 1");
+        assert_eq!(sanitize_node_name("..."), "_");
+    }
+
+    #[test]
+    fn test_sanitize_node_path() {
+        assert_eq!(
+            sanitize_node_path("Show.  \u{0000}/Season 1 /E01.mkv"),
+            "Show/Season 1/E01.mkv"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::Config;
 use lazy_static::lazy_static;
 use regex::Regex;
 
@@ -6,6 +7,11 @@ const ALLOWED_EXTS: [&str; 11] = [
     ".srt", ".sub", // subtitles
 ];
 
+// only allowed for audio_categories, so video libraries don't pick up soundtracks
+const AUDIO_EXTS: [&str; 6] = [".flac", ".mp3", ".m4a", ".opus", ".ogg", ".wav"];
+
+const ARCHIVE_EXTS: [&str; 5] = [".zip", ".rar", ".7z", ".tar", ".gz"];
+
 lazy_static! {
     static ref PART_FILTERS: Vec<Regex> = vec![
         Regex::new(r"^lore$").unwrap(),
@@ -22,13 +28,61 @@ lazy_static! {
     ];
 }
 
-pub fn should_ignore_path(input: &str) -> bool {
-    if !ALLOWED_EXTS.iter().any(|ext| input.ends_with(ext)) {
-        return true;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStatus {
+    Allowed,
+    DisallowedExtension,
+    FilteredPathPart,
+}
+
+/// Per category overrides for the default filters, see `get_path_filters`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PathFilters<'a> {
+    /// Allowed extensions without the leading dot, None allows the default video and subtitle extensions.
+    pub extensions: Option<&'a [String]>,
+    /// Keeps files in directories like samples and extras that are filtered out otherwise.
+    pub keep_extras: bool,
+    /// Allows audio files on top of the default extensions.
+    pub allow_audio: bool,
+}
+
+/// The filters for torrents in a category, categories without overrides use the defaults.
+pub fn get_path_filters<'a>(config: &'a Config, category: Option<&str>) -> PathFilters<'a> {
+    let Some(category) = category else {
+        return PathFilters::default();
+    };
+
+    PathFilters {
+        extensions: config.category_extensions.get(category).map(|e| e.as_slice()),
+        keep_extras: config.keep_extras_categories.iter().any(|c| c == category),
+        allow_audio: config.audio_categories.iter().any(|c| c == category),
+    }
+}
+
+pub fn should_ignore_path(input: &str, filters: PathFilters) -> bool {
+    get_path_status(input, filters) != PathStatus::Allowed
+}
+
+pub fn get_path_status(input: &str, filters: PathFilters) -> PathStatus {
+    let is_allowed_ext = match filters.extensions {
+        Some(extensions) => {
+            let input = input.to_lowercase();
+            extensions.iter().any(|ext| input.ends_with(&format!(".{}", ext)))
+        }
+        None => {
+            ALLOWED_EXTS.iter().any(|ext| input.ends_with(ext))
+                || (filters.allow_audio && AUDIO_EXTS.iter().any(|ext| input.to_lowercase().ends_with(ext)))
+        }
+    };
+
+    if !is_allowed_ext {
+        return PathStatus::DisallowedExtension;
+    }
+
+    if filters.keep_extras {
+        return PathStatus::Allowed;
     }
 
-    // todo: these should be optional, some people might want to keep these,
-    // this is more of a hold over from the old implementation.
     let path_parts = input.split('/');
     for path_part in path_parts {
         if path_part.is_empty() {
@@ -38,23 +92,106 @@ pub fn should_ignore_path(input: &str) -> bool {
         let path_part = path_part.to_lowercase();
         let is_filtered = PART_FILTERS.iter().any(|regex| regex.is_match(&path_part));
         if is_filtered {
-            return true;
+            return PathStatus::FilteredPathPart;
         }
     }
 
-    false
+    PathStatus::Allowed
+}
+
+pub fn is_archive_path(input: &str) -> bool {
+    let input = input.to_lowercase();
+    ARCHIVE_EXTS.iter().any(|ext| input.ends_with(ext))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::get_test_config;
 
     #[test]
     fn test_should_ignore_path() {
-        assert_eq!(should_ignore_path("torrent/samples/video.mp4"), true);
+        let filters = PathFilters::default();
+        assert_eq!(should_ignore_path("torrent/samples/video.mp4", filters), true);
         assert_eq!(
-            should_ignore_path("trailer park boys/season 1/episode 1.mp4"),
+            should_ignore_path("trailer park boys/season 1/episode 1.mp4", filters),
             false
         );
     }
+
+    #[test]
+    fn test_get_path_status() {
+        let filters = PathFilters::default();
+        assert_eq!(
+            get_path_status("torrent/samples/video.mp4", filters),
+            PathStatus::FilteredPathPart
+        );
+        assert_eq!(
+            get_path_status("torrent/video.zip", filters),
+            PathStatus::DisallowedExtension
+        );
+        assert_eq!(
+            get_path_status("torrent/setup.exe", filters),
+            PathStatus::DisallowedExtension
+        );
+        assert_eq!(get_path_status("torrent/video.mkv", filters), PathStatus::Allowed);
+    }
+
+    #[test]
+    fn test_category_filters() {
+        let mut config = get_test_config(&[("category_extensions", "lidarr=mp3|.FLAC")]);
+        config.keep_extras_categories = vec!["radarr".to_string()];
+
+        let music = get_path_filters(&config, Some("lidarr"));
+        assert_eq!(get_path_status("album/01 - track.flac", music), PathStatus::Allowed);
+        assert_eq!(get_path_status("album/02 - track.MP3", music), PathStatus::Allowed);
+        assert_eq!(
+            get_path_status("album/video.mkv", music),
+            PathStatus::DisallowedExtension
+        );
+
+        let movies = get_path_filters(&config, Some("radarr"));
+        assert_eq!(
+            get_path_status("movie/featurettes/making of.mkv", movies),
+            PathStatus::Allowed
+        );
+        assert_eq!(
+            get_path_status("movie/track.flac", movies),
+            PathStatus::DisallowedExtension
+        );
+
+        // categories without overrides and torrents without a category use the defaults
+        for filters in [
+            get_path_filters(&config, Some("sonarr")),
+            get_path_filters(&config, None),
+        ] {
+            assert_eq!(filters, PathFilters::default());
+            assert_eq!(
+                get_path_status("show/extras/interview.mkv", filters),
+                PathStatus::FilteredPathPart
+            );
+        }
+    }
+
+    #[test]
+    fn test_audio_categories() {
+        let mut config = get_test_config(&[]);
+        config.audio_categories = vec!["lidarr".to_string()];
+
+        let files = ["album/01 - track.flac", "album/02 - track.MP3", "album/03 - track.opus"];
+        let music = get_path_filters(&config, Some("lidarr"));
+        assert!(files.iter().all(|file| !should_ignore_path(file, music)));
+        // video is still allowed alongside audio
+        assert!(!should_ignore_path("album/music video.mkv", music));
+
+        let shows = get_path_filters(&config, Some("sonarr"));
+        assert!(files.iter().all(|file| should_ignore_path(file, shows)));
+    }
+
+    #[test]
+    fn test_is_archive_path() {
+        assert!(is_archive_path("torrent/video.zip"));
+        assert!(is_archive_path("torrent/VIDEO.RAR"));
+        assert!(!is_archive_path("torrent/setup.exe"));
+    }
 }
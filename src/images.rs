@@ -0,0 +1,166 @@
+use crate::AppState;
+use crate::config::get_config;
+use crate::error::AppError;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use reqwest::StatusCode;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024; // 5MB
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+const IMAGE_FORMATS: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Webp];
+
+impl ImageFormat {
+    /// Detects the format from the file signature instead of trusting the client's content type.
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+            Some(ImageFormat::Png)
+        } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else {
+            None
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Images are keyed by the torrent's info hash, which is also what keeps arbitrary paths out of the images dir.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn get_image_path(hash: &str, format: ImageFormat) -> PathBuf {
+    get_config()
+        .data_dir
+        .join("images")
+        .join(format!("{}.{}", hash.to_lowercase(), format.extension()))
+}
+
+async fn get_image(Path(hash): Path<String>) -> Result<Response, AppError> {
+    if !is_valid_hash(&hash) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid hash").into_response());
+    }
+
+    for format in IMAGE_FORMATS {
+        let path = get_image_path(&hash, format);
+        match tokio::fs::read(&path).await {
+            Ok(data) => {
+                return Ok((
+                    [
+                        (header::CONTENT_TYPE, format.content_type()),
+                        (header::CACHE_CONTROL, "public, max-age=86400"),
+                    ],
+                    data,
+                )
+                    .into_response());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok((StatusCode::NOT_FOUND, "Image not found").into_response())
+}
+
+async fn put_image(Path(hash): Path<String>, body: Bytes) -> Result<Response, AppError> {
+    if !is_valid_hash(&hash) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid hash").into_response());
+    }
+
+    let Some(format) = ImageFormat::detect(&body) else {
+        return Ok((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Images must be jpg, png or webp").into_response());
+    };
+
+    remove_images(&hash).await?;
+    tokio::fs::write(get_image_path(&hash, format), &body).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+async fn delete_image(Path(hash): Path<String>) -> Result<Response, AppError> {
+    if !is_valid_hash(&hash) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid hash").into_response());
+    }
+
+    remove_images(&hash).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Removes the image for the hash in every format, so replacing a png with a jpg doesn't leave the png behind.
+async fn remove_images(hash: &str) -> Result<(), std::io::Error> {
+    for format in IMAGE_FORMATS {
+        match tokio::fs::remove_file(get_image_path(hash, format)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+pub fn images_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/api/images/{hash}",
+            get(get_image).put(put_image).post(put_image).delete(delete_image),
+        )
+        .layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_image_format() {
+        assert_eq!(
+            ImageFormat::detect(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::detect(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0x00]),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::detect(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(ImageFormat::detect(b"GIF89a"), None);
+        assert_eq!(ImageFormat::detect(b""), None);
+    }
+
+    #[test]
+    fn test_is_valid_hash() {
+        assert!(is_valid_hash("1234567890abcdef1234567890ABCDEF12345678"));
+        assert!(!is_valid_hash("../../etc/passwd"));
+        assert!(!is_valid_hash("1234"));
+    }
+}
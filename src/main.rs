@@ -1,12 +1,20 @@
+use crate::activity::Activity;
 use crate::mount::LuminFS;
-use axum::Router;
+use axum::{
+    Router,
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::Response,
+};
 use cache::Cache;
 use config::get_config;
-use debrid::Debrid;
+use debrid::{DebridProvider, get_debrid_provider};
 use fuse3::raw::prelude::*;
 use fuse3::{MountOptions, raw::MountHandle};
+use images::images_router;
 use qbittorrent::mimic_qbittorrent;
-use reconciler::start_reconciler;
+use reconciler::{ReconcilerHealth, start_reconciler};
+use rpc::rpc_router;
 use sqlx::{
     SqlitePool,
     sqlite::{SqliteAutoVacuum, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
@@ -18,22 +26,32 @@ use std::{
     time::{Duration, Instant},
 };
 use tokio::{net::TcpListener, signal, sync::Notify, task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
+mod activity;
 mod cache;
 mod config;
 mod debrid;
 mod error;
+mod events;
 mod helpers;
+mod images;
 mod mount;
 mod qbittorrent;
 mod reconciler;
+mod rpc;
 mod state;
+#[cfg(test)]
+mod test_helpers;
 
 pub struct AppState {
     pub pool: SqlitePool,
-    pub debrid: Arc<Debrid>,
+    pub debrid: Arc<dyn DebridProvider>,
     pub notifier: Arc<Notify>,
+    pub cache: Arc<Cache>,
+    pub activity: Arc<Activity>,
+    pub reconciler_health: Arc<ReconcilerHealth>,
 }
 
 #[tokio::main]
@@ -70,23 +88,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .expect("Failed to run migrations");
 
-    let debrid = Arc::new(Debrid::new());
-    let notifier = Arc::new(Notify::new());
+    mount::create_root_dirs(&pool, &config.root_dirs)
+        .await
+        .expect("Failed to create root directories");
+
     let config = get_config();
+    let debrid = get_debrid_provider(config, &pool);
+    let notifier = Arc::new(Notify::new());
+    let activity = Arc::new(Activity::new());
+    let reconciler_health = Arc::new(ReconcilerHealth::default());
+    let shutdown = CancellationToken::new();
 
     let reconciler_handle = tokio::spawn({
+        let shutdown = shutdown.clone();
         let pool = pool.clone();
         let debrid = debrid.clone();
         let notifier = notifier.clone();
+        let activity = activity.clone();
+        let reconciler_health = reconciler_health.clone();
 
         async move {
-            run_with_retry("reconciler", || {
+            run_with_retry("reconciler", &shutdown, || {
                 let pool = pool.clone();
                 let debrid = debrid.clone();
                 let notifier = notifier.clone();
+                let activity = activity.clone();
+                let reconciler_health = reconciler_health.clone();
 
                 async move {
-                    start_reconciler(&pool, debrid, notifier)
+                    start_reconciler(&pool, debrid, notifier, activity, reconciler_health)
                         .await
                         .map_err(|e| format!("Reconciler error: {}", e))
                 }
@@ -103,12 +133,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         let cache_handle = tokio::spawn({
             let cache = cache.clone();
+            let shutdown = shutdown.clone();
             async move {
-                run_with_retry("cache_sweeper", || {
+                run_with_retry("cache_sweeper", &shutdown, || {
                     let cache = cache.clone();
+                    let shutdown = shutdown.clone();
                     async move {
                         cache
-                            .start_sweeper()
+                            .start_sweeper(shutdown)
                             .await
                             .map_err(|e| format!("Cache sweeper error: {}", e))
                     }
@@ -138,7 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .gid(gid);
 
         let cache = cache.clone();
-        let fs = LuminFS::new(pool.clone(), cache);
+        let fs = LuminFS::new(pool.clone(), cache, activity.clone());
         let session = Session::new(mount_options);
         if config.mount_unprivileged {
             session.mount_with_unprivileged(fs, &config.mount_path).await.unwrap()
@@ -147,8 +179,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let state = Arc::new(AppState { pool, debrid, notifier });
-    let app = Router::new().merge(mimic_qbittorrent().with_state(state.clone()));
+    let state = Arc::new(AppState {
+        pool,
+        debrid,
+        notifier,
+        cache,
+        activity,
+        reconciler_health,
+    });
+    let app = Router::new()
+        .merge(mimic_qbittorrent().with_state(state.clone()))
+        .merge(rpc_router().with_state(state.clone()))
+        .merge(images_router().with_state(state.clone()))
+        .layer(middleware::from_fn_with_state(state.clone(), record_activity));
 
     let bind_host = env::var("LUMIN_HOST").unwrap_or("127.0.0.1".to_string());
     let bind_port = env::var("LUMIN_PORT").unwrap_or("8000".to_string());
@@ -156,14 +199,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(bind_addr).await.unwrap();
     info!("Listening on http://{}", listener.local_addr().unwrap());
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(mount_handle, reconciler_handle, cache_handle))
+        .with_graceful_shutdown(shutdown_signal(mount_handle, reconciler_handle, cache_handle, shutdown))
         .await
         .unwrap();
 
     Ok(())
 }
 
-async fn shutdown_signal(mount_handle: MountHandle, reconciler_handle: JoinHandle<()>, cache_handle: JoinHandle<()>) {
+async fn record_activity(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    state.activity.record();
+    next.run(request).await
+}
+
+async fn shutdown_signal(
+    mount_handle: MountHandle,
+    reconciler_handle: JoinHandle<()>,
+    mut cache_handle: JoinHandle<()>,
+    shutdown: CancellationToken,
+) {
     let ctrl_c = async {
         signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
     };
@@ -175,15 +228,23 @@ async fn shutdown_signal(mount_handle: MountHandle, reconciler_handle: JoinHandl
             .await;
     };
 
-    tokio::select! {
-        _ = reconciler_handle => {},
-        _ = cache_handle => {},
+    let cache_finished = tokio::select! {
+        _ = reconciler_handle => false,
+        _ = &mut cache_handle => true,
         _ = ctrl_c => {
             info!("Received Ctrl+C");
+            false
         },
         _ = terminate => {
             info!("Received termination signal");
+            false
         },
+    };
+
+    // let the cache sweeper finish the chunk it's working on and flush metadata before exiting
+    shutdown.cancel();
+    if !cache_finished {
+        let _ = cache_handle.await;
     }
 
     drop(mount_handle);
@@ -193,7 +254,8 @@ async fn shutdown_signal(mount_handle: MountHandle, reconciler_handle: JoinHandl
 /// Retries failed tasks up to 3 times with 5-minute delays
 /// Resets attempt counter if task runs for more than 60 seconds
 /// Kills the program if all retries are exhausted
-async fn run_with_retry<F, Fut>(task_name: &str, task_factory: F) -> Result<(), String>
+/// Stops waiting to retry once `shutdown` is cancelled, so a failed task doesn't hold up shutdown
+async fn run_with_retry<F, Fut>(task_name: &str, shutdown: &CancellationToken, task_factory: F) -> Result<(), String>
 where
     F: Fn() -> Fut,
     Fut: std::future::Future<Output = Result<(), String>>,
@@ -245,7 +307,13 @@ where
                     MAX_ATTEMPTS
                 );
 
-                sleep(RETRY_DELAY).await;
+                tokio::select! {
+                    _ = sleep(RETRY_DELAY) => {}
+                    _ = shutdown.cancelled() => {
+                        info!("Shutting down, not restarting {}", task_name);
+                        return Ok(());
+                    }
+                }
             }
         }
     }
@@ -1,9 +1,13 @@
-use crate::cache::{Cache, CacheFile};
-use crate::mount::node::{Node, TEST_NODE_ID, get_test_attr};
+use crate::activity::Activity;
+use crate::cache::{Cache, CacheFile, CacheReadError};
+use crate::config::get_config;
+use crate::mount::node::{Node, TEST_NODE_ID, get_blocks, get_test_attr};
+use crate::state::TorrentState;
 use fuse3::Result;
 use fuse3::raw::prelude::*;
 use futures_util::stream::{self, Iter};
 use sqlx::SqlitePool;
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
 use std::num::NonZeroU32;
 use std::sync::Arc;
@@ -12,17 +16,114 @@ use std::vec::IntoIter;
 use tracing::trace;
 
 mod node;
+mod queries;
+
+pub use queries::{create_root_dirs, list_dir};
 
 const TTL: Duration = Duration::from_secs(1); // 1 second TTL
 
 pub struct LuminFS {
     pool: SqlitePool,
     cache: Arc<Cache>,
+    activity: Arc<Activity>,
 }
 
 impl LuminFS {
-    pub fn new(pool: SqlitePool, cache: Arc<Cache>) -> Self {
-        Self { pool, cache }
+    pub fn new(pool: SqlitePool, cache: Arc<Cache>, activity: Arc<Activity>) -> Self {
+        Self { pool, cache, activity }
+    }
+
+    /// With `report_unready_as_empty`, files whose torrent can't be read yet report a size of 0
+    /// so scanners that stat before reading don't import a file that will fail to read.
+    async fn get_node_attr(&self, node: &Node) -> Result<FileAttr> {
+        let mut attr = node.get_attr();
+        if let Some(blocks) = self.get_cached_blocks(node.file_id) {
+            attr.blocks = blocks;
+        }
+
+        if !get_config().report_unready_as_empty || node.file_id.is_none() {
+            return Ok(attr);
+        }
+
+        let is_ready = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM nodes n
+            JOIN torrents t ON t.id = n.torrent_id
+            WHERE n.id = ? AND t.state = ? AND t.debrid_id IS NOT NULL",
+            node.id,
+            TorrentState::Ready
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("node readiness db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+        if is_ready == 0 {
+            attr.size = 0;
+        }
+
+        Ok(attr)
+    }
+
+    /// With `report_cached_blocks`, files report the bytes that are in the cache as their allocated blocks,
+    /// so `du` shows how much of each file has been downloaded. Files that were never read have no blocks.
+    fn get_cached_blocks(&self, file_id: Option<i64>) -> Option<u64> {
+        if !get_config().report_cached_blocks {
+            return None;
+        }
+
+        let cached_bytes = self
+            .cache
+            .get_entry(file_id?)
+            .map(|entry| entry.get_cached_bytes())
+            .unwrap_or(0);
+
+        Some(get_blocks(cached_bytes))
+    }
+
+    /// Returns the cache file for a node, None if it's a directory or its torrent isn't on the debrid service yet.
+    async fn get_cache_file(&self, node_id: i64) -> Result<Option<CacheFile>> {
+        // todo: this should be handled by the "upsert_entry" call, we should just give it a file id.
+        // doing it this way means for every read request we are scanning 3 tables
+        sqlx::query_as!(
+            CacheFile,
+            r#"SELECT tf.id AS "id!", tf.size AS "size!", tf.path AS "path!", tf.debrid_id AS "file_debrid_id!", t.debrid_id AS "torrent_debrid_id!",
+                tf.duration_hint_secs
+            FROM nodes
+            LEFT JOIN torrent_files tf ON tf.id = nodes.file_id
+            LEFT JOIN torrents t ON t.id = tf.torrent_id
+            WHERE nodes.id = ? AND tf.debrid_id IS NOT NULL AND t.debrid_id IS NOT NULL"#,
+            node_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("read db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })
+    }
+
+    async fn get_unready_children(&self, parent_id: i64) -> Result<HashSet<i64>> {
+        if !get_config().report_unready_as_empty {
+            return Ok(HashSet::new());
+        }
+
+        let unready = sqlx::query_scalar!(
+            "SELECT n.id FROM nodes n
+            JOIN torrents t ON t.id = n.torrent_id
+            WHERE n.parent_id = ? AND (t.state != ? OR t.debrid_id IS NULL)",
+            parent_id,
+            TorrentState::Ready
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("node readiness db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+        Ok(unready.into_iter().collect())
     }
 }
 
@@ -41,26 +142,9 @@ impl Filesystem for LuminFS {
     async fn lookup(&self, _req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("lookup(parent={}, name={:?})", parent, name);
 
-        let parent = parent as i64;
-        let name_str = name.to_string_lossy().to_string();
-        let node = sqlx::query_as!(
-            Node,
-            "SELECT id, parent_id, size, created_at, updated_at, file_id, name FROM nodes WHERE parent_id = ? AND name = ?",
-            parent,
-            name_str
-        )
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("lookup db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        let Some(node) = node else {
-            return Err(libc::ENOENT.into());
-        };
-
-        let attr = node.get_attr();
+        let name_str = name.to_string_lossy();
+        let node = queries::find_child(&self.pool, parent as i64, &name_str).await?;
+        let attr = self.get_node_attr(&node).await?;
         Ok(ReplyEntry {
             attr: attr,
             generation: 0,
@@ -70,46 +154,8 @@ impl Filesystem for LuminFS {
 
     async fn mkdir(&self, _req: Request, parent_id: u64, name: &OsStr, _mode: u32, _umask: u32) -> Result<ReplyEntry> {
         trace!("mkdir(parent={}, name={:?})", parent_id, name);
-        let parent_id = parent_id as i64;
-        let parent = sqlx::query!("SELECT id, file_id, readonly FROM nodes WHERE id = ?", parent_id)
-            .fetch_optional(&self.pool)
-            .await
-            .map_err(|e| {
-                tracing::error!("mkdir db error: {}", e);
-                fuse3::Errno::from(libc::EIO)
-            })?;
-
-        let Some(parent) = parent else {
-            return Err(libc::ENOENT.into());
-        };
-
-        if parent.file_id.is_some() {
-            // Cannot create a directory inside a file
-            return Err(libc::ENOTDIR.into());
-        }
-
-        if parent.readonly == 1 && parent.id != 1 {
-            // cannot create directories inside immutable nodes
-            // (except the root node, that would be a little silly)
-            return Err(libc::EPERM.into());
-        }
-
-        let name_str = name.to_string_lossy().to_string();
-        let node = sqlx::query_as!(
-            Node,
-            "INSERT INTO nodes (parent_id, name, readonly) VALUES (?, ?, ?) 
-            RETURNING id, parent_id, size, created_at, updated_at, file_id, name",
-            parent_id,
-            name_str,
-            0
-        )
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("mkdir db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
+        let name_str = name.to_string_lossy();
+        let node = queries::create_dir(&self.pool, parent_id as i64, &name_str).await?;
         let attr = node.get_attr();
         Ok(ReplyEntry {
             attr: attr,
@@ -156,6 +202,10 @@ impl Filesystem for LuminFS {
                 fuse3::Errno::from(libc::EIO)
             })?;
 
+        if get_config().cleanup_empty_dirs {
+            queries::remove_empty_dirs(&self.pool, parent_id).await?;
+        }
+
         Ok(())
     }
 
@@ -191,7 +241,7 @@ impl Filesystem for LuminFS {
         let new_node = sqlx::query_as!(
             Node,
             "INSERT INTO nodes (parent_id, name, size, file_id, torrent_id, readonly) VALUES (?, ?, ?, ?, ?, ?) 
-            RETURNING id, parent_id, size, created_at, updated_at, file_id, name",
+            RETURNING id, parent_id, size, created_at, updated_at, file_id, torrent_id, name",
             link_parent_id,
             link_name_str,
             original_node.size,
@@ -256,6 +306,10 @@ impl Filesystem for LuminFS {
                 fuse3::Errno::from(libc::EIO)
             })?;
 
+        if get_config().cleanup_empty_dirs {
+            queries::remove_empty_dirs(&self.pool, parent).await?;
+        }
+
         Ok(())
     }
 
@@ -272,27 +326,16 @@ impl Filesystem for LuminFS {
             parent_id, name, new_parent, new_name
         );
 
-        let parent_id = parent_id as i64;
-        let new_parent_id = new_parent as i64;
-        let name_str = name.to_string_lossy().to_string();
-        let new_name_str = new_name.to_string_lossy().to_string();
-        let result = sqlx::query!(
-            "UPDATE nodes SET parent_id = ?, name = ? WHERE parent_id = ? AND name = ?",
-            new_parent_id,
-            new_name_str,
-            parent_id,
-            name_str,
+        let name_str = name.to_string_lossy();
+        let new_name_str = new_name.to_string_lossy();
+        queries::rename_node(
+            &self.pool,
+            parent_id as i64,
+            &name_str,
+            new_parent as i64,
+            &new_name_str,
         )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("rename db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        if result.rows_affected() == 0 {
-            return Err(libc::ENOENT.into());
-        }
+        .await?;
 
         Ok(())
     }
@@ -358,7 +401,7 @@ impl Filesystem for LuminFS {
         let node_id = node_id as i64;
         let node = sqlx::query_as!(
             Node,
-            "SELECT id, parent_id, size, created_at, updated_at, file_id, name FROM nodes WHERE id = ?",
+            "SELECT id, parent_id, size, created_at, updated_at, file_id, torrent_id, name FROM nodes WHERE id = ?",
             node_id
         )
         .fetch_optional(&self.pool)
@@ -385,7 +428,7 @@ impl Filesystem for LuminFS {
         let node_id = node_id as i64;
         let node = sqlx::query_as!(
             Node,
-            "SELECT id, parent_id, size, created_at, updated_at, file_id, name FROM nodes WHERE id = ?",
+            "SELECT id, parent_id, size, created_at, updated_at, file_id, torrent_id, name FROM nodes WHERE id = ?",
             node_id
         )
         .fetch_optional(&self.pool)
@@ -399,7 +442,7 @@ impl Filesystem for LuminFS {
             return Err(libc::ENOENT.into());
         };
 
-        let attr = node.get_attr();
+        let attr = self.get_node_attr(&node).await?;
         Ok(ReplyAttr { attr: attr, ttl: TTL })
     }
 
@@ -437,6 +480,32 @@ impl Filesystem for LuminFS {
     where
         Self: 'a;
 
+    async fn readdir(
+        &self,
+        _req: Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
+        trace!("readdir(ino={}, offset={})", ino, offset);
+        let entries = queries::list_dir_entries(&self.pool, ino as i64, offset.max(0) as u64).await?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| {
+                Ok(DirectoryEntry {
+                    inode: entry.inode as u64,
+                    kind: entry.attr.kind,
+                    name: OsString::from(entry.name),
+                    offset: entry.offset,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
     async fn readdirplus(
         &self,
         _req: Request,
@@ -446,75 +515,54 @@ impl Filesystem for LuminFS {
         _lock_owner: u64,
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("readdirplus(ino={}, offset={})", ino, offset);
-        let ino = ino as i64;
-        let node = sqlx::query_as!(
-            Node,
-            "SELECT id, parent_id, size, created_at, updated_at, file_id, name FROM nodes WHERE id = ?",
-            ino
-        )
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("readdirplus db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        let Some(node) = node else {
-            return Err(libc::ENOENT.into());
-        };
-
-        if node.file_id.is_some() {
-            return Err(libc::ENOTDIR.into());
-        }
-
-        // todo: this should support offset/limit and maybe streaming
-        let children = sqlx::query_as!(
-            Node,
-            "SELECT id, parent_id, size, created_at, updated_at, file_id, name FROM nodes WHERE parent_id = ?",
-            ino
-        )
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("readdirplus db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        let parent_ino = node.parent_id.unwrap_or(1);
-        let mut entries = vec![
-            (node.id, FileType::Directory, ".", node.get_attr(), 1),
-            (parent_ino, FileType::Directory, "..", node.get_attr(), 2),
-        ];
+        let entries = queries::list_dir_entries(&self.pool, ino as i64, offset).await?;
+        let unready_children = self.get_unready_children(ino as i64).await?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| {
+                let mut attr = entry.attr;
+                if unready_children.contains(&entry.inode) {
+                    attr.size = 0;
+                }
 
-        for (offset, child) in children.iter().enumerate() {
-            let attr = child.get_attr();
-            entries.push((child.id, attr.kind, &child.name, attr, offset as u64 + 3));
-        }
+                if let Some(blocks) = self.get_cached_blocks(entry.file_id) {
+                    attr.blocks = blocks;
+                }
 
-        let children = entries
-            .into_iter()
-            .map(|(node_id, kind, name, attr, offset)| {
                 Ok(DirectoryEntryPlus {
-                    attr: attr,
+                    attr,
                     attr_ttl: TTL,
                     entry_ttl: TTL,
                     generation: 0,
-                    inode: node_id as u64,
-                    kind,
-                    name: OsString::from(name),
-                    offset: offset as i64,
+                    inode: entry.inode as u64,
+                    kind: attr.kind,
+                    name: OsString::from(entry.name),
+                    offset: entry.offset,
                 })
             })
-            .skip(offset as _)
             .collect::<Vec<_>>();
 
         Ok(ReplyDirectoryPlus {
-            entries: stream::iter(children),
+            entries: stream::iter(entries),
         })
     }
 
+    async fn open(&self, _req: Request, node_id: u64, flags: u32) -> Result<ReplyOpen> {
+        trace!("open(node_id={}, flags={})", node_id, flags);
+        if get_config().preload_on_open
+            && let Some(cache_file) = self.get_cache_file(node_id as i64).await?
+        {
+            // players open the file then probe it, starting the preload now overlaps the
+            // download with that instead of waiting for the first read.
+            self.cache.upsert_entry(cache_file).queue_preload_chunks();
+        }
+
+        Ok(ReplyOpen { fh: 0, flags: 0 })
+    }
+
     async fn read(&self, _req: Request, node_id: u64, _fh: u64, offset: u64, size: u32) -> Result<ReplyData> {
         trace!("read(node_id={}, offset={}, size={})", node_id, offset, size);
+        self.activity.record();
         // let (node, file, torrent) = nodes::Entity::find_active(false)
         //     .filter(nodes::Column::Id.eq(node_id))
         //     .find_also_related(torrent_files::Entity)
@@ -531,39 +579,30 @@ impl Filesystem for LuminFS {
         //     return Err(libc::EISDIR.into());
         // };
 
-        // todo: this should be handled by the "upsert_entry" call, we should just give it a file id.
-        // doing it this way means for every read request we are scanning 3 tables
         let node_id = node_id as i64;
-        let cache_file = sqlx::query_as!(
-            CacheFile,
-            r#"SELECT tf.id AS "id!", tf.size AS "size!", tf.path AS "path!", tf.debrid_id AS "file_debrid_id!", t.debrid_id AS "torrent_debrid_id!"
-            FROM nodes
-            LEFT JOIN torrent_files tf ON tf.id = nodes.file_id
-            LEFT JOIN torrents t ON t.id = tf.torrent_id
-            WHERE nodes.id = ? AND tf.debrid_id IS NOT NULL AND t.debrid_id IS NOT NULL"#,
-            node_id
-        )
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("read db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        // todo: missing debrid_id will cause ENOENT but it should cause EIO
+        let cache_file = self.get_cache_file(node_id).await?;
         let Some(cache_file) = cache_file else {
-            return Err(libc::ENOENT.into());
+            // the node might exist but its torrent hasn't been created on the debrid service yet.
+            // empty placeholder files don't need the debrid service to be read.
+            let file_size = queries::get_file_size(&self.pool, node_id).await?;
+            if file_size == 0 {
+                return Ok(ReplyData {
+                    data: Vec::new().into(),
+                });
+            }
+
+            return Err(read_error_to_errno(CacheReadError::NotReady));
         };
 
-        if offset + size as u64 > cache_file.size as u64 {
-            return Err(libc::EINVAL.into());
-        }
+        let Some(size) = get_read_size(offset, size as u64, cache_file.size as u64) else {
+            // reads at or past the end of the file (including any read of an empty file) are EOF
+            return Ok(ReplyData {
+                data: Vec::new().into(),
+            });
+        };
 
         let file = self.cache.upsert_entry(cache_file);
-        let data = file.read_bytes(offset, size as u64).await.map_err(|e| {
-            tracing::error!("cache read error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
+        let data = file.read_bytes(offset, size).await.map_err(read_error_to_errno)?;
 
         Ok(ReplyData { data: data.into() })
     }
@@ -572,18 +611,7 @@ impl Filesystem for LuminFS {
     // todo: this is kinda gross, it would be better to.. do something else. maybe just use fake data?
     async fn statfs(&self, _req: Request, _node_id: u64) -> Result<ReplyStatFs> {
         trace!("statfs(node_id={})", _node_id);
-        let result = sqlx::query!(
-            "SELECT COUNT(*) AS file_count, COALESCE(SUM(size), 0) AS total_size FROM nodes WHERE size IS NOT NULL"
-        )
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("statfs db error: {}", e);
-            fuse3::Errno::from(libc::EIO)
-        })?;
-
-        let file_count = result.file_count as u64;
-        let total_size = result.total_size as u64;
+        let (file_count, total_size) = queries::get_fs_stats(&self.pool).await?;
 
         const BLOCK_SIZE: u64 = 4096;
         let total_blocks = (total_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
@@ -603,3 +631,39 @@ impl Filesystem for LuminFS {
         })
     }
 }
+
+/// Reads that run past the end of the file are shortened, None means there is nothing to read.
+fn get_read_size(offset: u64, size: u64, file_size: u64) -> Option<u64> {
+    if size == 0 || offset >= file_size {
+        return None;
+    }
+
+    Some(size.min(file_size - offset))
+}
+
+fn read_error_to_errno(error: CacheReadError) -> fuse3::Errno {
+    tracing::error!("cache read error: {}", error);
+    let errno = match error {
+        CacheReadError::Timeout(_) => libc::ETIMEDOUT,
+        CacheReadError::DownloadFailed(_) | CacheReadError::Io(_) => libc::EIO,
+        CacheReadError::OutOfRange { .. } | CacheReadError::ChunkOutOfRange { .. } => libc::EINVAL,
+        CacheReadError::NotReady => libc::EAGAIN,
+    };
+
+    fuse3::Errno::from(errno)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_read_size() {
+        assert_eq!(get_read_size(0, 4096, 10_000), Some(4096));
+        assert_eq!(get_read_size(8192, 4096, 10_000), Some(1808));
+        assert_eq!(get_read_size(10_000, 4096, 10_000), None);
+        assert_eq!(get_read_size(0, 0, 10_000), None);
+        // empty files never reach the cache
+        assert_eq!(get_read_size(0, 4096, 0), None);
+    }
+}
@@ -6,6 +6,8 @@ const DEFAULT_UID: u32 = 1000;
 const DEFAULT_GID: u32 = 1000;
 const DEFAULT_FILE_PERMS: u16 = 0x777;
 const DEFAULT_DIR_PERMS: u16 = 0o777;
+// st_blocks is always counted in 512 byte units, regardless of blksize
+const BLOCK_SIZE: u64 = 512;
 
 #[derive(sqlx::FromRow)]
 pub struct Node {
@@ -14,6 +16,7 @@ pub struct Node {
     pub size: i64,
     pub name: String,
     pub file_id: Option<i64>,
+    pub torrent_id: Option<i64>,
     pub updated_at: i64,
     pub created_at: i64,
 }
@@ -57,6 +60,11 @@ impl Node {
     }
 }
 
+/// The number of blocks allocated for the given bytes.
+pub fn get_blocks(bytes: u64) -> u64 {
+    bytes.div_ceil(BLOCK_SIZE)
+}
+
 pub fn get_test_attr() -> FileAttr {
     let now = std::time::SystemTime::now();
     FileAttr {
@@ -75,3 +83,35 @@ pub fn get_test_attr() -> FileAttr {
         uid: 0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_attr_times_are_seconds() {
+        let node = Node {
+            id: 3,
+            parent_id: Some(2),
+            size: 100,
+            name: "file.mkv".to_string(),
+            file_id: Some(1),
+            torrent_id: Some(1),
+            updated_at: 1_745_000_000,
+            created_at: 1_744_000_000,
+        };
+
+        let attr = node.get_attr();
+        assert_eq!(attr.ctime.sec, 1_744_000_000);
+        assert_eq!(attr.mtime.sec, 1_745_000_000);
+        assert_eq!(attr.atime.sec, 1_745_000_000);
+    }
+
+    #[test]
+    fn test_get_blocks() {
+        assert_eq!(get_blocks(0), 0);
+        assert_eq!(get_blocks(1), 1);
+        assert_eq!(get_blocks(512), 1);
+        assert_eq!(get_blocks(8 * 1024 * 1024 + 1), 16385);
+    }
+}
@@ -0,0 +1,579 @@
+//! Database logic behind the filesystem handlers, kept separate from `Filesystem` so it can be
+//! tested against an in-memory database without a mount.
+use crate::mount::node::Node;
+use fuse3::Result;
+use fuse3::raw::prelude::FileAttr;
+use sqlx::SqlitePool;
+
+/// An entry in a directory listing. `offset` is the offset of the next entry, which is what
+/// the kernel passes back to continue the listing.
+pub struct DirEntry {
+    pub inode: i64,
+    pub file_id: Option<i64>,
+    pub name: String,
+    pub attr: FileAttr,
+    pub offset: i64,
+}
+
+pub async fn find_child(pool: &SqlitePool, parent_id: i64, name: &str) -> Result<Node> {
+    let node = sqlx::query_as!(
+        Node,
+        "SELECT id, parent_id, size, created_at, updated_at, file_id, torrent_id, name FROM nodes
+        WHERE parent_id = ? AND name = ? AND (file_id IS NULL OR file_id NOT IN (SELECT id FROM torrent_files WHERE priority = 0))",
+        parent_id,
+        name
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("lookup db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    node.ok_or_else(|| libc::ENOENT.into())
+}
+
+pub async fn create_dir(pool: &SqlitePool, parent_id: i64, name: &str) -> Result<Node> {
+    let parent = sqlx::query!("SELECT id, file_id, readonly FROM nodes WHERE id = ?", parent_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("mkdir db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+    let Some(parent) = parent else {
+        return Err(libc::ENOENT.into());
+    };
+
+    if parent.file_id.is_some() {
+        // Cannot create a directory inside a file
+        return Err(libc::ENOTDIR.into());
+    }
+
+    if parent.readonly == 1 && parent.id != 1 {
+        // cannot create directories inside immutable nodes
+        // (except the root node, that would be a little silly)
+        return Err(libc::EPERM.into());
+    }
+
+    sqlx::query_as!(
+        Node,
+        "INSERT INTO nodes (parent_id, name, readonly) VALUES (?, ?, ?)
+        RETURNING id, parent_id, size, created_at, updated_at, file_id, torrent_id, name",
+        parent_id,
+        name,
+        0
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("mkdir db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })
+}
+
+pub async fn rename_node(
+    pool: &SqlitePool,
+    parent_id: i64,
+    name: &str,
+    new_parent_id: i64,
+    new_name: &str,
+) -> Result<()> {
+    let node = sqlx::query!(
+        "SELECT id, readonly FROM nodes WHERE parent_id = ? AND name = ?",
+        parent_id,
+        name
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("rename db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    let Some(node) = node else {
+        return Err(libc::ENOENT.into());
+    };
+
+    if node.readonly == 1 || node.id == 1 || node.id == 2 {
+        // the reconciler owns the downloads tree and expects it to stay where it put it
+        return Err(libc::EPERM.into());
+    }
+
+    let new_parent = sqlx::query!("SELECT id, file_id, readonly FROM nodes WHERE id = ?", new_parent_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("rename db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+    let Some(new_parent) = new_parent else {
+        return Err(libc::ENOENT.into());
+    };
+
+    if new_parent.file_id.is_some() {
+        return Err(libc::ENOTDIR.into());
+    }
+
+    if new_parent.readonly == 1 && new_parent.id != 1 {
+        // same as mkdir, nothing can be moved into the immutable nodes
+        return Err(libc::EPERM.into());
+    }
+
+    sqlx::query!(
+        "UPDATE nodes SET parent_id = ?, name = ? WHERE id = ?",
+        new_parent_id,
+        new_name,
+        node.id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("rename db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    Ok(())
+}
+
+/// Returns the directory node and its visible children.
+pub async fn list_dir(pool: &SqlitePool, node_id: i64) -> Result<(Node, Vec<Node>)> {
+    let node = sqlx::query_as!(
+        Node,
+        "SELECT id, parent_id, size, created_at, updated_at, file_id, torrent_id, name FROM nodes WHERE id = ?",
+        node_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("readdirplus db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    let Some(node) = node else {
+        return Err(libc::ENOENT.into());
+    };
+
+    if node.file_id.is_some() {
+        return Err(libc::ENOTDIR.into());
+    }
+
+    // todo: this should support offset/limit and maybe streaming
+    // ordered so offsets into the listing stay stable between calls
+    let children = sqlx::query_as!(
+        Node,
+        "SELECT id, parent_id, size, created_at, updated_at, file_id, torrent_id, name FROM nodes
+        WHERE parent_id = ? AND (file_id IS NULL OR file_id NOT IN (SELECT id FROM torrent_files WHERE priority = 0))
+        ORDER BY name",
+        node_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("readdirplus db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    Ok((node, children))
+}
+
+/// Creates the configured top level directories under the root, existing directories are left alone.
+/// These are normal directories so they can be used like any other folder, running this on
+/// startup recreates any that were removed.
+pub async fn create_root_dirs(pool: &SqlitePool, names: &[String]) -> std::result::Result<(), sqlx::Error> {
+    for name in names {
+        sqlx::query!(
+            "INSERT INTO nodes (parent_id, name, readonly) VALUES (1, ?, 0) ON CONFLICT (parent_id, name) DO NOTHING",
+            name
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Removes `node_id` and its ancestors for as long as they are empty, like rclone's `--rmdirs`.
+/// Stops at readonly directories and at top level directories, so the root, downloads and the
+/// configured root directories are never removed.
+pub async fn remove_empty_dirs(pool: &SqlitePool, node_id: i64) -> Result<()> {
+    let mut node_id = node_id;
+    loop {
+        let node = sqlx::query!(
+            r#"SELECT parent_id, readonly, file_id, (SELECT COUNT(*) FROM nodes c WHERE c.parent_id = nodes.id) as "child_count!: i64"
+            FROM nodes WHERE id = ?"#,
+            node_id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("cleanup db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+        let Some(node) = node else {
+            return Ok(());
+        };
+
+        let Some(parent_id) = node.parent_id else {
+            return Ok(());
+        };
+
+        if parent_id == 1 || node.readonly == 1 || node.file_id.is_some() || node.child_count > 0 {
+            return Ok(());
+        }
+
+        sqlx::query!("DELETE FROM nodes WHERE id = ?", node_id)
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("cleanup db error: {}", e);
+                fuse3::Errno::from(libc::EIO)
+            })?;
+
+        node_id = parent_id;
+    }
+}
+
+/// Returns the size of a file node, or ENOENT if the node doesn't exist or is a directory.
+pub async fn get_file_size(pool: &SqlitePool, node_id: i64) -> Result<i64> {
+    let size = sqlx::query_scalar!("SELECT size FROM nodes WHERE id = ? AND file_id IS NOT NULL", node_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("read db error: {}", e);
+            fuse3::Errno::from(libc::EIO)
+        })?;
+
+    size.ok_or_else(|| libc::ENOENT.into())
+}
+
+/// Returns the number of files and their total size. Hardlinks share a `file_id`, so each file
+/// is only counted once no matter how many directories it's linked into.
+pub async fn get_fs_stats(pool: &SqlitePool) -> Result<(u64, u64)> {
+    let result = sqlx::query!(
+        r#"SELECT COUNT(*) AS "file_count!: i64", COALESCE(SUM(size), 0) AS "total_size!: i64"
+        FROM (SELECT MAX(size) AS size FROM nodes WHERE file_id IS NOT NULL GROUP BY file_id)"#
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("statfs db error: {}", e);
+        fuse3::Errno::from(libc::EIO)
+    })?;
+
+    Ok((result.file_count as u64, result.total_size as u64))
+}
+
+/// Returns the entries of a directory, including `.` and `..`, starting at `offset`.
+pub async fn list_dir_entries(pool: &SqlitePool, node_id: i64, offset: u64) -> Result<Vec<DirEntry>> {
+    let (node, children) = list_dir(pool, node_id).await?;
+    let parent_id = node.parent_id.unwrap_or(1);
+    let mut entries = vec![
+        DirEntry {
+            inode: node.id,
+            file_id: node.file_id,
+            name: ".".to_string(),
+            attr: node.get_attr(),
+            offset: 1,
+        },
+        DirEntry {
+            inode: parent_id,
+            file_id: None,
+            name: "..".to_string(),
+            attr: node.get_attr(),
+            offset: 2,
+        },
+    ];
+
+    for (offset, child) in children.into_iter().enumerate() {
+        entries.push(DirEntry {
+            inode: child.id,
+            file_id: child.file_id,
+            attr: child.get_attr(),
+            name: child.name,
+            offset: offset as i64 + 3,
+        });
+    }
+
+    Ok(entries.into_iter().skip(offset as usize).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::get_test_pool;
+
+    const ROOT_ID: i64 = 1;
+    const DOWNLOADS_ID: i64 = 2;
+
+    async fn insert_file(pool: &SqlitePool, parent_id: i64, name: &str) -> i64 {
+        let hash = name.as_bytes();
+        let torrent_id = sqlx::query_scalar!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, ?, 0, '') RETURNING id",
+            hash,
+            name
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        let file_id = sqlx::query_scalar!(
+            "INSERT INTO torrent_files (debrid_id, torrent_id, path, size) VALUES (0, ?, ?, 100) RETURNING id",
+            torrent_id,
+            name
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        sqlx::query_scalar!(
+            "INSERT INTO nodes (parent_id, name, size, readonly, torrent_id, file_id) VALUES (?, ?, 100, 1, ?, ?) RETURNING id",
+            parent_id,
+            name,
+            torrent_id,
+            file_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let pool = get_test_pool(true).await;
+        let downloads = find_child(&pool, ROOT_ID, "downloads").await.unwrap();
+        assert_eq!(downloads.id, DOWNLOADS_ID);
+        assert!(downloads.file_id.is_none());
+
+        let file_node_id = insert_file(&pool, DOWNLOADS_ID, "movie.mkv").await;
+        let file = find_child(&pool, DOWNLOADS_ID, "movie.mkv").await.unwrap();
+        assert_eq!(file.id, file_node_id);
+        assert_eq!(file.get_attr().size, 100);
+
+        let missing = find_child(&pool, ROOT_ID, "missing").await;
+        assert_eq!(missing.err(), Some(libc::ENOENT.into()));
+    }
+
+    #[tokio::test]
+    async fn test_mkdir() {
+        let pool = get_test_pool(true).await;
+        let movies = create_dir(&pool, ROOT_ID, "movies").await.unwrap();
+        assert_eq!(movies.parent_id, Some(ROOT_ID));
+        create_dir(&pool, movies.id, "nested").await.unwrap();
+
+        let file_node_id = insert_file(&pool, DOWNLOADS_ID, "movie.mkv").await;
+        assert_eq!(
+            create_dir(&pool, DOWNLOADS_ID, "nope").await.err(),
+            Some(libc::EPERM.into())
+        );
+        assert_eq!(
+            create_dir(&pool, file_node_id, "nope").await.err(),
+            Some(libc::ENOTDIR.into())
+        );
+        assert_eq!(create_dir(&pool, 9999, "nope").await.err(), Some(libc::ENOENT.into()));
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let pool = get_test_pool(true).await;
+        let movies = create_dir(&pool, ROOT_ID, "movies").await.unwrap();
+        let shows = create_dir(&pool, ROOT_ID, "shows").await.unwrap();
+        create_dir(&pool, movies.id, "Some Show").await.unwrap();
+
+        rename_node(&pool, movies.id, "Some Show", shows.id, "Some Show (2020)")
+            .await
+            .unwrap();
+
+        assert!(find_child(&pool, movies.id, "Some Show").await.is_err());
+        assert!(find_child(&pool, shows.id, "Some Show (2020)").await.is_ok());
+        assert_eq!(
+            rename_node(&pool, movies.id, "missing", shows.id, "missing")
+                .await
+                .err(),
+            Some(libc::ENOENT.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_readonly() {
+        let pool = get_test_pool(true).await;
+        let movies = create_dir(&pool, ROOT_ID, "movies").await.unwrap();
+        insert_file(&pool, DOWNLOADS_ID, "movie.mkv").await;
+
+        assert_eq!(
+            rename_node(&pool, DOWNLOADS_ID, "movie.mkv", movies.id, "movie.mkv")
+                .await
+                .err(),
+            Some(libc::EPERM.into())
+        );
+        assert_eq!(
+            rename_node(&pool, ROOT_ID, "downloads", ROOT_ID, "renamed").await.err(),
+            Some(libc::EPERM.into())
+        );
+        assert_eq!(
+            rename_node(&pool, ROOT_ID, "movies", DOWNLOADS_ID, "movies")
+                .await
+                .err(),
+            Some(libc::EPERM.into())
+        );
+
+        assert!(find_child(&pool, DOWNLOADS_ID, "movie.mkv").await.is_ok());
+        assert!(find_child(&pool, ROOT_ID, "downloads").await.is_ok());
+        assert!(find_child(&pool, ROOT_ID, "movies").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_readdirplus() {
+        let pool = get_test_pool(true).await;
+        insert_file(&pool, DOWNLOADS_ID, "a.mkv").await;
+        let hidden_node_id = insert_file(&pool, DOWNLOADS_ID, "b.mkv").await;
+        sqlx::query!(
+            "UPDATE torrent_files SET priority = 0 WHERE id = (SELECT file_id FROM nodes WHERE id = ?)",
+            hidden_node_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (node, children) = list_dir(&pool, DOWNLOADS_ID).await.unwrap();
+        assert_eq!(node.id, DOWNLOADS_ID);
+        let names = children.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["a.mkv"]);
+
+        let file_node_id = children[0].id;
+        assert_eq!(list_dir(&pool, file_node_id).await.err(), Some(libc::ENOTDIR.into()));
+        assert_eq!(list_dir(&pool, 9999).await.err(), Some(libc::ENOENT.into()));
+    }
+
+    #[tokio::test]
+    async fn test_list_dir_entries() {
+        let pool = get_test_pool(true).await;
+        let first_id = insert_file(&pool, DOWNLOADS_ID, "a.mkv").await;
+        let second_id = insert_file(&pool, DOWNLOADS_ID, "b.mkv").await;
+
+        let entries = list_dir_entries(&pool, DOWNLOADS_ID, 0).await.unwrap();
+        let listed = entries
+            .iter()
+            .map(|e| (e.inode, e.name.as_str(), e.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            vec![
+                (DOWNLOADS_ID, ".", 1),
+                (ROOT_ID, "..", 2),
+                (first_id, "a.mkv", 3),
+                (second_id, "b.mkv", 4)
+            ]
+        );
+
+        // continuing from the offset of an entry returns the entries after it
+        let entries = list_dir_entries(&pool, DOWNLOADS_ID, 3).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "b.mkv");
+        assert_eq!(entries[0].attr.kind, fuse3::FileType::RegularFile);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_size() {
+        let pool = get_test_pool(true).await;
+        let node_id = insert_file(&pool, DOWNLOADS_ID, "a.mkv").await;
+        assert_eq!(get_file_size(&pool, node_id).await.unwrap(), 100);
+
+        sqlx::query!("UPDATE nodes SET size = 0 WHERE id = ?", node_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(get_file_size(&pool, node_id).await.unwrap(), 0);
+
+        assert_eq!(
+            get_file_size(&pool, DOWNLOADS_ID).await.err(),
+            Some(libc::ENOENT.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_root_dirs() {
+        let pool = get_test_pool(true).await;
+        let existing = create_dir(&pool, ROOT_ID, "movies").await.unwrap();
+        let names = vec!["movies".to_string(), "tv".to_string()];
+        create_root_dirs(&pool, &names).await.unwrap();
+        // running again on the next startup must not fail or duplicate anything
+        create_root_dirs(&pool, &names).await.unwrap();
+
+        let (_, children) = list_dir(&pool, ROOT_ID).await.unwrap();
+        let mut names = children.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["downloads", "movies", "tv"]);
+        assert_eq!(find_child(&pool, ROOT_ID, "movies").await.unwrap().id, existing.id);
+
+        // the roots can be used like any other folder
+        let tv = find_child(&pool, ROOT_ID, "tv").await.unwrap();
+        create_dir(&pool, tv.id, "Some Show").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_empty_dirs() {
+        let pool = get_test_pool(true).await;
+        let movies = create_dir(&pool, ROOT_ID, "movies").await.unwrap();
+        let movie = create_dir(&pool, movies.id, "Movie (2020)").await.unwrap();
+        let extras = create_dir(&pool, movie.id, "Extras").await.unwrap();
+        let kept = create_dir(&pool, movies.id, "Other (2021)").await.unwrap();
+        let download_id = insert_file(&pool, DOWNLOADS_ID, "movie.mkv").await;
+        let link = sqlx::query!(
+            "INSERT INTO nodes (parent_id, name, size, readonly, torrent_id, file_id)
+            SELECT ?, name, size, 0, torrent_id, file_id FROM nodes WHERE id = ? RETURNING id",
+            extras.id,
+            download_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        // nothing is removed while the file is still there
+        remove_empty_dirs(&pool, extras.id).await.unwrap();
+        assert!(find_child(&pool, movie.id, "Extras").await.is_ok());
+
+        sqlx::query!("DELETE FROM nodes WHERE id = ?", link.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        remove_empty_dirs(&pool, extras.id).await.unwrap();
+
+        let (_, children) = list_dir(&pool, movies.id).await.unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].id, kept.id);
+
+        // top level and readonly directories are left alone
+        remove_empty_dirs(&pool, kept.id).await.unwrap();
+        assert!(find_child(&pool, ROOT_ID, "movies").await.is_ok());
+        remove_empty_dirs(&pool, DOWNLOADS_ID).await.unwrap();
+        assert!(find_child(&pool, ROOT_ID, "downloads").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_fs_stats() {
+        let pool = get_test_pool(true).await;
+        assert_eq!(get_fs_stats(&pool).await.unwrap(), (0, 0));
+
+        let download_id = insert_file(&pool, DOWNLOADS_ID, "movie.mkv").await;
+        insert_file(&pool, DOWNLOADS_ID, "other.mkv").await;
+        for name in ["movies", "backup"] {
+            let dir = create_dir(&pool, ROOT_ID, name).await.unwrap();
+            sqlx::query!(
+                "INSERT INTO nodes (parent_id, name, size, readonly, torrent_id, file_id)
+                SELECT ?, name, size, 0, torrent_id, file_id FROM nodes WHERE id = ?",
+                dir.id,
+                download_id
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // the hardlinks of movie.mkv don't count towards the total
+        assert_eq!(get_fs_stats(&pool).await.unwrap(), (2, 200));
+    }
+}
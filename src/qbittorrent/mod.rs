@@ -1,10 +1,12 @@
 use crate::AppState;
+use crate::config::Config;
 use crate::config::get_config;
 use crate::error::AppError;
-use crate::helpers::add_trackers_to_magnet_uri::add_trackers_to_magnet_uri;
+use crate::events::{EventKind, record_event};
+use crate::helpers::add_trackers_to_magnet_uri::{add_trackers_to_magnet_uri, strip_trackers_from_magnet_uri};
 use crate::helpers::parse_magnet_uri::parse_magnet_uri;
 use crate::qbittorrent::torrent::Torrent;
-use crate::state::TorrentState;
+use crate::state::{TorrentSource, TorrentState};
 use axum::extract::{FromRequest, Multipart, Query, Request, State};
 use axum::http::Method;
 use axum::http::Uri;
@@ -21,12 +23,13 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::warn;
 
-mod torrent;
+pub mod torrent;
 
 #[derive(FromRow)]
 pub struct TorrentFile {
     pub path: String,
     pub size: i64,
+    pub priority: i64,
 }
 
 pub async fn auth_login() -> impl IntoResponse {
@@ -34,11 +37,11 @@ pub async fn auth_login() -> impl IntoResponse {
 }
 
 async fn app_version() -> &'static str {
-    "v4.3.2"
+    &get_config().qbittorrent_version
 }
 
 async fn app_webapi_version() -> &'static str {
-    "2.7"
+    &get_config().qbittorrent_webapi_version
 }
 
 async fn app_buildinfo() -> impl IntoResponse {
@@ -60,15 +63,42 @@ async fn app_preferences() -> impl IntoResponse {
     let config = get_config();
     let save_path = config.mount_path.join("downloads").to_string_lossy().into_owned();
 
+    // sonarr and radarr read the seeding limits and queueing settings on connect to warn about
+    // misconfiguration, the rest are the fields other clients commonly check exist.
     Json(json!({
         "save_path": save_path,
+        "temp_path_enabled": false,
+        "temp_path": "",
         "max_active_downloads": 5,
         "max_active_torrents": 10,
         "max_active_uploads": 5,
+        "queueing_enabled": false,
+        "max_ratio_enabled": false,
+        "max_ratio": -1,
+        "max_ratio_act": 0,
+        "max_seeding_time_enabled": false,
+        "max_seeding_time": -1,
+        "auto_tmm_enabled": false,
+        "preallocate_all": false,
+        "incomplete_files_ext": false,
         "dht": true, // allows magnets with no trackers to be added
+        "pex": true,
+        "lsd": true,
+        "max_connec": 500,
+        "max_connec_per_torrent": 100,
+        "max_uploads": 20,
+        "max_uploads_per_torrent": 4,
+        "listen_port": 6881,
+        "web_ui_address": "*",
+        "web_ui_port": 8080,
+        "web_ui_username": "admin",
+        "web_ui_csrf_protection_enabled": false,
+        "web_ui_host_header_validation_enabled": false,
+        "bypass_local_auth": true,
     }))
 }
 
+// clients send their own preferences back, they're accepted and ignored.
 async fn app_set_preferences() -> impl IntoResponse {
     StatusCode::OK
 }
@@ -78,20 +108,18 @@ async fn app_default_save_path() -> impl IntoResponse {
     config.mount_path.join("downloads").to_string_lossy().into_owned()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct QBTorrentsInfoRequest {
     pub category: Option<String>,
+    pub sort: Option<String>,
+    pub reverse: Option<bool>,
 }
 
 async fn torrents_info(
     State(state): State<Arc<AppState>>,
     Query(query): Query<QBTorrentsInfoRequest>,
 ) -> Result<impl IntoResponse, AppError> {
-    let mut sql = "SELECT * FROM torrents WHERE hidden = 0".to_string();
-    if let Some(category) = query.category {
-        sql += &format!(" AND category = '{}'", category);
-    }
-
+    let sql = get_torrents_info_sql(&query);
     let torrents = sqlx::query_as::<_, Torrent>(&sql).fetch_all(&state.pool).await?;
 
     Ok(Json(
@@ -99,6 +127,43 @@ async fn torrents_info(
     ))
 }
 
+fn get_torrents_info_sql(query: &QBTorrentsInfoRequest) -> String {
+    let mut sql = "SELECT * FROM torrents WHERE hidden = 0".to_string();
+    if let Some(category) = &query.category {
+        sql += &format!(" AND category = '{}'", category);
+    }
+
+    // unknown sort fields are ignored, the same as qbittorrent does
+    if let Some(column) = query.sort.as_deref().and_then(get_sort_column) {
+        let direction = if query.reverse.unwrap_or(false) { "DESC" } else { "ASC" };
+        sql += &format!(" ORDER BY {} {}, id {}", column, direction, direction);
+    }
+
+    sql
+}
+
+/// Maps a qbittorrent torrent field name to the column it is sorted by.
+fn get_sort_column(field: &str) -> Option<&'static str> {
+    match field {
+        "name" => Some("name"),
+        "hash" => Some("hash"),
+        "size" | "total_size" => Some("size"),
+        "progress" => Some("progress"),
+        "state" => Some("state"),
+        "category" => Some("category"),
+        "ratio" => Some("ratio"),
+        "eta" => Some("eta_secs"),
+        "dlspeed" => Some("download_speed"),
+        "upspeed" => Some("upload_speed"),
+        "num_seeds" => Some("seeds"),
+        "num_leechs" => Some("peers"),
+        "added_on" => Some("created_at"),
+        "completion_on" => Some("finished_at"),
+        "last_activity" => Some("COALESCE(checked_at, created_at)"),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct QBTorrentsHashRequest {
     pub hash: String,
@@ -113,7 +178,7 @@ async fn torrents_files(
         return Ok((StatusCode::NOT_FOUND, "Torrent not found").into_response());
     };
 
-    let files = sqlx::query_as::<_, TorrentFile>("SELECT * FROM torrent_files WHERE torrent_id = ?")
+    let files = sqlx::query_as::<_, TorrentFile>("SELECT * FROM torrent_files WHERE torrent_id = ? ORDER BY id")
         .bind(torrent.id)
         .fetch_all(&state.pool)
         .await?;
@@ -126,7 +191,7 @@ async fn torrents_files(
                     "name": f.path,
                     "size": f.size,
                     "progress": torrent.progress,
-                    "priority": 1,
+                    "priority": f.priority,
                     "piece_range": [0, 0],
                     "availability": 1.0,
                 })
@@ -136,6 +201,62 @@ async fn torrents_files(
     .into_response())
 }
 
+#[derive(Debug, Deserialize)]
+struct QBTorrentsFilePrioRequest {
+    pub hash: String,
+    pub id: String,
+    pub priority: i64,
+}
+
+async fn torrents_file_prio(
+    State(state): State<Arc<AppState>>,
+    Form(request): Form<QBTorrentsFilePrioRequest>,
+) -> Result<Response, AppError> {
+    let Ok(indexes) = request
+        .id
+        .split('|')
+        .map(|s| s.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid file id").into_response());
+    };
+
+    if !matches!(request.priority, 0 | 1 | 6 | 7) {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid priority").into_response());
+    }
+
+    let torrent = Torrent::find_by_hash(&request.hash, &state.pool).await?;
+    let Some(torrent) = torrent else {
+        return Ok((StatusCode::NOT_FOUND, "Torrent not found").into_response());
+    };
+
+    // file ids are indexes into the list returned by torrents_files
+    let file_ids = sqlx::query_scalar!(
+        "SELECT id FROM torrent_files WHERE torrent_id = ? ORDER BY id",
+        torrent.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut tx = state.pool.begin().await?;
+    for index in indexes {
+        let Some(file_id) = usize::try_from(index).ok().and_then(|i| file_ids.get(i)) else {
+            return Ok((StatusCode::CONFLICT, "File id not found").into_response());
+        };
+
+        sqlx::query!(
+            "UPDATE torrent_files SET priority = ? WHERE id = ?",
+            request.priority,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(StatusCode::OK.into_response())
+}
+
 async fn torrent_properties(
     State(state): State<Arc<AppState>>,
     Query(query): Query<QBTorrentsHashRequest>,
@@ -149,7 +270,8 @@ async fn torrent_properties(
     return Ok(Json(json!({
         "hash": torrent.hash,
         "save_path": torrent.save_path,
-        "seeding_time": 0,
+        "seeding_time": torrent.seeding_time.unwrap_or(0),
+        "error_message": torrent.error_message,
     }))
     .into_response());
 }
@@ -175,8 +297,18 @@ async fn torrents_delete(
     }
 
     let mut tx = state.pool.begin().await?;
+    let mut removing = Vec::new();
+    let mut deleted = Vec::new();
 
     for hash in &hashes {
+        let name = sqlx::query_scalar!("SELECT name FROM torrents WHERE hash = ? AND hidden = 0", hash)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        if let Some(name) = name {
+            deleted.push((hash, name));
+        }
+
         let references = sqlx::query_scalar!(
             "SELECT COUNT(*) FROM nodes WHERE readonly = 0 AND torrent_id IN (SELECT id FROM torrents WHERE hash = ?)",
             hash
@@ -194,6 +326,8 @@ async fn torrents_delete(
             )
             .execute(&mut *tx)
             .await?;
+
+            removing.push(hash);
         } else {
             sqlx::query!("UPDATE torrents SET hidden = 1 WHERE hash = ?", hash)
                 .execute(&mut *tx)
@@ -202,6 +336,37 @@ async fn torrents_delete(
     }
 
     tx.commit().await?;
+    for (hash, name) in deleted {
+        record_event(&state.pool, EventKind::Deleted, hash, &name, None).await;
+    }
+
+    if get_config().delete_immediately {
+        for hash in removing {
+            let torrent = sqlx::query!("SELECT id, debrid_id FROM torrents WHERE hash = ?", hash)
+                .fetch_optional(&state.pool)
+                .await?;
+
+            let Some(torrent) = torrent else {
+                continue;
+            };
+
+            let Some(debrid_id) = torrent.debrid_id else {
+                // never made it to the debrid service, the reconciler will clean it up.
+                continue;
+            };
+
+            // if this fails, the torrent is still marked for removal and the reconciler will retry.
+            if let Err(e) = state.debrid.delete_torrent(&(debrid_id as u64)).await {
+                warn!("Failed to immediately delete torrent {}: {}", hash, e);
+                continue;
+            }
+
+            sqlx::query!("DELETE FROM torrents WHERE id = ?", torrent.id)
+                .execute(&state.pool)
+                .await?;
+        }
+    }
+
     Ok(StatusCode::OK.into_response())
 }
 
@@ -210,10 +375,20 @@ async fn add_torrent(
     magnet_uris: Vec<String>,
     category: Option<String>,
 ) -> Result<Response, AppError> {
+    let config = get_config();
+    let category = get_add_category(category, config.default_category.as_deref());
+    if category
+        .as_ref()
+        .is_some_and(|c| !is_known_category(c, &config.categories))
+    {
+        return Ok((StatusCode::CONFLICT, "Unknown category").into_response());
+    }
+
     let mut tx = state.pool.begin().await?;
+    let mut added = Vec::new();
 
     for magnet_uri in magnet_uris {
-        let magnet_uri = add_trackers_to_magnet_uri(&magnet_uri);
+        let magnet_uri = prepare_magnet_uri(magnet_uri, config);
         let Some(meta) = parse_magnet_uri(&magnet_uri) else {
             return Ok((StatusCode::BAD_REQUEST, "Invalid magnet URI").into_response());
         };
@@ -225,6 +400,11 @@ async fn add_torrent(
         .fetch_optional(tx.as_mut())
         .await?;
 
+        if let Some(v2_hash) = &meta.v2_hash {
+            // hybrid magnets are always tracked by their v1 hash so they match v1 magnets for the same content
+            tracing::debug!("Magnet has v2 hash {}, using v1 hash {}", v2_hash, meta.hash);
+        }
+
         if let Some(existing) = existing {
             tracing::debug!("Torrent with hash {} already exists, updating it", meta.hash);
 
@@ -246,19 +426,27 @@ async fn add_torrent(
             tracing::debug!("Adding new torrent with hash {}", meta.hash);
             let name = meta.name.as_ref().unwrap_or(&meta.hash);
             sqlx::query!(
-                "INSERT INTO torrents (hash, name, category, state, magnet_uri) VALUES (?, ?, ?, ?, ?)",
+                "INSERT INTO torrents (hash, name, category, state, magnet_uri, source) VALUES (?, ?, ?, ?, ?, ?)",
                 meta.hash,
                 name,
                 category,
                 TorrentState::Pending,
-                magnet_uri
+                magnet_uri,
+                TorrentSource::Qbittorrent
             )
             .execute(&mut *tx)
             .await?;
+
+            added.push((meta.hash.clone(), name.clone()));
         }
     }
 
     tx.commit().await?;
+    for (hash, name) in added {
+        let message = format!("added via {}", TorrentSource::Qbittorrent.to_str());
+        record_event(&state.pool, EventKind::Added, &hash, &name, Some(&message)).await;
+    }
+
     state.notifier.notify_one();
     Ok(StatusCode::OK.into_response())
 }
@@ -361,9 +549,18 @@ async fn torrents_set_category(
         return Ok((StatusCode::BAD_REQUEST, "No hashes provided").into_response());
     }
 
+    // an empty category removes the category from the torrent
+    let category = Some(query.category).filter(|c| !c.is_empty());
+    if category
+        .as_ref()
+        .is_some_and(|c| !is_known_category(c, &get_config().categories))
+    {
+        return Ok((StatusCode::CONFLICT, "Unknown category").into_response());
+    }
+
     let mut tx = state.pool.begin().await?;
     for hash in hashes {
-        sqlx::query!("UPDATE torrents SET category = ? WHERE hash = ?", query.category, hash)
+        sqlx::query!("UPDATE torrents SET category = ? WHERE hash = ?", category, hash)
             .execute(&mut *tx)
             .await?;
     }
@@ -372,6 +569,32 @@ async fn torrents_set_category(
     Ok(StatusCode::OK.into_response())
 }
 
+fn prepare_magnet_uri(magnet_uri: String, config: &Config) -> String {
+    let mut magnet_uri = magnet_uri;
+    if config.strip_trackers {
+        magnet_uri = strip_trackers_from_magnet_uri(&magnet_uri);
+    }
+
+    if config.append_trackers {
+        magnet_uri = add_trackers_to_magnet_uri(&magnet_uri);
+    }
+
+    magnet_uri
+}
+
+/// Torrents added without a category are put in the default category, if one is configured.
+fn get_add_category(category: Option<String>, default_category: Option<&str>) -> Option<String> {
+    category
+        .filter(|c| !c.is_empty())
+        .or_else(|| default_category.map(|c| c.to_string()))
+}
+
+/// Categories are configured through `config.categories`, anything else is rejected so that
+/// torrents can't end up in a category that `torrents_categories` doesn't list.
+pub fn is_known_category(category: &str, categories: &[String]) -> bool {
+    categories.iter().any(|c| c == category)
+}
+
 async fn torrents_categories() -> Result<Response, AppError> {
     let config = get_config();
     let save_path = config.mount_path.join("downloads").to_string_lossy().into_owned();
@@ -431,11 +654,13 @@ pub fn mimic_qbittorrent() -> Router<Arc<AppState>> {
             "/api/v2/app/preferences",
             get(app_preferences).post(app_set_preferences),
         )
+        .route("/api/v2/app/setPreferences", post(app_set_preferences))
         .route("/api/v2/app/defaultSavePath", get(app_default_save_path))
         .route("/api/v2/app/webapiVersion", get(app_webapi_version))
         .route("/api/v2/app/version", get(app_version))
         .route("/api/v2/torrents/info", get(torrents_info))
         .route("/api/v2/torrents/files", get(torrents_files))
+        .route("/api/v2/torrents/filePrio", post(torrents_file_prio))
         .route("/api/v2/torrents/properties", get(torrent_properties))
         .route(
             "/api/v2/torrents/delete",
@@ -448,3 +673,86 @@ pub fn mimic_qbittorrent() -> Router<Arc<AppState>> {
         .route("/api/v2/torrents/removeCategory", post(torrents_remove_category))
         .route("/api/v2/{*path}", any(fallback))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_known_category() {
+        let categories = vec!["sonarr".to_string(), "radarr".to_string()];
+        assert!(is_known_category("sonarr", &categories));
+        assert!(!is_known_category("lidarr", &categories));
+        assert!(!is_known_category("Sonarr", &categories));
+    }
+
+    async fn get_sorted_names(pool: &sqlx::SqlitePool, query: &QBTorrentsInfoRequest) -> Vec<String> {
+        sqlx::query_as::<_, Torrent>(&get_torrents_info_sql(query))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_torrents_info_sort() {
+        let pool = crate::test_helpers::get_test_pool(false).await;
+        for (hash, name, size) in [(&[1u8][..], "b", 300), (&[2u8][..], "c", 100), (&[3u8][..], "a", 200)] {
+            sqlx::query("INSERT INTO torrents (hash, name, state, magnet_uri, size) VALUES (?, ?, 0, 'magnet:', ?)")
+                .bind(hash)
+                .bind(name)
+                .bind(size)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let query = QBTorrentsInfoRequest {
+            sort: Some("name".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_sorted_names(&pool, &query).await, vec!["a", "b", "c"]);
+
+        let query = QBTorrentsInfoRequest {
+            sort: Some("size".to_string()),
+            reverse: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(get_sorted_names(&pool, &query).await, vec!["b", "a", "c"]);
+
+        let query = QBTorrentsInfoRequest {
+            sort: Some("size; DROP TABLE torrents".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_sorted_names(&pool, &query).await.len(), 3);
+    }
+
+    #[test]
+    fn test_get_add_category() {
+        assert_eq!(get_add_category(None, None), None);
+        assert_eq!(get_add_category(None, Some("radarr")), Some("radarr".to_string()));
+        assert_eq!(
+            get_add_category(Some("".to_string()), Some("radarr")),
+            Some("radarr".to_string())
+        );
+        assert_eq!(
+            get_add_category(Some("sonarr".to_string()), Some("radarr")),
+            Some("sonarr".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prepare_magnet_uri() {
+        let magnet = "magnet:?xt=urn:btih:1234567890abcdef1234567890abcdef12345678&tr=udp://tracker.example.com";
+        let config = crate::config::get_test_config(&[("append_trackers", "false")]);
+        assert_eq!(prepare_magnet_uri(magnet.to_string(), &config), magnet);
+
+        let config = crate::config::get_test_config(&[]);
+        assert!(prepare_magnet_uri(magnet.to_string(), &config).contains("tracker.opentrackr.org"));
+
+        let config = crate::config::get_test_config(&[("append_trackers", "false"), ("strip_trackers", "true")]);
+        assert!(!prepare_magnet_uri(magnet.to_string(), &config).contains("tr="));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::config::get_config;
-use crate::state::TorrentState;
+use crate::helpers::now_secs::now_secs;
+use crate::state::{TorrentSource, TorrentState};
 use serde::Serialize;
 use sqlx::{FromRow, SqlitePool};
 
@@ -9,6 +10,7 @@ pub struct Torrent {
     pub hash: Vec<u8>,
     pub name: String,
     pub state: TorrentState,
+    pub error_message: Option<String>,
     pub magnet_uri: String,
     pub progress: f64,
     pub upload_speed: i64,
@@ -23,11 +25,21 @@ pub struct Torrent {
     pub created_at: i64,
     pub checked_at: Option<i64>,
     pub finished_at: Option<i64>,
+    pub source: TorrentSource,
 }
 
 impl Torrent {
     pub fn to_qbittorrent(&self) -> QBittorrentTorrent {
-        let save_path = get_config().mount_path.join("downloads");
+        let config = get_config();
+        let save_path = config.mount_path.join("downloads");
+        let seeding_time = get_seeding_time_secs(self.finished_at, now_secs());
+        let ratio = match config.reported_ratio {
+            // torrents are "seeding" as soon as they are ready, reporting a high ratio lets
+            // the *arr cleanup remove them once they have been imported.
+            Some(reported_ratio) if self.state == TorrentState::Ready => reported_ratio,
+            _ => self.ratio,
+        };
+
         QBittorrentTorrent {
             hash: hex::encode(&self.hash),
             name: self.name.clone(),
@@ -37,13 +49,28 @@ impl Torrent {
             state: self.state.to_str().to_string(),
             category: self.category.clone(),
             save_path: Some(save_path.to_string_lossy().into_owned()),
-            ratio: self.ratio,
+            ratio,
             ratio_limit: None,
-            seeding_time: None,
+            seeding_time,
             seeding_time_limit: None,
             inactive_seeding_time_limit: None,
-            last_activity: self.checked_at.unwrap_or(self.created_at) as u64,
+            last_activity: get_last_activity_secs(self.checked_at, self.created_at),
+            error_message: self.get_error_message(),
+        }
+    }
+
+    /// The reason the torrent failed, if it is in the error state. The stored message is
+    /// not cleared when a torrent recovers, so it is ignored for any other state.
+    pub fn get_error_message(&self) -> Option<String> {
+        if self.state != TorrentState::Error {
+            return None;
         }
+
+        Some(
+            self.error_message
+                .clone()
+                .unwrap_or_else(|| "Unknown error".to_string()),
+        )
     }
 
     pub async fn find_by_hash(hash: &str, pool: &SqlitePool) -> Result<Option<Torrent>, sqlx::Error> {
@@ -54,6 +81,7 @@ impl Torrent {
                 hash,
                 name,
                 state as "state: TorrentState",
+                error_message,
                 magnet_uri,
                 progress,
                 upload_speed,
@@ -67,7 +95,8 @@ impl Torrent {
                 category,
                 created_at,
                 checked_at,
-                finished_at
+                finished_at,
+                source as "source: TorrentSource"
             FROM torrents
             WHERE hash = ? AND hidden = 0"#,
             hash
@@ -77,6 +106,17 @@ impl Torrent {
     }
 }
 
+pub fn get_last_activity_secs(checked_at: Option<i64>, created_at: i64) -> u64 {
+    checked_at.unwrap_or(created_at).max(0) as u64
+}
+
+/// Torrents are considered to be seeding from the moment they finished.
+pub fn get_seeding_time_secs(finished_at: Option<i64>, now: i64) -> Option<u32> {
+    let finished_at = finished_at?;
+    let seeding_time_secs = (now - finished_at).max(0);
+    Some(seeding_time_secs.min(u32::MAX as i64) as u32)
+}
+
 #[derive(Debug, Serialize)]
 pub struct QBittorrentTorrent {
     pub hash: String,
@@ -94,4 +134,27 @@ pub struct QBittorrentTorrent {
     pub seeding_time_limit: Option<u32>,
     pub inactive_seeding_time_limit: Option<u32>,
     pub last_activity: u64,
+    pub error_message: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_seeding_time_secs() {
+        assert_eq!(get_seeding_time_secs(None, 1_745_000_000), None);
+        assert_eq!(get_seeding_time_secs(Some(1_744_999_994), 1_745_000_000), Some(6));
+        // clock skew should never report a negative seeding time
+        assert_eq!(get_seeding_time_secs(Some(1_745_000_012), 1_745_000_000), Some(0));
+    }
+
+    #[test]
+    fn test_get_last_activity_secs() {
+        assert_eq!(
+            get_last_activity_secs(Some(1_745_000_000), 1_744_000_000),
+            1_745_000_000
+        );
+        assert_eq!(get_last_activity_secs(None, 1_744_000_000), 1_744_000_000);
+    }
 }
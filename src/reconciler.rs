@@ -1,310 +1,865 @@
-use crate::config::get_config;
-use crate::debrid::{Debrid, TorboxApiErrorType, TorboxError, TorboxTorrentFile};
-use crate::helpers::should_ignore_path::should_ignore_path;
-use crate::state::TorrentState;
+use crate::activity::Activity;
+use crate::config::{Config, get_config};
+use crate::debrid::{DebridError, DebridProvider, DebridTorrent, DebridTorrentFile, TorboxApiErrorType};
+use crate::events::{EventKind, record_event};
+use crate::helpers::now_secs::now_secs;
+use crate::helpers::sanitize_node_name::sanitize_node_path;
+use crate::helpers::should_ignore_path::{
+    PathStatus, get_path_filters, get_path_status, is_archive_path, should_ignore_path,
+};
+use crate::state::{TorrentSource, TorrentState};
 use anyhow::Result;
+use serde::Serialize;
 use sqlx::{Sqlite, SqlitePool, Transaction};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use tokio::sync::Notify;
 use tokio::time::sleep;
 
 const RECHECK_INTERVAL_SECS: u64 = 10 * 60; // 10 minutes
 const MIN_RECHECK_INTERVAL_SECS: u64 = 30; // 30 seconds
+// with reconcile_on_add, how long to wait for more torrents after one is added. cached torrents
+// are ready in the first pass, so they show as completed within a few seconds of being added
+// instead of the 30 seconds it can take to wait out MIN_RECHECK_INTERVAL_SECS.
+const ADD_DEBOUNCE_SECS: u64 = 2;
+// how many passes a torrent with no valid files is rechecked for before it's left errored
+const MAX_EMPTY_FILE_CHECKS: i64 = 6;
+const STUCK_TORRENT_AGE_SECS: i64 = 30 * 60; // 30 minutes
+// how far each pass moves the average pass duration, higher follows recent passes more closely
+const PASS_DURATION_SMOOTHING: f64 = 0.2;
 
-pub async fn start_reconciler(db: &SqlitePool, debrid: Arc<Debrid>, notifier: Arc<Notify>) -> Result<()> {
+/// How long reconcile passes take. With enough torrents or a slow debrid api, a pass can take longer
+/// than `RECHECK_INTERVAL_SECS` and the reconciler never actually waits between passes, this makes that visible.
+#[derive(Default)]
+pub struct ReconcilerHealth {
+    last_pass_ms: AtomicU64,
+    average_pass_ms: AtomicU64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ReconcilerStatus {
+    pub last_pass_ms: u64,
+    /// Moving average of the pass duration, 0 until the first pass has finished.
+    pub average_pass_ms: u64,
+    /// Passes are taking longer than the recheck interval on average.
+    pub lagging: bool,
+}
+
+impl ReconcilerHealth {
+    pub fn record_pass(&self, duration: Duration) {
+        let duration_ms = duration.as_millis() as u64;
+        let average_ms = get_moving_average(self.average_pass_ms.load(Ordering::Relaxed), duration_ms);
+        self.last_pass_ms.store(duration_ms, Ordering::Relaxed);
+        self.average_pass_ms.store(average_ms, Ordering::Relaxed);
+    }
+
+    pub fn get_status(&self) -> ReconcilerStatus {
+        let average_pass_ms = self.average_pass_ms.load(Ordering::Relaxed);
+        ReconcilerStatus {
+            last_pass_ms: self.last_pass_ms.load(Ordering::Relaxed),
+            average_pass_ms,
+            lagging: average_pass_ms > RECHECK_INTERVAL_SECS * 1000,
+        }
+    }
+}
+
+fn get_moving_average(average_ms: u64, duration_ms: u64) -> u64 {
+    // the first pass has nothing to average with
+    if average_ms == 0 {
+        return duration_ms;
+    }
+
+    (average_ms as f64 * (1.0 - PASS_DURATION_SMOOTHING) + duration_ms as f64 * PASS_DURATION_SMOOTHING).round() as u64
+}
+
+pub struct LocalTorrent {
+    pub id: i64,
+    pub hash: Vec<u8>,
+    pub name: String,
+    pub state: TorrentState,
+    pub hidden: i64,
+    pub magnet_uri: String,
+    pub debrid_id: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub created_at: i64,
+    pub empty_file_checks: i64,
+    pub category: Option<String>,
+    pub link_check_failed: bool,
+}
+
+pub async fn find_local_torrent(db: &SqlitePool, torrent_id: i64) -> Result<Option<LocalTorrent>, sqlx::Error> {
+    sqlx::query_as!(
+        LocalTorrent,
+        r#"SELECT id, hash, name, state as "state: TorrentState", hidden, magnet_uri, debrid_id, finished_at, created_at,
+            empty_file_checks, category, link_check_failed as "link_check_failed: bool"
+        FROM torrents WHERE id = ?"#,
+        torrent_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn start_reconciler(
+    db: &SqlitePool,
+    debrid: Arc<dyn DebridProvider>,
+    notifier: Arc<Notify>,
+    activity: Arc<Activity>,
+    health: Arc<ReconcilerHealth>,
+) -> Result<()> {
     // gives time for the reconciler to be blocked on startup, without taking
     // too much time for an initial sync
     sleep(Duration::from_secs(5)).await;
 
     let config = get_config();
-    let mut download_limit: usize = 20;
+    let mut download_limit: usize = config.download_slots;
+
+    // the notifier is only used for added torrents, rechecks are handled by the interval.
+    let min_notified_interval_secs = if config.reconcile_on_add {
+        ADD_DEBOUNCE_SECS
+    } else {
+        MIN_RECHECK_INTERVAL_SECS
+    };
 
     loop {
-        tracing::debug!("Reconciling torrents");
-        let mut remote_torrents: HashMap<Vec<u8>, _> = debrid
-            .get_torrent_list(false)
-            .await?
-            .into_iter()
-            .map(|t| (hex::decode(&t.hash).unwrap(), t))
-            .collect();
-
-        let active_count = remote_torrents.values().into_iter().filter(|t| t.active).count();
-
-        // todo: this was using streaming, but it holds the db connection and because we have a single
-        // connection, it means inner queries will block indefinitely.
-        // let local_torrents = torrents::Entity::find().all(db).await?;
-        let local_torrents = sqlx::query!(
-            "SELECT id, hash, state as \"state: TorrentState\", error_message, hidden, magnet_uri, finished_at FROM torrents"
-        )
-        .fetch_all(db)
-        .await?;
+        let started_at = Instant::now();
+        let recheck = reconcile(db, debrid.as_ref(), config, &mut download_limit).await?;
+        let pass_duration = started_at.elapsed();
+        health.record_pass(pass_duration);
+        if pass_duration.as_secs() >= RECHECK_INTERVAL_SECS {
+            tracing::warn!(
+                "reconciling torrents took {} seconds, longer than the {} second recheck interval (average {} seconds)",
+                pass_duration.as_secs(),
+                RECHECK_INTERVAL_SECS,
+                health.get_status().average_pass_ms / 1000
+            );
+        }
 
-        for local_torrent in local_torrents {
-            let torrent_hash = hex::encode(&local_torrent.hash);
-            let live_ref_count = sqlx::query_scalar!(
-                "SELECT COUNT(*) as count FROM nodes WHERE torrent_id = ? AND readonly = 0",
-                local_torrent.id
-            )
-            .fetch_one(db)
-            .await?;
+        // while nothing is using lumin, torrents that are still downloading can wait for the normal interval
+        let is_idle = activity.is_idle();
+        let interval_secs = if recheck && !is_idle {
+            MIN_RECHECK_INTERVAL_SECS
+        } else {
+            RECHECK_INTERVAL_SECS
+        };
 
-            // files_created check is necessary or else when we add a torrent, we instantly remove it.
-            // we have to wait until the files are created in the download dir.
-            let initial_state = if local_torrent.hidden == 1 && live_ref_count == 0 {
-                // remove torrents with no references, they are essentially dead.
-                tracing::info!("marking unused torrent {} for removal", torrent_hash);
-                TorrentState::Removing
-            } else {
-                local_torrent.state
-            };
+        let finished_at = Instant::now();
+        tracing::debug!("finished reconciling torrents");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(interval_secs)) => {}
+            _ = activity.resumed(), if is_idle && recheck => {
+                tracing::info!("reconciler woken up by activity");
+            }
+            _ = notifier.notified() => {
+                let since_finished_secs = finished_at.elapsed().as_secs();
+                if since_finished_secs < min_notified_interval_secs {
+                    let wait_time = min_notified_interval_secs - since_finished_secs;
+                    tracing::info!("reconciler notified early, waiting {} seconds", wait_time);
+                    sleep(Duration::from_secs(wait_time)).await;
+                } else {
+                    tracing::info!("reconciler notified early, skipping wait");
+                }
+            }
+        }
+    }
+}
 
-            let debrid_torrent = match remote_torrents.remove(&local_torrent.hash) {
-                Some(torrent) => torrent,
-                None => {
-                    if initial_state == TorrentState::Removing {
-                        // https://tenor.com/bYVT6.gif
-                        tracing::warn!("removing torrent {}", torrent_hash);
-                        sqlx::query!("DELETE FROM torrents WHERE id = ?", local_torrent.id)
-                            .execute(db)
-                            .await?;
+/// Runs a single reconcile pass, returning true if another pass should run soon.
+async fn reconcile(
+    db: &SqlitePool,
+    debrid: &dyn DebridProvider,
+    config: &Config,
+    download_limit: &mut usize,
+) -> Result<bool> {
+    let mut recheck = false;
+    tracing::debug!("Reconciling torrents");
+    let mut remote_torrents: HashMap<Vec<u8>, _> = debrid
+        .get_torrent_list(false)
+        .await?
+        .into_iter()
+        .map(|t| (hex::decode(&t.hash).unwrap(), t))
+        .collect();
 
-                        continue;
-                    }
+    let mut active_count = remote_torrents.values().into_iter().filter(|t| t.active).count();
+    let mut create_count = 0;
 
-                    if active_count >= download_limit {
-                        tracing::debug!(
-                            "torrent download limit {} hit, not adding torrent {}",
-                            download_limit,
+    // todo: this was using streaming, but it holds the db connection and because we have a single
+    // connection, it means inner queries will block indefinitely.
+    // let local_torrents = torrents::Entity::find().all(db).await?;
+    let local_torrents = sqlx::query_as!(
+        LocalTorrent,
+        r#"SELECT id, hash, name, state as "state: TorrentState", hidden, magnet_uri, debrid_id, finished_at, created_at,
+            empty_file_checks, category, link_check_failed as "link_check_failed: bool"
+        FROM torrents"#
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut removed_count = 0;
+    let mut failed_count = 0;
+    for mut local_torrent in local_torrents {
+        let torrent_hash = hex::encode(&local_torrent.hash);
+        let live_ref_count = sqlx::query_scalar!(
+            "SELECT COUNT(*) as count FROM nodes WHERE torrent_id = ? AND readonly = 0",
+            local_torrent.id
+        )
+        .fetch_one(db)
+        .await?;
+
+        // files_created check is necessary or else when we add a torrent, we instantly remove it.
+        // we have to wait until the files are created in the download dir.
+        // torrents that were just added may not have been grabbed by the client yet, so they get a grace period.
+        let is_new = now_secs() - local_torrent.created_at < config.min_torrent_age_secs as i64;
+        if local_torrent.hidden == 1 && live_ref_count == 0 && !is_new {
+            // remove torrents with no references, they are essentially dead.
+            tracing::info!("marking unused torrent {} for removal", torrent_hash);
+            local_torrent.state = TorrentState::Removing;
+        }
+
+        let debrid_torrent = match remote_torrents.remove(&local_torrent.hash) {
+            Some(torrent) => torrent,
+            None => {
+                // a torrent with a debrid id was created on the debrid service before, so if it's gone
+                // now it was deleted there (usually by the user on the website) rather than never added.
+                let deleted_remotely = config.honor_remote_deletion && local_torrent.debrid_id.is_some();
+                if local_torrent.state == TorrentState::Removing || deleted_remotely {
+                    // https://tenor.com/bYVT6.gif
+                    let reason = if local_torrent.state == TorrentState::Removing {
+                        tracing::warn!("removing torrent {}", torrent_hash);
+                        "Torrent is no longer used"
+                    } else {
+                        tracing::warn!(
+                            "torrent {} was deleted from the debrid service, removing it",
                             torrent_hash
                         );
-                        continue;
+                        "Torrent was deleted from the debrid service"
+                    };
+
+                    sqlx::query!("DELETE FROM torrents WHERE id = ?", local_torrent.id)
+                        .execute(db)
+                        .await?;
+
+                    record_event(db, EventKind::Removed, &torrent_hash, &local_torrent.name, Some(reason)).await;
+
+                    removed_count += 1;
+                    continue;
+                }
+
+                if active_count >= *download_limit {
+                    tracing::debug!(
+                        "torrent download limit {} hit, not adding torrent {}",
+                        download_limit,
+                        torrent_hash
+                    );
+
+                    if local_torrent.state != TorrentState::Queued {
+                        sqlx::query!(
+                            "UPDATE torrents SET state = ? WHERE id = ?",
+                            TorrentState::Queued,
+                            local_torrent.id
+                        )
+                        .execute(db)
+                        .await?;
                     }
 
-                    // torrent does not exist on the debrid service, we need to add it
-                    match debrid.create_from_magnet(&local_torrent.magnet_uri).await {
-                        Err(TorboxError::ApiError(api_error)) => match api_error.data {
-                            TorboxApiErrorType::ActiveLimit { active_limit } => {
-                                tracing::warn!("ACTIVE_LIMIT error hit, limiting active torrents to {}", active_limit);
-                                download_limit = active_limit as usize;
-                                continue;
-                            }
-                            _ => {
-                                tracing::error!("Failed to create torrent from magnet: {}", api_error);
-                                continue;
-                            }
-                        },
-                        Err(e) => {
-                            tracing::error!("Failed to create torrent from magnet: {}", e);
+                    continue;
+                }
+
+                if create_count >= config.max_creates_per_pass {
+                    // spreads adding a large backlog (like on first startup) over multiple passes
+                    tracing::debug!(
+                        "added {} torrents this pass, deferring torrent {}",
+                        create_count,
+                        torrent_hash
+                    );
+
+                    recheck = true;
+                    continue;
+                }
+
+                // torrent does not exist on the debrid service, we need to add it
+                create_count += 1;
+                match debrid.create_from_magnet(&local_torrent.magnet_uri).await {
+                    Err(DebridError::ApiError(api_error)) => match api_error.data {
+                        TorboxApiErrorType::ActiveLimit { active_limit } => {
+                            tracing::warn!("ACTIVE_LIMIT error hit, limiting active torrents to {}", active_limit);
+                            *download_limit = active_limit as usize;
+                            continue;
+                        }
+                        _ => {
+                            tracing::error!("Failed to create torrent from magnet: {}", api_error);
                             continue;
                         }
-                        Ok(created_torrent) => debrid.get_torrent_info(&created_torrent.torrent_id).await?,
+                    },
+                    Err(e) => {
+                        tracing::error!("Failed to create torrent from magnet: {}", e);
+                        continue;
+                    }
+                    Ok(created_torrent) => {
+                        let created_torrent = debrid.get_torrent_info(&created_torrent.torrent_id).await?;
+                        if created_torrent.active {
+                            // keeps the download limit accurate for the rest of the pass
+                            active_count += 1;
+                        }
+
+                        created_torrent
                     }
                 }
-            };
+            }
+        };
 
-            if initial_state == TorrentState::Removing {
-                // remove the torrent from the debrid service
-                tracing::info!("removing torrent {}", torrent_hash);
-                debrid.delete_torrent(&debrid_torrent.id).await?;
-                sqlx::query!("DELETE FROM torrents WHERE id = ?", local_torrent.id)
-                    .execute(db)
-                    .await?;
+        let outcome = reconcile_one(db, debrid, config, &local_torrent, debrid_torrent).await?;
+        if outcome.removed {
+            removed_count += 1;
+        } else if outcome.errored {
+            failed_count += 1;
+        }
+    }
 
-                continue;
-            }
+    if removed_count > 0 || failed_count > 0 {
+        tracing::debug!("removed {} torrents, {} torrents failed", removed_count, failed_count);
+    }
 
-            let mut dir_name = None;
-            if let Some(files) = debrid_torrent.files {
-                dir_name = files
-                    .first()
-                    .and_then(|file| file.name.split_once('/'))
-                    .map(|(dir, _)| dir.to_string());
-
-                let filtered_files = files
-                    .into_iter()
-                    .filter(|file| !should_ignore_path(&file.name))
-                    .collect::<Vec<_>>();
-
-                if filtered_files.len() == 0 {
-                    // todo: it would be nice if we could include more specific information in the error message
-                    // this handles a few cases:
-                    // - torbox has (had?) a bug where if a user requested a torernt be zipped, it was zipped for everyone
-                    // (apparently not a bug, but a feature:tm:!), which caused it to be unstreamable. because we filter out
-                    // zip files, those broken torrents will trigger this.
-                    // - torrents that are intentionally malicious and that only contain EXEs or other silly things
-                    tracing::error!("torrent {} has no valid files, marking as failed", torrent_hash);
-                    let state = TorrentState::Error as i64;
-                    let error_message = "Torrent has no valid files".to_string();
-                    sqlx::query!(
-                        "UPDATE torrents SET state = ?, error_message = ? WHERE id = ?",
-                        state,
-                        error_message,
-                        local_torrent.id
-                    )
-                    .execute(db)
-                    .await?;
-                } else {
-                    let mut tx = db.begin().await?;
-                    for file in filtered_files.into_iter() {
-                        if should_ignore_path(&file.name) {
-                            tracing::warn!("ignoring file {} in torrent {}", file.name, torrent_hash);
-                            continue;
-                        }
+    let stuck = handle_stuck_torrents(db, config.stuck_torrent_timeout_secs).await?;
+    if stuck > 0 {
+        tracing::warn!(
+            "{} torrents are marked as downloading or ready but were never added to the debrid service",
+            stuck
+        );
+    }
 
-                        let file_id = file.id as i64;
-                        let file_size = file.size as i64;
-
-                        let file_id = sqlx::query_scalar!(
-                            "INSERT INTO torrent_files (torrent_id, path, debrid_id, size) VALUES (?, ?, ?, ?)
-                            ON CONFLICT(torrent_id, path) DO UPDATE SET debrid_id = excluded.debrid_id, size = excluded.size
-                            RETURNING id",
-                            local_torrent.id,
-                            file.name,
-                            file_id,
-                            file_size,
-                        )
-                        .fetch_one(tx.as_mut())
-                        .await?;
+    if config.remove_orphaned_nodes {
+        let removed = remove_orphaned_nodes(db).await?;
+        if removed > 0 {
+            tracing::warn!(
+                "removed {} nodes pointing at files or torrents that no longer exist",
+                removed
+            );
+        }
+    }
 
-                        create_nodes_for_file(&mut tx, local_torrent.id, file_id, &file).await?;
-                    }
+    if config.delete_unmapped && remote_torrents.len() > 0 {
+        for (hash, torrent) in remote_torrents {
+            let torrent_hash = hex::encode(hash);
+            tracing::info!("deleting unmapped debrid torrent {}", torrent_hash);
+            debrid.delete_torrent(&torrent.id).await?;
+        }
+    } else if config.import_unmapped && !remote_torrents.is_empty() {
+        for (hash, torrent) in remote_torrents {
+            tracing::info!("importing unmapped debrid torrent {}", torrent.hash);
+            import_unmapped_torrent(db, &hash, &torrent).await?;
+        }
 
-                    tx.commit().await?;
-                }
+        // files and nodes are created once the next pass sees the torrent
+        recheck = true;
+    }
+
+    Ok(recheck)
+}
+
+/// What reconciling a single torrent changed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReconcileOutcome {
+    /// The state the torrent moved to, None if it stayed the same.
+    pub new_state: Option<TorrentState>,
+    /// How many files had nodes created for them.
+    pub nodes_created: usize,
+    pub removed: bool,
+    /// Whether the torrent is failed after this pass, either reported by the debrid service or found by us.
+    pub errored: bool,
+}
+
+/// Reconciles a torrent that exists on the debrid service, removing it from both sides if it's marked
+/// for removal and otherwise updating it from the debrid service.
+pub async fn reconcile_one(
+    db: &SqlitePool,
+    debrid: &dyn DebridProvider,
+    config: &Config,
+    local_torrent: &LocalTorrent,
+    debrid_torrent: DebridTorrent,
+) -> Result<ReconcileOutcome> {
+    if local_torrent.state != TorrentState::Removing {
+        return sync_torrent(db, debrid, config, local_torrent, debrid_torrent).await;
+    }
+
+    // remove the torrent from the debrid service
+    let torrent_hash = hex::encode(&local_torrent.hash);
+    tracing::info!("removing torrent {}", torrent_hash);
+    debrid.delete_torrent(&debrid_torrent.id).await?;
+    sqlx::query!("DELETE FROM torrents WHERE id = ?", local_torrent.id)
+        .execute(db)
+        .await?;
+
+    let reason = Some("Torrent is no longer used");
+    record_event(db, EventKind::Removed, &torrent_hash, &local_torrent.name, reason).await;
+
+    Ok(ReconcileOutcome {
+        removed: true,
+        ..Default::default()
+    })
+}
+
+/// Updates a torrent from its state on the debrid service, creating its files and nodes once it has them.
+async fn sync_torrent(
+    db: &SqlitePool,
+    debrid: &dyn DebridProvider,
+    config: &Config,
+    local_torrent: &LocalTorrent,
+    debrid_torrent: DebridTorrent,
+) -> Result<ReconcileOutcome> {
+    let torrent_hash = hex::encode(&local_torrent.hash);
+    let mut outcome = ReconcileOutcome::default();
+    if local_torrent.state == TorrentState::Error && local_torrent.empty_file_checks >= MAX_EMPTY_FILE_CHECKS {
+        // gave up waiting for the torrent to gain valid files
+        outcome.errored = true;
+        return Ok(outcome);
+    }
+
+    let mut next_state = get_next_state(
+        debrid_torrent.state,
+        debrid_torrent.download_present,
+        config.trust_download_present,
+    );
+
+    if config.verify_on_ready && next_state == TorrentState::Ready && local_torrent.state != TorrentState::Ready {
+        if local_torrent.link_check_failed {
+            // the link is only checked when the torrent first becomes ready, instead of requesting one every pass
+            outcome.errored = true;
+            return Ok(outcome);
+        }
+
+        // torbox has a lot of "broken" torrents that give a database error when you try and stream them,
+        // so we check a download link can be created before exposing files that can never be read.
+        if let Err(error) = verify_download_link(debrid, &debrid_torrent).await {
+            tracing::warn!(
+                "torrent {} has become ready but its download link is broken, marking it as failed: {}",
+                torrent_hash,
+                error
+            );
+
+            let error_message = "Failed to create a download link for the torrent, it's likely corrupted";
+            sqlx::query!(
+                "UPDATE torrents SET state = ?, error_message = ?, link_check_failed = 1 WHERE id = ?",
+                TorrentState::Error,
+                error_message,
+                local_torrent.id
+            )
+            .execute(db)
+            .await?;
+
+            if local_torrent.state != TorrentState::Error {
+                let message = Some(error_message);
+                record_event(db, EventKind::Failed, &torrent_hash, &local_torrent.name, message).await;
+                outcome.new_state = Some(TorrentState::Error);
             }
 
-            let mut next_state = TorrentState::from_str(&debrid_torrent.download_state);
-            if debrid_torrent.download_present && next_state == TorrentState::Downloading {
-                // sometimes the download is present but the torrent state does not agree.
-                // this seems okay and speeds up torrent availability.
-                next_state = TorrentState::Ready;
+            outcome.errored = true;
+            return Ok(outcome);
+        }
+
+        if local_torrent.state == TorrentState::Error {
+            // the error from before the torrent became ready no longer applies
+            sqlx::query!(
+                "UPDATE torrents SET error_message = NULL WHERE id = ?",
+                local_torrent.id
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+
+    let mut dir_name = None;
+    let mut error_message = None;
+    if let Some(files) = debrid_torrent.files {
+        dir_name = files
+            .first()
+            .and_then(|file| file.name.split_once('/'))
+            .map(|(dir, _)| dir.to_string());
+
+        let filters = get_path_filters(config, local_torrent.category.as_deref());
+        let mut filtered_files = Vec::new();
+        let mut only_archives = true;
+        for file in files {
+            match get_path_status(&file.name, filters) {
+                PathStatus::Allowed => filtered_files.push(file),
+                status => {
+                    tracing::debug!("ignoring file {} in torrent {} ({:?})", file.name, torrent_hash, status);
+                    if status != PathStatus::DisallowedExtension || !is_archive_path(&file.name) {
+                        only_archives = false;
+                    }
+                }
             }
+        }
 
-            // if next_state == TorrentState::Ready && initial_state != next_state {
-            //     // if the torrent changes into a Ready state, we want to verify the torrent is ready.
-            //     // torbox for some reason has a lot of "broken" torrents that give a database error
-            //     // when you try and stream them. so whatever, this works for now.
-            //     let can_download = debrid.get_download_link(debrid_torrent.id as i64, 0).await.ok();
-
-            //     if can_download.is_none() {
-            //         tracing::warn!(
-            //             "Torrent {} has become Ready but its download link is broken. Marking it as failed",
-            //             local_torrent.hash
-            //         );
-            //         let mut local_torrent = local_torrent.into_active_model();
-            //         local_torrent.state = Set(TorrentState::Error);
-            //         local_torrent.error_message = Set(Some(
-            //             "Failed to create download link for torrent, its likely corrupted".to_string(),
-            //         ));
-            //         local_torrent.save(db).await?;
-            //         continue;
-            //     }
-            // }
-
-            if next_state != TorrentState::try_from(local_torrent.state)? {
-                tracing::info!(
-                    "torrent {} changed from {:?} to {:?}",
+        if filtered_files.is_empty() {
+            // this handles a few cases:
+            // - torbox has (had?) a bug where if a user requested a torernt be zipped, it was zipped for everyone
+            // (apparently not a bug, but a feature:tm:!), which caused it to be unstreamable. because we filter out
+            // zip files, those broken torrents will trigger this.
+            // - torrents that are intentionally malicious and that only contain EXEs or other silly things
+            // - debrid services that populate the file list progressively, which is why it's rechecked
+            if local_torrent.state == TorrentState::Error {
+                tracing::debug!(
+                    "torrent {} still has no valid files (check {} of {})",
                     torrent_hash,
-                    initial_state,
-                    next_state
+                    local_torrent.empty_file_checks + 1,
+                    MAX_EMPTY_FILE_CHECKS
                 );
+            } else {
+                tracing::error!("torrent {} has no valid files, marking as failed", torrent_hash);
             }
 
-            let debrid_id = debrid_torrent.id as i64;
-            let finished_at = if next_state == TorrentState::Ready {
-                Some(
-                    local_torrent
-                        .finished_at
-                        .unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
-                )
+            let message = if only_archives {
+                "Torrent only contains archives, which can't be streamed"
             } else {
-                None
+                "Torrent has no valid files"
             };
-
-            let now = chrono::Utc::now().timestamp_millis();
-            let eta = debrid_torrent.eta as i64;
-            let size = debrid_torrent.size as i64;
-            let seeds = debrid_torrent.seeds as i64;
-            let peers = debrid_torrent.peers as i64;
             sqlx::query!(
-                "UPDATE torrents SET
-                    name = COALESCE(name, ?),
-                    state = ?,
-                    debrid_id = ?,
-                    progress = ?,
-                    upload_speed = ?,
-                    download_speed = ?,
-                    seeds = ?,
-                    peers = ?,
-                    ratio = ?,
-                    eta_secs = ?,
-                    size = ?,
-                    checked_at = ?,
-                    finished_at = ?
-            ",
-                dir_name,
-                next_state,
-                debrid_id,
-                debrid_torrent.progress,
-                debrid_torrent.upload_speed,
-                debrid_torrent.download_speed,
-                seeds,
-                peers,
-                debrid_torrent.ratio,
-                eta,
-                size,
-                now,
-                finished_at,
+                "UPDATE torrents SET error_message = ?, empty_file_checks = empty_file_checks + 1 WHERE id = ?",
+                message,
+                local_torrent.id
             )
             .execute(db)
             .await?;
-        }
 
-        if config.delete_unmapped && remote_torrents.len() > 0 {
-            for (hash, torrent) in remote_torrents {
-                let torrent_hash = hex::encode(hash);
-                tracing::info!("deleting unmapped debrid torrent {}", torrent_hash);
-                debrid.delete_torrent(&torrent.id).await?;
+            next_state = TorrentState::Error;
+            error_message = Some(message);
+        } else {
+            if local_torrent.empty_file_checks > 0 {
+                tracing::info!("torrent {} now has valid files", torrent_hash);
+                sqlx::query!(
+                    "UPDATE torrents SET error_message = NULL, empty_file_checks = 0 WHERE id = ?",
+                    local_torrent.id
+                )
+                .execute(db)
+                .await?;
             }
-        }
 
-        let finished_at = Instant::now();
-        tracing::debug!("finished reconciling torrents");
-        tokio::select! {
-            _ = sleep(Duration::from_secs(RECHECK_INTERVAL_SECS)) => {}
-            _ = notifier.notified() => {
-                let since_finished_secs = finished_at.elapsed().as_secs();
-                if since_finished_secs < MIN_RECHECK_INTERVAL_SECS {
-                    let wait_time = MIN_RECHECK_INTERVAL_SECS - since_finished_secs;
-                    tracing::info!("reconciler notified early, waiting {} seconds", wait_time);
-                    sleep(Duration::from_secs(wait_time)).await;
-                } else {
-                    tracing::info!("reconciler notified early, skipping wait");
+            let mut tx = db.begin().await?;
+            for file in filtered_files.into_iter() {
+                if should_ignore_path(&file.name, filters) {
+                    tracing::warn!("ignoring file {} in torrent {}", file.name, torrent_hash);
+                    continue;
                 }
+
+                let file_id = file.id as i64;
+                let file_size = file.size as i64;
+
+                let file_id = sqlx::query_scalar!(
+                    "INSERT INTO torrent_files (torrent_id, path, debrid_id, size) VALUES (?, ?, ?, ?)
+                    ON CONFLICT(torrent_id, path) DO UPDATE SET debrid_id = excluded.debrid_id, size = excluded.size
+                    RETURNING id",
+                    local_torrent.id,
+                    file.name,
+                    file_id,
+                    file_size,
+                )
+                .fetch_one(tx.as_mut())
+                .await?;
+
+                if config.sanitize_node_names {
+                    // torrent_files keeps the original path, only the node names are sanitized
+                    let sanitized_file = DebridTorrentFile {
+                        id: file.id,
+                        name: sanitize_node_path(&file.name),
+                        size: file.size,
+                    };
+
+                    if create_nodes_for_file(&mut tx, local_torrent.id, &torrent_hash, file_id, &sanitized_file).await?
+                    {
+                        outcome.nodes_created += 1;
+                    }
+                } else if create_nodes_for_file(&mut tx, local_torrent.id, &torrent_hash, file_id, &file).await? {
+                    outcome.nodes_created += 1;
+                }
+            }
+
+            tx.commit().await?;
+        }
+    }
+
+    if next_state != TorrentState::try_from(local_torrent.state)? {
+        tracing::info!(
+            "torrent {} changed from {:?} to {:?}",
+            torrent_hash,
+            local_torrent.state,
+            next_state
+        );
+
+        outcome.new_state = Some(next_state);
+        match next_state {
+            TorrentState::Ready => {
+                record_event(db, EventKind::Ready, &torrent_hash, &local_torrent.name, None).await;
+            }
+            TorrentState::Error => {
+                let message = Some(error_message.unwrap_or("The debrid service reported an error"));
+                record_event(db, EventKind::Failed, &torrent_hash, &local_torrent.name, message).await;
             }
+            _ => {}
+        }
+    }
+
+    let debrid_id = debrid_torrent.id as i64;
+    let finished_at = if next_state == TorrentState::Ready {
+        Some(local_torrent.finished_at.unwrap_or_else(now_secs))
+    } else {
+        None
+    };
+
+    // cached torrents can be ready before torbox reports them as fully downloaded, clients
+    // won't import a completed torrent that isn't at 100%.
+    let progress = if next_state == TorrentState::Ready {
+        1.0
+    } else {
+        debrid_torrent.progress
+    };
+
+    let now = now_secs();
+    let eta = debrid_torrent.eta as i64;
+    let size = debrid_torrent.size as i64;
+    let seeds = debrid_torrent.seeds as i64;
+    let peers = debrid_torrent.peers as i64;
+    sqlx::query!(
+        "UPDATE torrents SET
+            name = COALESCE(name, ?),
+            state = ?,
+            debrid_id = ?,
+            progress = ?,
+            upload_speed = ?,
+            download_speed = ?,
+            seeds = ?,
+            peers = ?,
+            ratio = ?,
+            eta_secs = ?,
+            size = ?,
+            checked_at = ?,
+            finished_at = ?
+        WHERE id = ?
+    ",
+        dir_name,
+        next_state,
+        debrid_id,
+        progress,
+        debrid_torrent.upload_speed,
+        debrid_torrent.download_speed,
+        seeds,
+        peers,
+        debrid_torrent.ratio,
+        eta,
+        size,
+        now,
+        finished_at,
+        local_torrent.id,
+    )
+    .execute(db)
+    .await?;
+
+    outcome.errored = next_state == TorrentState::Error;
+    Ok(outcome)
+}
+
+/// Reconciles a single torrent without running a full pass, so torrents marked for removal are removed.
+/// Returns false if the torrent hasn't been added to the debrid service yet, adding torrents is left to the reconciler.
+pub async fn reconcile_torrent(
+    db: &SqlitePool,
+    debrid: &dyn DebridProvider,
+    config: &Config,
+    local_torrent: &LocalTorrent,
+) -> Result<bool> {
+    let Some(debrid_id) = local_torrent.debrid_id else {
+        return Ok(false);
+    };
+
+    let debrid_torrent = debrid.get_torrent_info(&(debrid_id as u32)).await?;
+    reconcile_one(db, debrid, config, local_torrent, debrid_torrent).await?;
+    Ok(true)
+}
+
+/// Checks a download link can be created for the first file of the torrent.
+async fn verify_download_link(debrid: &dyn DebridProvider, torrent: &DebridTorrent) -> Result<(), DebridError> {
+    let Some(file) = torrent.files.as_ref().and_then(|files| files.first()) else {
+        return Ok(());
+    };
+
+    debrid.get_download_link(torrent.id as i64, file.id as i64).await?;
+    Ok(())
+}
+
+fn get_next_state(next_state: TorrentState, download_present: bool, trust_download_present: bool) -> TorrentState {
+    if trust_download_present && download_present && next_state == TorrentState::Downloading {
+        // sometimes the download is present but the torrent state does not agree.
+        // this seems okay and speeds up torrent availability.
+        return TorrentState::Ready;
+    }
+
+    next_state
+}
+
+/// Returns the ids of torrents that have been downloading or ready for a while without a debrid id.
+/// Their files can never be read, so without this they would just ENOENT on every read.
+pub async fn find_stuck_torrents(db: &SqlitePool) -> Result<Vec<i64>> {
+    let cutoff = now_secs() - STUCK_TORRENT_AGE_SECS;
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM torrents WHERE debrid_id IS NULL AND state IN (?, ?) AND created_at < ?",
+        TorrentState::Downloading,
+        TorrentState::Ready,
+        cutoff
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(ids)
+}
+
+/// Counts stuck torrents, marking those older than `timeout_secs` as failed. Returns the number of
+/// torrents that are still stuck.
+async fn handle_stuck_torrents(db: &SqlitePool, timeout_secs: Option<u64>) -> Result<usize> {
+    let stuck = find_stuck_torrents(db).await?;
+    let Some(timeout_secs) = timeout_secs else {
+        return Ok(stuck.len());
+    };
+
+    let cutoff = now_secs() - timeout_secs as i64;
+    let error_message = "Torrent was never added to the debrid service".to_string();
+    let mut failed = 0;
+    for id in &stuck {
+        let torrent = sqlx::query!(
+            "UPDATE torrents SET state = ?, error_message = ? WHERE id = ? AND created_at < ? RETURNING hash, name",
+            TorrentState::Error,
+            error_message,
+            id,
+            cutoff
+        )
+        .fetch_optional(db)
+        .await?;
+
+        if let Some(torrent) = torrent {
+            tracing::error!("torrent {} has been stuck without a debrid id, marking as failed", id);
+            let torrent_hash = hex::encode(&torrent.hash);
+            record_event(
+                db,
+                EventKind::Failed,
+                &torrent_hash,
+                &torrent.name,
+                Some(&error_message),
+            )
+            .await;
+            failed += 1;
         }
     }
+
+    Ok(stuck.len() - failed)
+}
+
+/// Removes nodes whose file or torrent no longer exists. The foreign keys should cascade, but if they
+/// didn't (or the rows were removed with foreign keys disabled), these nodes would ENOENT on every read.
+async fn remove_orphaned_nodes(db: &SqlitePool) -> Result<u64> {
+    let result = sqlx::query!(
+        "DELETE FROM nodes WHERE
+            (file_id IS NOT NULL AND file_id NOT IN (SELECT id FROM torrent_files))
+            OR (torrent_id IS NOT NULL AND torrent_id NOT IN (SELECT id FROM torrents))"
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Creates a local record for a torrent that was added to the debrid service outside of lumin.
+async fn import_unmapped_torrent(db: &SqlitePool, hash: &[u8], torrent: &DebridTorrent) -> Result<()> {
+    let magnet_uri = format!(
+        "magnet:?xt=urn:btih:{}&dn={}",
+        hex::encode(hash),
+        urlencoding::encode(&torrent.name)
+    );
+
+    let debrid_id = torrent.id as i64;
+    let result = sqlx::query!(
+        "INSERT INTO torrents (hash, name, state, debrid_id, magnet_uri, source) VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (hash) DO NOTHING",
+        hash,
+        torrent.name,
+        TorrentState::Pending,
+        debrid_id,
+        magnet_uri,
+        TorrentSource::Unmapped
+    )
+    .execute(db)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        let message = format!("added via {}", TorrentSource::Unmapped.to_str());
+        record_event(db, EventKind::Added, &torrent.hash, &torrent.name, Some(&message)).await;
+    }
+
+    Ok(())
 }
 
+/// Creates the readonly nodes for a file in the downloads directory. If another torrent already has
+/// a file at the same path, the file is placed under a directory named after this torrent's hash instead.
 async fn create_nodes_for_file(
     pool: &mut Transaction<'_, Sqlite>,
     torrent_id: i64,
+    torrent_hash: &str,
     file_id: i64,
-    file: &TorboxTorrentFile,
-) -> Result<()> {
+    file: &DebridTorrentFile,
+) -> Result<bool> {
+    let existing = sqlx::query_scalar!("SELECT COUNT(*) FROM nodes WHERE file_id = ? AND readonly = 1", file_id)
+        .fetch_one(pool.as_mut())
+        .await?;
+
+    if existing > 0 {
+        return Ok(false);
+    }
+
     let parts = file.name.split('/').collect::<Vec<&str>>();
+    if create_nodes_for_path(pool, 2, &parts, torrent_id, file_id, file.size).await? {
+        return Ok(true);
+    }
+
+    tracing::warn!(
+        "file {} in torrent {} collides with a file from another torrent, namespacing it under {}",
+        file.name,
+        torrent_hash,
+        torrent_hash
+    );
+
+    let mut namespaced_parts = vec![torrent_hash];
+    namespaced_parts.extend(parts);
+    if !create_nodes_for_path(pool, 2, &namespaced_parts, torrent_id, file_id, file.size).await? {
+        tracing::error!(
+            "file {} in torrent {} still collides, skipping it",
+            file.name,
+            torrent_hash
+        );
+
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Returns false without creating the file node if something already exists at the path.
+async fn create_nodes_for_path(
+    pool: &mut Transaction<'_, Sqlite>,
+    root_id: i64,
+    parts: &[&str],
+    torrent_id: i64,
+    file_id: i64,
+    file_size: u64,
+) -> Result<bool> {
     let parts_len = parts.len();
-    let mut parent_id = 2; // downloads dir id
+    let mut parent_id = root_id;
     for (i, part) in parts.iter().enumerate() {
         let is_last = i == parts_len - 1;
         let name = <&str as ToString>::to_string(part);
 
         if is_last {
-            let size = file.size as i64;
-            sqlx::query!(
-                "INSERT INTO nodes (parent_id, name, readonly, size, file_id, torrent_id) VALUES (?, ?, 1, ?, ?, ?)",
+            let size = file_size as i64;
+            let result = sqlx::query!(
+                "INSERT INTO nodes (parent_id, name, readonly, size, file_id, torrent_id) VALUES (?, ?, 1, ?, ?, ?)
+                ON CONFLICT (parent_id, name) DO NOTHING",
                 parent_id,
                 name,
                 size,
@@ -313,6 +868,8 @@ async fn create_nodes_for_file(
             )
             .execute(pool.as_mut())
             .await?;
+
+            return Ok(result.rows_affected() > 0);
         } else {
             // we can't do nothing or exec with returning won't work (RETURNING only
             // works if a column is updated or inserted)
@@ -328,5 +885,827 @@ async fn create_nodes_for_file(
         }
     }
 
-    return Ok(());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_test_config;
+    use crate::debrid::{DebridCreatedTorrent, DebridInstantAvailability};
+    use crate::test_helpers::get_test_pool;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_reconciler_health() {
+        let health = ReconcilerHealth::default();
+        assert_eq!(
+            health.get_status(),
+            ReconcilerStatus {
+                last_pass_ms: 0,
+                average_pass_ms: 0,
+                lagging: false,
+            }
+        );
+
+        health.record_pass(Duration::from_secs(60));
+        assert_eq!(health.get_status().average_pass_ms, 60_000);
+
+        // a single slow pass moves the average without marking the reconciler as lagging
+        let slow_pass = Duration::from_secs(RECHECK_INTERVAL_SECS * 2);
+        health.record_pass(slow_pass);
+        let status = health.get_status();
+        assert_eq!(status.last_pass_ms, slow_pass.as_millis() as u64);
+        assert_eq!(status.average_pass_ms, 288_000);
+        assert!(!status.lagging);
+
+        for _ in 0..10 {
+            health.record_pass(slow_pass);
+        }
+        assert!(health.get_status().lagging);
+    }
+
+    /// Serves a fixed torrent list and records deletions instead of calling torbox.
+    #[derive(Default)]
+    struct MockDebrid {
+        torrents: Mutex<Vec<DebridTorrent>>,
+        deleted: Mutex<Vec<u64>>,
+        created: Mutex<Vec<String>>,
+        broken_links: bool,
+        link_requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DebridProvider for MockDebrid {
+        async fn get_torrent_list(&self, _use_cache: bool) -> Result<Vec<DebridTorrent>, DebridError> {
+            Ok(self.torrents.lock().unwrap().clone())
+        }
+
+        async fn get_torrent_info(&self, torrent_id: &u32) -> Result<DebridTorrent, DebridError> {
+            let torrents = self.torrents.lock().unwrap();
+            let torrent = torrents.iter().find(|t| t.id == *torrent_id as u64);
+            Ok(torrent.cloned().expect("unknown torrent id"))
+        }
+
+        async fn create_from_magnet(&self, magnet_uri: &str) -> Result<DebridCreatedTorrent, DebridError> {
+            let hash = magnet_uri.split("btih:").nth(1).expect("magnet without a hash");
+            let mut created = self.created.lock().unwrap();
+            let id = 100 + created.len() as u64;
+            created.push(magnet_uri.to_string());
+            self.torrents
+                .lock()
+                .unwrap()
+                .push(get_remote_torrent(id, hash, serde_json::Value::Null));
+
+            Ok(DebridCreatedTorrent { torrent_id: id as u32 })
+        }
+
+        async fn delete_torrent(&self, torrent_id: &u64) -> Result<(), DebridError> {
+            self.deleted.lock().unwrap().push(*torrent_id);
+            self.torrents.lock().unwrap().retain(|t| t.id != *torrent_id);
+            Ok(())
+        }
+
+        async fn check_cached(&self, _hashes: &[String]) -> Result<DebridInstantAvailability, DebridError> {
+            Ok(DebridInstantAvailability(HashMap::new()))
+        }
+
+        async fn get_download_link(&self, torrent_id: i64, file_id: i64) -> Result<String, DebridError> {
+            self.link_requests.fetch_add(1, Ordering::SeqCst);
+            if self.broken_links {
+                return Err(anyhow::anyhow!("DATABASE_ERROR").into());
+            }
+
+            Ok(format!("https://example.com/{}/{}", torrent_id, file_id))
+        }
+    }
+
+    fn get_remote_torrent(id: u64, hash: &str, files: serde_json::Value) -> DebridTorrent {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "hash": hash,
+            "seeds": 0,
+            "peers": 0,
+            "name": "Show",
+            "ratio": 0.0,
+            "progress": 1.0,
+            "download_speed": 0,
+            "active": false,
+            "eta": 0,
+            "size": 100,
+            "upload_speed": 0,
+            "download_state": "cached",
+            "download_present": true,
+            "files": files,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_remove_orphaned_nodes() {
+        let pool = get_test_pool(false).await;
+        sqlx::query!(
+            "INSERT INTO nodes (id, parent_id, name, readonly, file_id, torrent_id) VALUES (100, 1, 'orphan.mkv', 0, 42, 42)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let removed = remove_orphaned_nodes(&pool).await.unwrap();
+        assert_eq!(removed, 1);
+
+        // the root and downloads nodes have no file and must be left alone
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM nodes")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 2);
+    }
+
+    #[tokio::test]
+    async fn test_import_unmapped_torrent() {
+        let pool = get_test_pool(false).await;
+        let hash = hex::decode("1234567890abcdef1234567890abcdef12345678").unwrap();
+        let torrent: DebridTorrent = serde_json::from_value(serde_json::json!({
+            "id": 7,
+            "hash": "1234567890abcdef1234567890abcdef12345678",
+            "seeds": 0,
+            "peers": 0,
+            "name": "Some Show S01",
+            "ratio": 0.0,
+            "progress": 1.0,
+            "download_speed": 0,
+            "active": false,
+            "eta": 0,
+            "size": 100,
+            "upload_speed": 0,
+            "download_state": "cached",
+            "download_present": true,
+            "files": null,
+        }))
+        .unwrap();
+
+        import_unmapped_torrent(&pool, &hash, &torrent).await.unwrap();
+        // importing twice must not fail or create a duplicate
+        import_unmapped_torrent(&pool, &hash, &torrent).await.unwrap();
+
+        let imported = sqlx::query!(
+            r#"SELECT name, debrid_id, magnet_uri, state as "state: TorrentState", source as "source: TorrentSource"
+            FROM torrents WHERE hash = ?"#,
+            hash
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].name, "Some Show S01");
+        assert_eq!(imported[0].debrid_id, Some(7));
+        assert_eq!(imported[0].state, TorrentState::Pending);
+        assert_eq!(imported[0].source, TorrentSource::Unmapped);
+        assert_eq!(
+            imported[0].magnet_uri,
+            "magnet:?xt=urn:btih:1234567890abcdef1234567890abcdef12345678&dn=Some%20Show%20S01"
+        );
+
+        let events = crate::events::find_recent_events(&pool, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message.as_deref(), Some("added via unmapped"));
+    }
+
+    #[tokio::test]
+    async fn test_create_nodes_for_colliding_files() {
+        let pool = get_test_pool(false).await;
+        let file = DebridTorrentFile {
+            id: 0,
+            name: "Season 1/E01.mkv".to_string(),
+            size: 100,
+        };
+
+        let mut tx = pool.begin().await.unwrap();
+        create_nodes_for_file(&mut tx, 1, "aaaa", 10, &file).await.unwrap();
+        create_nodes_for_file(&mut tx, 2, "bbbb", 20, &file).await.unwrap();
+        // running again for an existing file must not create anything new
+        create_nodes_for_file(&mut tx, 1, "aaaa", 10, &file).await.unwrap();
+        create_nodes_for_file(&mut tx, 2, "bbbb", 20, &file).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let first_parent =
+            sqlx::query_scalar!("SELECT p.name FROM nodes n JOIN nodes p ON p.id = n.parent_id WHERE n.file_id = 10")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(first_parent, vec!["Season 1".to_string()]);
+
+        let second_grandparent = sqlx::query_scalar!(
+            "SELECT g.name FROM nodes n
+            JOIN nodes p ON p.id = n.parent_id
+            JOIN nodes g ON g.id = p.parent_id
+            WHERE n.file_id = 20"
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(second_grandparent, vec!["bbbb".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_ready_torrent_creates_nodes() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:')",
+            hash_bytes,
+            TorrentState::Pending
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let debrid = MockDebrid::default();
+        debrid.torrents.lock().unwrap().push(get_remote_torrent(
+            7,
+            hash,
+            serde_json::json!([{ "id": 0, "name": "Show/E01.mkv", "size": 100 }]),
+        ));
+
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+        let torrent = sqlx::query!(
+            r#"SELECT name, debrid_id, state as "state: TorrentState", finished_at FROM torrents WHERE hash = ?"#,
+            hash_bytes
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(torrent.state, TorrentState::Ready);
+        assert_eq!(torrent.name, "Show");
+        assert_eq!(torrent.debrid_id, Some(7));
+        assert!(torrent.finished_at.is_some());
+
+        let file_node = sqlx::query!(
+            "SELECT n.name, n.size, p.name as parent_name, p.parent_id as grandparent_id FROM nodes n
+            JOIN nodes p ON p.id = n.parent_id
+            WHERE n.file_id IS NOT NULL"
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(file_node.len(), 1);
+        assert_eq!(file_node[0].name, "E01.mkv");
+        assert_eq!(file_node[0].size, 100);
+        assert_eq!(file_node[0].parent_name, "Show");
+        assert_eq!(file_node[0].grandparent_id, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_ready_torrent_reports_complete() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:')",
+            hash_bytes,
+            TorrentState::Pending
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // cached on torbox, but still reported as downloading
+        let mut remote_torrent = get_remote_torrent(7, hash, serde_json::Value::Null);
+        remote_torrent.state = TorrentState::Downloading;
+        remote_torrent.progress = 0.5;
+        let debrid = MockDebrid::default();
+        debrid.torrents.lock().unwrap().push(remote_torrent);
+
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+        let torrent = sqlx::query!(
+            r#"SELECT state as "state: TorrentState", progress FROM torrents WHERE hash = ?"#,
+            hash_bytes
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(torrent.state, TorrentState::Ready);
+        assert_eq!(torrent.progress, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_single_torrent() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let other_hash = "abcdef1234567890abcdef1234567890abcdef12";
+        for (hash, debrid_id) in [(hash, Some(7)), (other_hash, None)] {
+            let hash_bytes = hex::decode(hash).unwrap();
+            sqlx::query!(
+                "INSERT INTO torrents (hash, name, state, magnet_uri, debrid_id) VALUES (?, 'Show', ?, 'magnet:', ?)",
+                hash_bytes,
+                TorrentState::Downloading,
+                debrid_id
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let debrid = MockDebrid::default();
+        debrid.torrents.lock().unwrap().push(get_remote_torrent(
+            7,
+            hash,
+            serde_json::json!([{ "id": 0, "name": "Show/E01.mkv", "size": 100 }]),
+        ));
+
+        let torrent = find_local_torrent(&pool, 1).await.unwrap().unwrap();
+        assert!(reconcile_torrent(&pool, &debrid, &config, &torrent).await.unwrap());
+
+        let torrents = sqlx::query!(r#"SELECT id, state as "state: TorrentState" FROM torrents ORDER BY id"#)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(torrents[0].state, TorrentState::Ready);
+        // the other torrent is left for the next pass, it isn't added to the debrid service
+        assert_eq!(torrents[1].state, TorrentState::Downloading);
+        assert!(debrid.created.lock().unwrap().is_empty());
+
+        let files = sqlx::query_scalar!("SELECT path FROM torrent_files WHERE torrent_id = 1")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(files, vec!["Show/E01.mkv"]);
+
+        let other = find_local_torrent(&pool, 2).await.unwrap().unwrap();
+        assert!(!reconcile_torrent(&pool, &debrid, &config, &other).await.unwrap());
+        assert!(find_local_torrent(&pool, 3).await.unwrap().is_none());
+
+        // a torrent marked for removal is removed rather than brought back
+        sqlx::query!(
+            "UPDATE torrents SET state = ?, hidden = 1 WHERE id = 1",
+            TorrentState::Removing
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let torrent = find_local_torrent(&pool, 1).await.unwrap().unwrap();
+        assert!(reconcile_torrent(&pool, &debrid, &config, &torrent).await.unwrap());
+        assert_eq!(*debrid.deleted.lock().unwrap(), vec![7]);
+        assert!(find_local_torrent(&pool, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_one() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hashes = [
+            "1234567890abcdef1234567890abcdef12345678",
+            "abcdef1234567890abcdef1234567890abcdef12",
+        ];
+        for hash in hashes {
+            let hash_bytes = hex::decode(hash).unwrap();
+            sqlx::query!(
+                "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:')",
+                hash_bytes,
+                TorrentState::Downloading
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let debrid = MockDebrid::default();
+        let files = serde_json::json!([
+            { "id": 0, "name": "Show/E01.mkv", "size": 100 },
+            { "id": 1, "name": "Show/E02.mkv", "size": 100 },
+        ]);
+        let remote_torrent = get_remote_torrent(7, hashes[0], files);
+        debrid.torrents.lock().unwrap().push(remote_torrent.clone());
+
+        let torrent = find_local_torrent(&pool, 1).await.unwrap().unwrap();
+        let outcome = reconcile_one(&pool, &debrid, &config, &torrent, remote_torrent.clone())
+            .await
+            .unwrap();
+        let expected = ReconcileOutcome {
+            new_state: Some(TorrentState::Ready),
+            nodes_created: 2,
+            ..Default::default()
+        };
+        assert_eq!(outcome, expected);
+
+        // nothing changes on the next pass
+        let torrent = find_local_torrent(&pool, 1).await.unwrap().unwrap();
+        let outcome = reconcile_one(&pool, &debrid, &config, &torrent, remote_torrent.clone())
+            .await
+            .unwrap();
+        assert_eq!(outcome, ReconcileOutcome::default());
+
+        // torrents marked for removal are deleted from the debrid service
+        let mut torrent = find_local_torrent(&pool, 1).await.unwrap().unwrap();
+        torrent.state = TorrentState::Removing;
+        let outcome = reconcile_one(&pool, &debrid, &config, &torrent, remote_torrent)
+            .await
+            .unwrap();
+        assert!(outcome.removed);
+        assert_eq!(*debrid.deleted.lock().unwrap(), vec![7]);
+        assert!(find_local_torrent(&pool, 1).await.unwrap().is_none());
+
+        let files = serde_json::json!([{ "id": 0, "name": "Show/setup.exe", "size": 100 }]);
+        let remote_torrent = get_remote_torrent(8, hashes[1], files);
+        let torrent = find_local_torrent(&pool, 2).await.unwrap().unwrap();
+        let outcome = reconcile_one(&pool, &debrid, &config, &torrent, remote_torrent)
+            .await
+            .unwrap();
+        let expected = ReconcileOutcome {
+            new_state: Some(TorrentState::Error),
+            errored: true,
+            ..Default::default()
+        };
+        assert_eq!(outcome, expected);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_torrent_gains_valid_files() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:')",
+            hash_bytes,
+            TorrentState::Pending
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let debrid = MockDebrid::default();
+        debrid.torrents.lock().unwrap().push(get_remote_torrent(
+            7,
+            hash,
+            serde_json::json!([{ "id": 0, "name": "Show/setup.exe", "size": 100 }]),
+        ));
+
+        let get_torrent = || {
+            sqlx::query!(
+                r#"SELECT state as "state: TorrentState", error_message, empty_file_checks FROM torrents WHERE hash = ?"#,
+                hash_bytes
+            )
+            .fetch_one(&pool)
+        };
+
+        let mut download_limit = config.download_slots;
+        for _ in 0..2 {
+            reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        }
+
+        let torrent = get_torrent().await.unwrap();
+        assert_eq!(torrent.state, TorrentState::Error);
+        assert_eq!(torrent.error_message.as_deref(), Some("Torrent has no valid files"));
+        assert_eq!(torrent.empty_file_checks, 2);
+
+        let file = DebridTorrentFile {
+            id: 1,
+            name: "Show/E01.mkv".to_string(),
+            size: 100,
+        };
+        debrid.torrents.lock().unwrap()[0].files.as_mut().unwrap().push(file);
+
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        let torrent = get_torrent().await.unwrap();
+        assert_eq!(torrent.state, TorrentState::Ready);
+        assert_eq!(torrent.error_message, None);
+        assert_eq!(torrent.empty_file_checks, 0);
+
+        let nodes = sqlx::query_scalar!("SELECT COUNT(*) FROM nodes WHERE file_id IS NOT NULL")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(nodes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_stops_checking_for_valid_files() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri, empty_file_checks) VALUES (?, 'Show', ?, 'magnet:', ?)",
+            hash_bytes,
+            TorrentState::Error,
+            MAX_EMPTY_FILE_CHECKS
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let debrid = MockDebrid::default();
+        debrid.torrents.lock().unwrap().push(get_remote_torrent(
+            7,
+            hash,
+            serde_json::json!([{ "id": 0, "name": "Show/E01.mkv", "size": 100 }]),
+        ));
+
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+        let state = sqlx::query_scalar!(r#"SELECT state as "state: TorrentState" FROM torrents"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Error);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_removes_unreferenced_torrent() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri, hidden, created_at) VALUES (?, 'Show', ?, 'magnet:', 1, 0)",
+            hash_bytes,
+            TorrentState::Ready
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let debrid = MockDebrid::default();
+        debrid
+            .torrents
+            .lock()
+            .unwrap()
+            .push(get_remote_torrent(7, hash, serde_json::Value::Null));
+
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+        assert_eq!(*debrid.deleted.lock().unwrap(), vec![7]);
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM torrents")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_keeps_new_unreferenced_torrent() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[("min_torrent_age_secs", "300")]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri, hidden) VALUES (?, 'Show', ?, 'magnet:', 1)",
+            hash_bytes,
+            TorrentState::Ready
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let debrid = MockDebrid::default();
+        debrid
+            .torrents
+            .lock()
+            .unwrap()
+            .push(get_remote_torrent(7, hash, serde_json::Value::Null));
+
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+        assert!(debrid.deleted.lock().unwrap().is_empty());
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM torrents")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_missing_remote_torrent() {
+        let pool = get_test_pool(false).await;
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        let magnet_uri = format!("magnet:?xt=urn:btih:{}", hash);
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri, debrid_id) VALUES (?, 'Show', ?, ?, 7)",
+            hash_bytes,
+            TorrentState::Ready,
+            magnet_uri
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // by default the torrent is added again
+        let config = get_test_config(&[]);
+        let debrid = MockDebrid::default();
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        assert_eq!(*debrid.created.lock().unwrap(), vec![magnet_uri.clone()]);
+        let debrid_id = sqlx::query_scalar!("SELECT debrid_id FROM torrents WHERE hash = ?", hash_bytes)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(debrid_id, Some(100));
+
+        // once it has been deleted on the debrid service again, it's removed locally too
+        let config = get_test_config(&[("honor_remote_deletion", "true")]);
+        let debrid = MockDebrid::default();
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        assert!(debrid.created.lock().unwrap().is_empty());
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM torrents")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_honor_remote_deletion_new_torrent() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[("honor_remote_deletion", "true")]);
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let hash_bytes = hex::decode(hash).unwrap();
+        let magnet_uri = format!("magnet:?xt=urn:btih:{}", hash);
+        sqlx::query!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, ?)",
+            hash_bytes,
+            TorrentState::Pending,
+            magnet_uri
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // torrents that were never added to the debrid service are still added
+        let debrid = MockDebrid::default();
+        let mut download_limit = config.download_slots;
+        reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        assert_eq!(*debrid.created.lock().unwrap(), vec![magnet_uri]);
+        let state = sqlx::query_scalar!(
+            r#"SELECT state as "state: TorrentState" FROM torrents WHERE hash = ?"#,
+            hash_bytes
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(state, TorrentState::Ready);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_max_creates_per_pass() {
+        let pool = get_test_pool(false).await;
+        let config = get_test_config(&[("max_creates_per_pass", "2")]);
+        for hash in [
+            "1111111111111111111111111111111111111111",
+            "2222222222222222222222222222222222222222",
+            "3333333333333333333333333333333333333333",
+        ] {
+            let hash_bytes = hex::decode(hash).unwrap();
+            let magnet_uri = format!("magnet:?xt=urn:btih:{}", hash);
+            sqlx::query!(
+                "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, ?)",
+                hash_bytes,
+                TorrentState::Pending,
+                magnet_uri
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let debrid = MockDebrid::default();
+        let mut download_limit = config.download_slots;
+        let recheck = reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        assert!(recheck);
+        assert_eq!(debrid.created.lock().unwrap().len(), 2);
+
+        // the deferred torrent is added on the next pass
+        let recheck = reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+        assert!(!recheck);
+        assert_eq!(debrid.created.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_handle_stuck_torrents() {
+        let pool = get_test_pool(false).await;
+        let now = now_secs();
+        // a long stuck torrent, a recently stuck torrent, a new torrent and a torrent that was added
+        let torrents = [
+            (&[1u8][..], TorrentState::Ready, None, now - 24 * 60 * 60),
+            (&[2u8][..], TorrentState::Downloading, None, now - 60 * 60),
+            (&[3u8][..], TorrentState::Ready, None, now),
+            (&[4u8][..], TorrentState::Ready, Some(7), 0),
+        ];
+
+        for (hash, state, debrid_id, created_at) in torrents {
+            sqlx::query(
+                "INSERT INTO torrents (hash, name, state, magnet_uri, debrid_id, created_at)
+                VALUES (?, 'Show', ?, 'magnet:', ?, ?)",
+            )
+            .bind(hash)
+            .bind(state)
+            .bind(debrid_id)
+            .bind(created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(find_stuck_torrents(&pool).await.unwrap().len(), 2);
+        assert_eq!(handle_stuck_torrents(&pool, None).await.unwrap(), 2);
+        assert_eq!(handle_stuck_torrents(&pool, Some(2 * 60 * 60)).await.unwrap(), 1);
+
+        let failed = sqlx::query_scalar!(r#"SELECT hash FROM torrents WHERE state = ?"#, TorrentState::Error)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failed, vec![vec![1u8]]);
+    }
+
+    #[test]
+    fn test_get_next_state() {
+        assert_eq!(
+            get_next_state(TorrentState::Downloading, true, true),
+            TorrentState::Ready
+        );
+        assert_eq!(
+            get_next_state(TorrentState::Downloading, true, false),
+            TorrentState::Downloading
+        );
+        assert_eq!(
+            get_next_state(TorrentState::Downloading, false, true),
+            TorrentState::Downloading
+        );
+        assert_eq!(get_next_state(TorrentState::Ready, false, false), TorrentState::Ready);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_verify_on_ready() {
+        for broken_links in [false, true] {
+            let pool = get_test_pool(false).await;
+            let config = get_test_config(&[("verify_on_ready", "true")]);
+            let hash = "1234567890abcdef1234567890abcdef12345678";
+            let hash_bytes = hex::decode(hash).unwrap();
+            sqlx::query!(
+                "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:')",
+                hash_bytes,
+                TorrentState::Pending
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+
+            let debrid = MockDebrid {
+                broken_links,
+                ..Default::default()
+            };
+            debrid.torrents.lock().unwrap().push(get_remote_torrent(
+                7,
+                hash,
+                serde_json::json!([{ "id": 0, "name": "Show/E01.mkv", "size": 100 }]),
+            ));
+
+            let mut download_limit = config.download_slots;
+            reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+
+            let state = sqlx::query_scalar!(r#"SELECT state as "state: TorrentState" FROM torrents"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            let nodes = sqlx::query_scalar!("SELECT COUNT(*) FROM nodes WHERE file_id IS NOT NULL")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+            if broken_links {
+                assert_eq!(state, TorrentState::Error);
+                assert_eq!(nodes, 0);
+
+                // the failed check isn't repeated, so there's only one failed event
+                reconcile(&pool, &debrid, &config, &mut download_limit).await.unwrap();
+                let failed_events =
+                    sqlx::query_scalar!("SELECT COUNT(*) FROM events WHERE kind = ?", EventKind::Failed)
+                        .fetch_one(&pool)
+                        .await
+                        .unwrap();
+                assert_eq!(failed_events, 1);
+                assert_eq!(debrid.link_requests.load(Ordering::SeqCst), 1);
+            } else {
+                assert_eq!(state, TorrentState::Ready);
+                assert_eq!(nodes, 1);
+            }
+        }
+    }
 }
@@ -0,0 +1,951 @@
+use crate::AppState;
+use crate::config::get_config;
+use crate::error::RpcError;
+use crate::events::find_recent_events;
+use crate::helpers::get_unique_name::get_unique_name;
+use crate::helpers::parse_magnet_uri::parse_magnet_uri;
+use crate::mount::list_dir;
+use crate::qbittorrent::is_known_category;
+use crate::qbittorrent::torrent::Torrent;
+use crate::reconciler::{ReconcilerStatus, find_local_torrent, find_stuck_torrents};
+use crate::state::{TorrentSource, TorrentState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Deserialize)]
+struct AddTorrentToFolderRequest {
+    pub torrent_id: i64,
+    pub parent_node_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedNode {
+    pub id: i64,
+    pub name: String,
+}
+
+/// Creates a writable node under `parent_node_id` for each file in the torrent,
+/// the same as hardlinking each file into the folder by hand.
+async fn add_torrent_to_folder(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<AddTorrentToFolderRequest>,
+) -> Result<Response, RpcError> {
+    let created = link_torrent_files(&state.pool, request).await?;
+    Ok(Json(created).into_response())
+}
+
+async fn link_torrent_files(
+    pool: &SqlitePool,
+    request: AddTorrentToFolderRequest,
+) -> Result<Vec<CreatedNode>, RpcError> {
+    let mut tx = pool.begin().await?;
+    let parent = sqlx::query!(
+        "SELECT id, file_id, readonly FROM nodes WHERE id = ?",
+        request.parent_node_id
+    )
+    .fetch_optional(tx.as_mut())
+    .await?;
+
+    let Some(parent) = parent else {
+        return Err(RpcError::not_found("Parent node not found"));
+    };
+
+    if parent.file_id.is_some() {
+        return Err(RpcError::bad_request("Parent node is not a directory"));
+    }
+
+    if parent.readonly == 1 && parent.id != 1 {
+        return Err(RpcError::bad_request("Parent node is read-only"));
+    }
+
+    let files = sqlx::query!(
+        "SELECT id, path, size FROM torrent_files WHERE torrent_id = ? AND priority != 0 ORDER BY id",
+        request.torrent_id
+    )
+    .fetch_all(tx.as_mut())
+    .await?;
+
+    if files.is_empty() {
+        return Err(RpcError::not_found("Torrent not found or has no files"));
+    }
+
+    let mut taken: HashSet<String> = sqlx::query_scalar!("SELECT name FROM nodes WHERE parent_id = ?", parent.id)
+        .fetch_all(tx.as_mut())
+        .await?
+        .into_iter()
+        .collect();
+
+    let mut created = Vec::with_capacity(files.len());
+    for file in files {
+        let file_name = file.path.rsplit('/').next().unwrap_or(&file.path);
+        let name = get_unique_name(file_name, &taken);
+        let id = sqlx::query_scalar!(
+            "INSERT INTO nodes (parent_id, name, size, file_id, torrent_id, readonly) VALUES (?, ?, ?, ?, ?, 0)
+            RETURNING id",
+            parent.id,
+            name,
+            file.size,
+            file.id,
+            request.torrent_id
+        )
+        .fetch_one(tx.as_mut())
+        .await?;
+
+        taken.insert(name.clone());
+        created.push(CreatedNode { id, name });
+    }
+
+    tx.commit().await?;
+    Ok(created)
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckAvailabilityRequest {
+    pub magnets: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MagnetAvailability {
+    pub magnet: String,
+    pub hash: Option<String>,
+    pub v2_hash: Option<String>,
+    pub name: Option<String>,
+    pub cached: bool,
+    pub error: Option<String>,
+}
+
+/// Reports whether each magnet is cached on the debrid service without adding anything.
+async fn check_availability(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CheckAvailabilityRequest>,
+) -> Result<Response, RpcError> {
+    let parsed = request
+        .magnets
+        .iter()
+        .map(|magnet| parse_magnet_uri(magnet))
+        .collect::<Vec<_>>();
+
+    let hashes = parsed
+        .iter()
+        .flatten()
+        .map(|meta| meta.hash.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    let cached = if hashes.is_empty() {
+        Default::default()
+    } else {
+        state.debrid.check_cached(&hashes).await?.0
+    };
+
+    let results = request
+        .magnets
+        .into_iter()
+        .zip(parsed)
+        .map(|(magnet, meta)| match meta {
+            Some(meta) => {
+                let data = cached.get(&meta.hash);
+                MagnetAvailability {
+                    magnet,
+                    name: data.map(|d| d.name.clone()).or(meta.name),
+                    hash: Some(meta.hash),
+                    v2_hash: meta.v2_hash,
+                    cached: data.is_some(),
+                    error: None,
+                }
+            }
+            None => MagnetAvailability {
+                magnet,
+                hash: None,
+                v2_hash: None,
+                name: None,
+                cached: false,
+                error: Some("Invalid magnet URI".to_string()),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(results).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct LibraryStats {
+    pub torrents_by_state: HashMap<String, i64>,
+    pub total_torrents: i64,
+    pub ready_torrents: i64,
+    pub pending_torrents: i64,
+    /// Torrents that are taking up a download slot on the debrid service.
+    pub active_torrents: i64,
+    pub total_files: i64,
+    pub total_size_bytes: i64,
+    pub cached_bytes: u64,
+    /// Torrents that are downloading or ready but were never added to the debrid service.
+    pub stuck_torrents: usize,
+    /// Free download request slots, downloads are queued behind each other when this is 0.
+    pub available_download_permits: usize,
+    /// Milliseconds since the unix epoch until the debrid ratelimit lets downloads start again.
+    pub ratelimited_until: Option<u64>,
+    /// The request budget last reported by the debrid service.
+    pub remaining_request_budget: Option<u64>,
+    /// How long reconcile passes are taking, and whether they're falling behind the recheck interval.
+    pub reconciler: ReconcilerStatus,
+}
+
+async fn get_library_stats(State(state): State<Arc<AppState>>) -> Result<Response, RpcError> {
+    let state_counts = sqlx::query!(
+        r#"SELECT state as "state: TorrentState", COUNT(*) as "count!: i64" FROM torrents GROUP BY state"#
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let files =
+        sqlx::query!(r#"SELECT COUNT(*) as "count!: i64", COALESCE(SUM(size), 0) as "size!: i64" FROM torrent_files"#)
+            .fetch_one(&state.pool)
+            .await?;
+
+    let count_for = |states: &[TorrentState]| -> i64 {
+        state_counts
+            .iter()
+            .filter(|row| states.contains(&row.state))
+            .map(|row| row.count)
+            .sum()
+    };
+
+    let stats = LibraryStats {
+        torrents_by_state: state_counts
+            .iter()
+            .map(|row| (format!("{:?}", row.state).to_lowercase(), row.count))
+            .collect(),
+        total_torrents: state_counts.iter().map(|row| row.count).sum(),
+        ready_torrents: count_for(&[TorrentState::Ready]),
+        pending_torrents: count_for(&[TorrentState::Pending, TorrentState::Queued]),
+        active_torrents: count_for(&[TorrentState::Downloading, TorrentState::Stalled]),
+        total_files: files.count,
+        total_size_bytes: files.size,
+        cached_bytes: state.cache.get_cached_bytes(),
+        stuck_torrents: find_stuck_torrents(&state.pool).await?.len(),
+        available_download_permits: state.cache.get_ratelimiter().available_permits(),
+        ratelimited_until: state.cache.get_ratelimiter().ratelimited_until(),
+        remaining_request_budget: state.cache.get_ratelimiter().remaining_budget(),
+        reconciler: state.reconciler_health.get_status(),
+    };
+
+    Ok(Json(stats).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct TorrentSummary {
+    pub id: i64,
+    pub hash: String,
+    pub name: String,
+    pub state: TorrentState,
+    pub progress: f64,
+    pub size: i64,
+    pub category: Option<String>,
+    pub error_message: Option<String>,
+    pub source: TorrentSource,
+}
+
+async fn get_torrents(State(state): State<Arc<AppState>>) -> Result<Response, RpcError> {
+    let torrents = find_torrents(&state.pool).await?;
+    Ok(Json(torrents).into_response())
+}
+
+async fn find_torrents(pool: &SqlitePool) -> Result<Vec<TorrentSummary>, sqlx::Error> {
+    let torrents = sqlx::query_as::<_, Torrent>("SELECT * FROM torrents WHERE hidden = 0 ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(torrents
+        .into_iter()
+        .map(|torrent| TorrentSummary {
+            error_message: torrent.get_error_message(),
+            id: torrent.id,
+            hash: hex::encode(&torrent.hash),
+            name: torrent.name,
+            state: torrent.state,
+            progress: torrent.progress,
+            size: torrent.size,
+            category: torrent.category,
+            source: torrent.source,
+        })
+        .collect())
+}
+
+const DEFAULT_LIST_NODES_LIMIT: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct ListNodesRequest {
+    /// Defaults to the root node.
+    pub parent_id: Option<i64>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeSummary {
+    pub id: i64,
+    pub name: String,
+    pub size: i64,
+    pub is_dir: bool,
+    pub file_id: Option<i64>,
+    pub torrent_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeList {
+    pub id: i64,
+    /// The parent of the listed node, `None` for the root.
+    pub parent_id: Option<i64>,
+    /// The number of children before paging.
+    pub total: usize,
+    pub nodes: Vec<NodeSummary>,
+}
+
+async fn list_nodes(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ListNodesRequest>,
+) -> Result<Response, RpcError> {
+    let list = find_nodes(&state.pool, request).await?;
+    Ok(Json(list).into_response())
+}
+
+/// Lists the children of a node the same way the mount does, so hidden files are left out here too.
+async fn find_nodes(pool: &SqlitePool, request: ListNodesRequest) -> Result<NodeList, RpcError> {
+    let (node, children) = match list_dir(pool, request.parent_id.unwrap_or(1)).await {
+        Ok(result) => result,
+        Err(e) if e == libc::ENOENT.into() => return Err(RpcError::not_found("Node not found")),
+        Err(e) if e == libc::ENOTDIR.into() => return Err(RpcError::bad_request("Node is not a directory")),
+        Err(_) => return Err(anyhow::anyhow!("Failed to list nodes").into()),
+    };
+
+    let total = children.len();
+    let nodes = children
+        .into_iter()
+        .skip(request.offset.unwrap_or(0))
+        .take(request.limit.unwrap_or(DEFAULT_LIST_NODES_LIMIT))
+        .map(|child| NodeSummary {
+            is_dir: child.file_id.is_none(),
+            id: child.id,
+            name: child.name,
+            size: child.size,
+            file_id: child.file_id,
+            torrent_id: child.torrent_id,
+        })
+        .collect();
+
+    Ok(NodeList {
+        id: node.id,
+        parent_id: node.parent_id,
+        total,
+        nodes,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct SetTorrentCategoryRequest {
+    pub torrent_id: i64,
+    /// `None` or an empty string removes the category.
+    pub category: Option<String>,
+}
+
+async fn set_torrent_category(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SetTorrentCategoryRequest>,
+) -> Result<Response, RpcError> {
+    update_torrent_category(&state.pool, request, &get_config().categories).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct ReconcileTorrentRequest {
+    pub torrent_id: i64,
+}
+
+/// Reconciles a single torrent right away, instead of waiting for the next reconciler pass.
+async fn reconcile_torrent(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ReconcileTorrentRequest>,
+) -> Result<Response, RpcError> {
+    let Some(torrent) = find_local_torrent(&state.pool, request.torrent_id).await? else {
+        return Err(RpcError::not_found("Torrent not found"));
+    };
+
+    if !crate::reconciler::reconcile_torrent(&state.pool, state.debrid.as_ref(), get_config(), &torrent).await? {
+        return Err(RpcError::bad_request(
+            "Torrent hasn't been added to the debrid service yet",
+        ));
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Same rules as the qbittorrent `setCategory` endpoint, only configured categories are allowed.
+async fn update_torrent_category(
+    pool: &SqlitePool,
+    request: SetTorrentCategoryRequest,
+    categories: &[String],
+) -> Result<(), RpcError> {
+    let category = request.category.filter(|c| !c.is_empty());
+    if category.as_ref().is_some_and(|c| !is_known_category(c, categories)) {
+        return Err(RpcError::bad_request("Unknown category"));
+    }
+
+    let result = sqlx::query!(
+        "UPDATE torrents SET category = ? WHERE id = ? AND hidden = 0",
+        category,
+        request.torrent_id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(RpcError::not_found("Torrent not found"));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct CategorySummary {
+    pub name: String,
+    pub torrent_count: i64,
+}
+
+async fn list_categories(State(state): State<Arc<AppState>>) -> Result<Response, RpcError> {
+    let categories = find_categories(&state.pool, &get_config().categories).await?;
+    Ok(Json(categories).into_response())
+}
+
+/// Lists the configured categories in order, with how many visible torrents are in each.
+async fn find_categories(pool: &SqlitePool, categories: &[String]) -> Result<Vec<CategorySummary>, sqlx::Error> {
+    let counts: HashMap<String, i64> = sqlx::query!(
+        r#"SELECT category as "category!", COUNT(*) as "count!: i64" FROM torrents
+        WHERE hidden = 0 AND category IS NOT NULL GROUP BY category"#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.category, row.count))
+    .collect();
+
+    Ok(categories
+        .iter()
+        .map(|name| CategorySummary {
+            torrent_count: counts.get(name).copied().unwrap_or(0),
+            name: name.clone(),
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct EvictFileCacheRequest {
+    pub file_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct EvictTorrentCacheRequest {
+    pub torrent_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct EvictedCache {
+    pub removed_bytes: u64,
+}
+
+/// Frees the cached data for a file, the file stays in the library and is downloaded again when it's read.
+async fn evict_file_cache(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EvictFileCacheRequest>,
+) -> Result<Response, RpcError> {
+    let file = sqlx::query_scalar!("SELECT id FROM torrent_files WHERE id = ?", request.file_id)
+        .fetch_optional(&state.pool)
+        .await?;
+
+    let Some(file_id) = file else {
+        return Err(RpcError::not_found("File not found"));
+    };
+
+    let removed_bytes = state.cache.evict_files(&[file_id]).await?;
+    Ok(Json(EvictedCache { removed_bytes }).into_response())
+}
+
+/// Frees the cached data for every file in a torrent, like `evict_file_cache`.
+async fn evict_torrent_cache(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EvictTorrentCacheRequest>,
+) -> Result<Response, RpcError> {
+    let file_ids = sqlx::query_scalar!("SELECT id FROM torrent_files WHERE torrent_id = ?", request.torrent_id)
+        .fetch_all(&state.pool)
+        .await?;
+
+    if file_ids.is_empty() {
+        return Err(RpcError::not_found("Torrent not found or has no files"));
+    }
+
+    let removed_bytes = state.cache.evict_files(&file_ids).await?;
+    Ok(Json(EvictedCache { removed_bytes }).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetDurationHintRequest {
+    pub file_id: i64,
+    /// The playback duration of the file, None or 0 clears the hint.
+    pub duration_secs: Option<u64>,
+}
+
+/// Sets the duration read ahead uses to estimate how fast a file will be played, for files where
+/// the duration is known from somewhere else (like the metadata of a media server).
+async fn set_duration_hint(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SetDurationHintRequest>,
+) -> Result<Response, RpcError> {
+    let file_id = request.file_id;
+    let duration_secs = update_duration_hint(&state.pool, request).await?;
+    if let Some(entry) = state.cache.get_entry(file_id) {
+        entry.set_duration_hint_secs(duration_secs);
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+async fn update_duration_hint(pool: &SqlitePool, request: SetDurationHintRequest) -> Result<Option<u64>, RpcError> {
+    let duration_secs = request.duration_secs.filter(|&secs| secs > 0);
+    let duration = duration_secs.map(|secs| secs as i64);
+    let result = sqlx::query!(
+        "UPDATE torrent_files SET duration_hint_secs = ? WHERE id = ?",
+        duration,
+        request.file_id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(RpcError::not_found("File not found"));
+    }
+
+    Ok(duration_secs)
+}
+
+#[derive(Debug, Deserialize)]
+struct DebugNodeRequest {
+    pub node_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeDebugInfo {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub name: String,
+    pub size: i64,
+    pub readonly: bool,
+    /// The path of the node relative to the mount.
+    pub path: String,
+    pub file: Option<FileDebugInfo>,
+    pub torrent: Option<TorrentDebugInfo>,
+    /// `None` if the file has never been opened since startup, or the node is a directory.
+    pub cache: Option<CacheDebugInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileDebugInfo {
+    pub id: i64,
+    pub debrid_id: i64,
+    pub path: String,
+    pub size: i64,
+    pub priority: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TorrentDebugInfo {
+    pub id: i64,
+    pub hash: String,
+    pub name: String,
+    pub state: TorrentState,
+    pub hidden: bool,
+    pub debrid_id: Option<i64>,
+    pub error_message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheDebugInfo {
+    pub total_chunks: usize,
+    pub cached_chunks: usize,
+    pub downloading_chunks: usize,
+    pub cached_bytes: u64,
+}
+
+/// Everything needed to figure out why a file isn't showing up or can't be read, only available
+/// with `debug_rpc` enabled.
+async fn debug_node(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<DebugNodeRequest>,
+) -> Result<Response, RpcError> {
+    let mut info = find_node_debug_info(&state.pool, request.node_id).await?;
+    if let Some(file) = &info.file
+        && let Some(entry) = state.cache.get_entry(file.id)
+    {
+        let chunks = entry.get_chunks();
+        let cached = chunks.iter().filter(|c| c.cached.load(Ordering::Relaxed));
+        info.cache = Some(CacheDebugInfo {
+            total_chunks: chunks.len(),
+            cached_chunks: cached.clone().count(),
+            downloading_chunks: chunks.iter().filter(|c| c.downloading.try_lock().is_err()).count(),
+            cached_bytes: cached.map(|c| c.size).sum(),
+        });
+    }
+
+    Ok(Json(info).into_response())
+}
+
+async fn find_node_debug_info(pool: &SqlitePool, node_id: i64) -> Result<NodeDebugInfo, RpcError> {
+    let node = sqlx::query!(
+        "SELECT id, parent_id, name, size, readonly, file_id, torrent_id FROM nodes WHERE id = ?",
+        node_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(node) = node else {
+        return Err(RpcError::not_found("Node not found"));
+    };
+
+    let names = sqlx::query_scalar!(
+        r#"WITH RECURSIVE ancestors(id, parent_id, name, depth) AS (
+            SELECT id, parent_id, name, 0 FROM nodes WHERE id = ?
+            UNION ALL
+            SELECT n.id, n.parent_id, n.name, a.depth + 1 FROM nodes n JOIN ancestors a ON n.id = a.parent_id
+        )
+        SELECT name as "name!: String" FROM ancestors WHERE parent_id IS NOT NULL ORDER BY depth DESC"#,
+        node_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let file = sqlx::query_as!(
+        FileDebugInfo,
+        "SELECT id, debrid_id, path, size, priority FROM torrent_files WHERE id = ?",
+        node.file_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let torrent = sqlx::query!(
+        r#"SELECT id, hash, name, state as "state: TorrentState", hidden, debrid_id, error_message
+        FROM torrents WHERE id = ?"#,
+        node.torrent_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|torrent| TorrentDebugInfo {
+        id: torrent.id,
+        hash: hex::encode(&torrent.hash),
+        name: torrent.name,
+        state: torrent.state,
+        hidden: torrent.hidden != 0,
+        debrid_id: torrent.debrid_id,
+        error_message: torrent.error_message,
+    });
+
+    Ok(NodeDebugInfo {
+        id: node.id,
+        parent_id: node.parent_id,
+        name: node.name,
+        size: node.size,
+        readonly: node.readonly != 0,
+        path: format!("/{}", names.join("/")),
+        file,
+        torrent,
+        cache: None,
+    })
+}
+
+const DEFAULT_RECENT_EVENTS_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct GetRecentEventsRequest {
+    pub limit: Option<i64>,
+}
+
+async fn get_recent_events(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<GetRecentEventsRequest>,
+) -> Result<Response, RpcError> {
+    let limit = request.limit.unwrap_or(DEFAULT_RECENT_EVENTS_LIMIT);
+    let events = find_recent_events(&state.pool, limit).await?;
+    Ok(Json(events).into_response())
+}
+
+pub fn rpc_router() -> Router<Arc<AppState>> {
+    let router = Router::new()
+        .route("/api/rpc/add_torrent_to_folder", post(add_torrent_to_folder))
+        .route("/api/rpc/check_availability", post(check_availability))
+        .route("/api/rpc/get_library_stats", post(get_library_stats))
+        .route("/api/rpc/get_torrents", post(get_torrents))
+        .route("/api/rpc/list_nodes", post(list_nodes))
+        .route("/api/rpc/set_torrent_category", post(set_torrent_category))
+        .route("/api/rpc/reconcile_torrent", post(reconcile_torrent))
+        .route("/api/rpc/list_categories", post(list_categories))
+        .route("/api/rpc/evict_file_cache", post(evict_file_cache))
+        .route("/api/rpc/evict_torrent_cache", post(evict_torrent_cache))
+        .route("/api/rpc/set_duration_hint", post(set_duration_hint))
+        .route("/api/rpc/get_recent_events", post(get_recent_events));
+
+    if get_config().debug_rpc {
+        return router.route("/api/rpc/debug_node", post(debug_node));
+    }
+
+    router
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RpcErrorCode;
+    use crate::test_helpers::get_test_pool;
+
+    #[tokio::test]
+    async fn test_find_torrents_error_message() {
+        let pool = get_test_pool(false).await;
+        for (hash, name, state) in [
+            (&[1u8][..], "broken", TorrentState::Error),
+            (&[2u8][..], "fine", TorrentState::Ready),
+        ] {
+            // the message is left behind on the ready torrent to check it isn't reported
+            sqlx::query(
+                "INSERT INTO torrents (hash, name, state, magnet_uri, error_message)
+                VALUES (?, ?, ?, 'magnet:', 'Torrent has no valid files')",
+            )
+            .bind(hash)
+            .bind(name)
+            .bind(state)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let torrents = find_torrents(&pool).await.unwrap();
+        assert_eq!(torrents.len(), 2);
+        assert_eq!(torrents[0].name, "broken");
+        assert_eq!(torrents[0].error_message.as_deref(), Some("Torrent has no valid files"));
+        assert_eq!(torrents[1].error_message, None);
+        // rows without a source are from before it was tracked
+        assert_eq!(torrents[0].source, TorrentSource::Qbittorrent);
+    }
+
+    #[tokio::test]
+    async fn test_find_nodes() {
+        let pool = get_test_pool(false).await;
+        for name in ["b", "a", "c"] {
+            sqlx::query("INSERT INTO nodes (parent_id, name, readonly) VALUES (1, ?, 0)")
+                .bind(name)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let request = ListNodesRequest {
+            parent_id: None,
+            offset: Some(1),
+            limit: Some(2),
+        };
+        let list = find_nodes(&pool, request).await.unwrap();
+        assert_eq!(list.id, 1);
+        assert_eq!(list.parent_id, None);
+        // downloads is a child of the root too
+        assert_eq!(list.total, 4);
+        let names = list.nodes.iter().map(|node| node.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["b", "c"]);
+        assert!(list.nodes.iter().all(|node| node.is_dir));
+
+        let list = find_nodes(
+            &pool,
+            ListNodesRequest {
+                parent_id: Some(2),
+                offset: None,
+                limit: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(list.parent_id, Some(1));
+        assert!(list.nodes.is_empty());
+
+        let missing = ListNodesRequest {
+            parent_id: Some(1000),
+            offset: None,
+            limit: None,
+        };
+        let error = find_nodes(&pool, missing).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_link_torrent_files_errors() {
+        let pool = get_test_pool(false).await;
+        let request = AddTorrentToFolderRequest {
+            torrent_id: 1,
+            parent_node_id: 1000,
+        };
+        let error = link_torrent_files(&pool, request).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+
+        // the downloads folder is managed by the reconciler
+        let request = AddTorrentToFolderRequest {
+            torrent_id: 1,
+            parent_node_id: 2,
+        };
+        let error = link_torrent_files(&pool, request).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::BadRequest);
+
+        let request = AddTorrentToFolderRequest {
+            torrent_id: 1,
+            parent_node_id: 1,
+        };
+        let error = link_torrent_files(&pool, request).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_torrent_categories() {
+        let pool = get_test_pool(false).await;
+        let categories = vec!["sonarr".to_string(), "radarr".to_string()];
+        let hash = vec![1u8];
+        let torrent_id = sqlx::query_scalar!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:') RETURNING id",
+            hash,
+            TorrentState::Ready
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let set = |category: &str| SetTorrentCategoryRequest {
+            torrent_id,
+            category: Some(category.to_string()),
+        };
+
+        update_torrent_category(&pool, set("sonarr"), &categories)
+            .await
+            .unwrap();
+        let listed = find_categories(&pool, &categories).await.unwrap();
+        assert_eq!(listed[0].name, "sonarr");
+        assert_eq!(listed[0].torrent_count, 1);
+        assert_eq!(listed[1].name, "radarr");
+        assert_eq!(listed[1].torrent_count, 0);
+
+        let error = update_torrent_category(&pool, set("lidarr"), &categories)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::BadRequest);
+
+        let missing = SetTorrentCategoryRequest {
+            torrent_id: 1000,
+            category: None,
+        };
+        let error = update_torrent_category(&pool, missing, &categories).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+
+        // an empty category clears it
+        update_torrent_category(&pool, set(""), &categories).await.unwrap();
+        let listed = find_categories(&pool, &categories).await.unwrap();
+        assert!(listed.iter().all(|category| category.torrent_count == 0));
+    }
+
+    #[tokio::test]
+    async fn test_find_node_debug_info() {
+        let pool = get_test_pool(false).await;
+        let hash = vec![0xabu8];
+        let torrent_id = sqlx::query_scalar!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri, debrid_id) VALUES (?, 'Show', ?, 'magnet:', 7) RETURNING id",
+            hash,
+            TorrentState::Ready
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let file_id = sqlx::query_scalar!(
+            "INSERT INTO torrent_files (debrid_id, torrent_id, path, size) VALUES (3, ?, 'Show/E01.mkv', 100) RETURNING id",
+            torrent_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let dir_id =
+            sqlx::query_scalar!("INSERT INTO nodes (parent_id, name, readonly) VALUES (2, 'Show', 1) RETURNING id")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let node_id = sqlx::query_scalar!(
+            "INSERT INTO nodes (parent_id, name, size, readonly, torrent_id, file_id) VALUES (?, 'E01.mkv', 100, 1, ?, ?) RETURNING id",
+            dir_id,
+            torrent_id,
+            file_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let info = find_node_debug_info(&pool, node_id).await.unwrap();
+        assert_eq!(info.path, "/downloads/Show/E01.mkv");
+        assert_eq!(info.file.unwrap().debrid_id, 3);
+        let torrent = info.torrent.unwrap();
+        assert_eq!(torrent.hash, "ab");
+        assert_eq!(torrent.debrid_id, Some(7));
+
+        let info = find_node_debug_info(&pool, dir_id).await.unwrap();
+        assert_eq!(info.path, "/downloads/Show");
+        assert!(info.file.is_none() && info.torrent.is_none());
+
+        let error = find_node_debug_info(&pool, 9999).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_update_duration_hint() {
+        let pool = get_test_pool(false).await;
+        let hash = vec![1u8];
+        let torrent_id = sqlx::query_scalar!(
+            "INSERT INTO torrents (hash, name, state, magnet_uri) VALUES (?, 'Show', ?, 'magnet:') RETURNING id",
+            hash,
+            TorrentState::Ready
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let file_id = sqlx::query_scalar!(
+            "INSERT INTO torrent_files (debrid_id, torrent_id, path, size) VALUES (0, ?, 'E01.mkv', 100) RETURNING id",
+            torrent_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let set = |duration_secs| SetDurationHintRequest { file_id, duration_secs };
+        let get = || {
+            sqlx::query_scalar!("SELECT duration_hint_secs FROM torrent_files WHERE id = ?", file_id).fetch_one(&pool)
+        };
+
+        assert_eq!(update_duration_hint(&pool, set(Some(1800))).await.unwrap(), Some(1800));
+        assert_eq!(get().await.unwrap(), Some(1800));
+        // 0 clears it the same as None
+        assert_eq!(update_duration_hint(&pool, set(Some(0))).await.unwrap(), None);
+        assert_eq!(get().await.unwrap(), None);
+
+        let request = SetDurationHintRequest {
+            file_id: 9999,
+            duration_secs: None,
+        };
+        let error = update_duration_hint(&pool, request).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+    }
+}
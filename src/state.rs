@@ -9,6 +9,8 @@ pub enum TorrentState {
     Stalled = 3,
     Error = 4,
     Removing = 5,
+    /// Waiting for a free download slot before being added to the debrid service.
+    Queued = 6,
 }
 
 impl TorrentState {
@@ -35,7 +37,8 @@ impl TorrentState {
     pub fn to_str(&self) -> &'static str {
         // maps to a valid qbittorrent state
         match self {
-            TorrentState::Pending => "queuedDL",
+            TorrentState::Pending => "metaDL",
+            TorrentState::Queued => "queuedDL",
             TorrentState::Downloading => "downloading",
             TorrentState::Ready => "stalledUP",
             TorrentState::Stalled => "stalledDL",
@@ -44,3 +47,22 @@ impl TorrentState {
         }
     }
 }
+
+/// How a torrent was added to lumin.
+#[derive(Debug, PartialEq, Clone, Eq, Copy, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum TorrentSource {
+    Qbittorrent = 0,
+    /// Added to the debrid service outside of lumin and imported with `import_unmapped`.
+    Unmapped = 1,
+}
+
+impl TorrentSource {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            TorrentSource::Qbittorrent => "qbittorrent",
+            TorrentSource::Unmapped => "unmapped",
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+/// An in-memory database with all migrations applied. `foreign_keys` can be disabled
+/// so tests can insert rows that wouldn't normally be allowed.
+pub async fn get_test_pool(foreign_keys: bool) -> SqlitePool {
+    let options = SqliteConnectOptions::new().in_memory(true).foreign_keys(foreign_keys);
+    // every connection to an in-memory database gets its own database, so there can only be one
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}